@@ -1,6 +1,10 @@
-use clap::{Parser, Subcommand};
+use clap::parser::ValueSource;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand};
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum WorkMode {
     // 服务器模式
     Server,
@@ -10,9 +14,47 @@ pub enum WorkMode {
     Index,
 }
 
-#[derive(Parser, Clone)]
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // 每行一个 JSON 对象
+    Json,
+    // Apache/Nginx combined
+    Combined,
+    // Apache/Nginx common
+    Common,
+    // 带颜色的可读格式
+    Pretty,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotate {
+    // 只按大小切分
+    Never,
+    Hourly,
+    Daily,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadyCheck {
+    // 站点目录可读
+    Root,
+    // 反代上游可连接
+    Upstreams,
+}
+
+/// 命令行参数，同时也是配置文件（hs.toml/yaml/json）的结构，key 与长参数名一致
+#[derive(Parser, Clone, Debug, Serialize, Deserialize)]
 #[command(author, version, about, long_about = None)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
 pub struct CliOption {
+    /// Load options from a config file (.toml, .yaml, .yml or .json), CLI flags override file values
+    #[arg(long, value_name = "CONFIG")]
+    #[serde(skip)]
+    pub config: Option<String>,
+
     /// Work mode
     #[arg(short = 'm', long, value_enum, default_value_t = WorkMode::Index)]
     pub mode: WorkMode,
@@ -38,6 +80,31 @@ pub struct CliOption {
     #[arg(short = 'p', long, value_name = "PORT", default_value_t = 8080)]
     pub port: u16,
 
+    /// Listen on HOST:PORT, [IPV6]:PORT or unix:/run/hs.sock (unix only) instead of --host and --port, repeatable.
+    /// Options after `;`: tls, tls-cert=PATH, tls-key=PATH, h2c, ipv6-only, redirect-https
+    #[arg(long, value_name = "ADDR")]
+    pub listen: Vec<String>,
+
+    /// PEM certificate chain, enables HTTPS together with --tls-key
+    #[arg(long, value_name = "TLS-CERT", requires = "tls_key")]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key (PKCS8, RSA or EC) for --tls-cert
+    #[arg(long, value_name = "TLS-KEY", requires = "tls_cert")]
+    pub tls_key: Option<String>,
+
+    /// Also accept HTTP/2 without TLS (h2c with prior knowledge or Upgrade), HTTPS negotiates HTTP/2 anyway
+    #[arg(long, value_name = "H2C", default_value_t = false)]
+    pub h2c: bool,
+
+    /// Peers allowed to set the client IP with Forwarded/X-Forwarded-For/X-Real-IP, eg: 10.0.0.0/8,127.0.0.1
+    #[arg(long, value_name = "CIDR", value_delimiter = ',')]
+    pub trusted_proxies: Vec<String>,
+
+    /// Seconds to wait for in-flight requests on SIGTERM/SIGINT before exiting
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub shutdown_timeout: u64,
+
     /// Enable compress
     #[arg(
         short = 'c',
@@ -48,6 +115,30 @@ pub struct CliOption {
     )]
     pub compress: bool,
 
+    /// Serve precompressed file.br/file.zst/file.gz instead of file when the client accepts it, `--precompressed false` to disable
+    #[arg(long, value_name = "PRECOMPRESSED", action = ArgAction::Set, default_value_t = true)]
+    pub precompressed: bool,
+
+    /// Liveness endpoint, answered before basic auth, empty to disable
+    #[arg(long, value_name = "HEALTH-PATH", default_value_t = String::from("/_health"))]
+    pub health_path: String,
+
+    /// Readiness endpoint, answered before basic auth, empty to disable
+    #[arg(long, value_name = "READY-PATH", default_value_t = String::from("/_ready"))]
+    pub ready_path: String,
+
+    /// Prometheus metrics endpoint, eg: /_metrics, answered before basic auth, disabled when empty
+    #[arg(long, value_name = "METRICS-PATH", default_value_t = String::from(""))]
+    pub metrics_path: String,
+
+    /// Proxy upstream status endpoint (JSON), eg: /_upstreams, answered before basic auth, disabled when empty
+    #[arg(long, value_name = "UPSTREAMS-PATH", default_value_t = String::from(""))]
+    pub upstreams_path: String,
+
+    /// Checks run by the readiness endpoint, eg: root,upstreams
+    #[arg(long, value_enum, value_name = "READY-CHECK", value_delimiter = ',')]
+    pub ready_check: Vec<ReadyCheck>,
+
     /// Automatically open the browser
     #[arg(short = 'o', long, value_name = "open", default_value_t = false)]
     pub open: bool,
@@ -56,13 +147,38 @@ pub struct CliOption {
     #[arg(long, value_name = "CACHE", default_value_t = true)]
     pub cache: bool,
 
-    /// Path to save log at
-    // #[arg(long, value_name = "LOG")]
-    // pub log: Option<String>,
+    /// Cache-Control for files matching a glob (or ~regex), eg: "/assets/**=public, max-age=31536000, immutable"
+    #[arg(long, value_name = "PATTERN=VALUE")]
+    pub cache_control: Vec<String>,
+
+    /// Access log format, json also applies to the other log lines
+    #[arg(long, value_enum, value_name = "LOG-FORMAT", default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+
+    /// Path to save log at, stdout if not set
+    #[arg(long, value_name = "LOG")]
+    pub log: Option<String>,
+
+    /// Path to save warn/error log at, defaults to --log
+    #[arg(long, value_name = "ERROR_LOG")]
+    pub error_log: Option<String>,
 
-    // /// Path to save error log at
-    // #[arg(long, value_name = "ERROR_LOG")]
-    // pub error_log: Option<String>,
+    /// Rotate log files bigger than this size, eg: 100M, 0 to disable
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "0")]
+    #[serde(deserialize_with = "deserialize_size")]
+    pub log_max_size: u64,
+
+    /// Rotate log files every hour or day
+    #[arg(long, value_enum, value_name = "LOG-ROTATE", default_value_t = LogRotate::Never)]
+    pub log_rotate: LogRotate,
+
+    /// Number of rotated log files to keep
+    #[arg(long, value_name = "COUNT", default_value_t = 7)]
+    pub log_retain: usize,
+
+    /// Gzip rotated log files
+    #[arg(long, value_name = "LOG-COMPRESS", default_value_t = false)]
+    pub log_compress: bool,
 
     /// Enable upload, recommend to enable this in Index mode
     #[arg(short = 'u', long, value_name = "UPLOAD", default_value_t = false)]
@@ -76,7 +192,11 @@ pub struct CliOption {
     #[arg(long, value_name = "CUSTOM-404")]
     pub custom_404: Option<String>,
 
-    /// Set proxy for requests, eg: /api->http://127.0.0.1:8080
+    /// Page served when a proxy upstream fails (502, 504), eg: 50x.html
+    #[arg(long, value_name = "PROXY-ERROR-PAGE")]
+    pub proxy_error_page: Option<String>,
+
+    /// Set proxy for requests, eg: /api->http://127.0.0.1:8080, see Proxy Options
     #[arg(short = 'P', long, value_name = "PROXY", num_args(0..))]
     pub proxies: Vec<String>,
 
@@ -88,14 +208,155 @@ pub struct CliOption {
     #[arg(long, value_name = "IGNORE-FILES", default_value_t = String::from(r"^\."))]
     pub ignore_files: String,
 
+    /// Add a response header, optionally only for paths matching a glob (or ~regex), eg: "X-Frame-Options: DENY" or "/fonts/**=Access-Control-Allow-Origin: *"
+    #[arg(long = "header", value_name = "HEADER")]
+    pub headers: Vec<String>,
+
+    /// Add HSTS (HTTPS only), X-Content-Type-Options, Referrer-Policy, X-Frame-Options and Content-Security-Policy headers
+    #[arg(long, value_name = "SECURITY-HEADERS", default_value_t = false)]
+    pub security_headers: bool,
+
+    /// Content-Security-Policy header, defaults to a same-origin policy with --security-headers
+    #[arg(long, value_name = "CSP")]
+    pub content_security_policy: Option<String>,
+
     #[arg(long, value_name = "DISABLE-POWERED-BY", default_value_t = true)]
     pub disable_powered_by: bool,
 
+    /// Host names of this site, used inside --vhost, eg: docs.example.com
+    #[arg(long, value_name = "SERVER-NAME", value_delimiter = ',')]
+    pub server_name: Vec<String>,
+
+    /// Serve another site selected by the Host header, quote values with spaces, eg: "--server-name docs.example.com -m index -f './my docs'"
+    #[arg(long = "vhost", value_name = "OPTIONS", value_parser = parse_vhost, allow_hyphen_values = true)]
+    pub vhosts: Vec<CliOption>,
+
     #[command(subcommand)]
+    #[serde(skip)]
     pub command: Option<Commands>,
 }
 
-#[derive(Subcommand, Clone)]
+impl Default for CliOption {
+    /// 与不带任何参数启动时一致
+    fn default() -> Self {
+        CliOption::parse_from(["hs"])
+    }
+}
+
+/// --vhost 的值像 shell 一样拆分后作为一组独立的命令行参数解析
+fn parse_vhost(value: &str) -> Result<CliOption, String> {
+    let args = std::iter::once(String::from("hs")).chain(split_args(value)?);
+    let command = CliOption::command();
+    let matches = command
+        .clone()
+        .try_get_matches_from(args)
+        .map_err(|e| e.to_string().trim().to_string())?;
+    // 监听、日志等全局参数在虚拟主机里不生效，直接报错
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if !is_site_option(id) && matches.value_source(id) == Some(ValueSource::CommandLine) {
+            let flag = arg.get_long().map_or_else(|| id.to_string(), |long| format!("--{}", long));
+            return Err(format!("Invalid vhost options '{}', {} only applies at top level", value, flag));
+        }
+    }
+    if let Some(name) = matches.subcommand_name() {
+        return Err(format!("Invalid vhost options '{}', {} only applies at top level", value, name));
+    }
+    CliOption::from_arg_matches(&matches).map_err(|e| e.to_string().trim().to_string())
+}
+
+/// Options that `--vhost` and `[[vhosts]]` accept, by argument id, the others are server-wide.
+const SITE_OPTIONS: &[&str] = &[
+    "mode",
+    "path",
+    "folder",
+    "base",
+    "cache",
+    "cache_control",
+    "precompressed",
+    "upload",
+    "security",
+    "custom_404",
+    "proxy_error_page",
+    "proxies",
+    "websocket_proxies",
+    "ignore_files",
+    "headers",
+    "security_headers",
+    "content_security_policy",
+    "server_name",
+];
+
+pub fn is_site_option(id: &str) -> bool {
+    SITE_OPTIONS.contains(&id)
+}
+
+/// Splits on whitespace like a shell, `'...'` and `"..."` keep spaces in one argument,
+/// a backslash escapes the next character outside single quotes.
+fn split_args(value: &str) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '\\') | (Some('"'), '\\') => match chars.next() {
+                Some(next) => arg.get_or_insert_with(String::new).push(next),
+                None => return Err(format!("Invalid vhost options '{}', trailing backslash", value)),
+            },
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                // 空引号也是一个参数
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (_, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("Invalid vhost options '{}', unclosed quote", value));
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// 大小，支持 K/M/G 后缀（1024 进制），eg: 512K, 100M
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value, ""),
+    };
+    let multiplier = match unit.trim().to_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(format!("invalid size '{}', expected eg: 512K, 100M, 1G", value)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size '{}', expected eg: 512K, 100M, 1G", value))
+}
+
+/// 配置文件中大小既可以写数字也可以写 "100M"
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(n) => Ok(n),
+        Size::Text(s) => parse_size(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+#[derive(Subcommand, Clone, Debug)]
 pub enum Commands {
     /// Update hs self
     Update {},
@@ -0,0 +1,266 @@
+#[cfg(unix)]
+use std::net::Ipv4Addr;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use actix_web::{http::header, web, HttpMessage, HttpRequest};
+
+/// An IP network, eg: `10.0.0.0/8`, `fd00::/8`, a plain address means a single host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<IpNet, String> {
+        let invalid = || format!("Invalid CIDR '{}', expected eg: 10.0.0.0/8 or fd00::/8", value);
+        let (addr, prefix) = match value.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value.trim(), None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_match(u32::from(net) as u128, u32::from(ip) as u128, self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_match(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_match(net: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    (net >> shift) == (ip >> shift)
+}
+
+/// Peers whose `Forwarded`/`X-Forwarded-For`/`X-Real-IP` headers are believed.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn parse(cidrs: &[String]) -> Result<TrustedProxies, String> {
+        let nets = cidrs
+            .iter()
+            .map(|cidr| cidr.parse())
+            .collect::<Result<_, _>>()?;
+        Ok(TrustedProxies { nets })
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    /// Real client IP of a request coming from `peer`.
+    /// The forwarded chain is walked from the right and the first address not trusted wins,
+    /// so a client can not spoof its IP by sending the headers itself.
+    pub fn resolve(&self, peer: IpAddr, req: &HttpRequest) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let chain = forwarded_chain(req);
+        let mut client = peer;
+        for hop in chain.iter().rev() {
+            let Some(ip) = hop else {
+                // unknown 或混淆的地址，无法继续追溯
+                break;
+            };
+            client = *ip;
+            if !self.is_trusted(*ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+// 按顺序取 Forwarded 的 for=，否则 X-Forwarded-For，否则 X-Real-IP，无法解析的为 None
+fn forwarded_chain(req: &HttpRequest) -> Vec<Option<IpAddr>> {
+    let values = |name: header::HeaderName| -> Vec<String> {
+        req.headers()
+            .get_all(name)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect()
+    };
+    let forwarded: Vec<Option<IpAddr>> = values(header::FORWARDED)
+        .iter()
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim().eq_ignore_ascii_case("for").then(|| parse_node(value))
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    let forwarded_for = values(header::HeaderName::from_static("x-forwarded-for"));
+    if !forwarded_for.is_empty() {
+        return forwarded_for.iter().map(|v| parse_node(v)).collect();
+    }
+    values(header::HeaderName::from_static("x-real-ip"))
+        .iter()
+        .map(|v| parse_node(v))
+        .collect()
+}
+
+// 192.0.2.60、"192.0.2.60:4711"、"[2001:db8::1]:4711"、2001:db8::1
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split_once(']').and_then(|(ip, _)| ip.parse().ok());
+    }
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// Connection data of the unix socket listener, whose peers have no address.
+#[cfg(unix)]
+#[derive(Clone, Copy)]
+pub struct UnixPeer;
+
+/// Address of the direct peer, a peer of the unix socket listener is `127.0.0.1`.
+pub fn peer_ip(req: &HttpRequest) -> Option<IpAddr> {
+    if let Some(addr) = req.peer_addr() {
+        return Some(addr.ip());
+    }
+    #[cfg(unix)]
+    if req.conn_data::<UnixPeer>().is_some() {
+        return Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+    None
+}
+
+/// Whether the direct peer is one of `--trusted-proxies`.
+pub fn peer_is_trusted(req: &HttpRequest) -> bool {
+    match (peer_ip(req), req.app_data::<web::Data<TrustedProxies>>()) {
+        (Some(peer), Some(trusted)) => trusted.is_trusted(peer),
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ClientIp(IpAddr);
+
+/// Client IP of the request, resolved through the trusted proxies once and cached.
+/// `None` only when the peer address is unknown (eg: in tests).
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    if let Some(ClientIp(ip)) = req.extensions().get::<ClientIp>() {
+        return Some(*ip);
+    }
+    let peer = peer_ip(req)?;
+    let ip = match req.app_data::<web::Data<TrustedProxies>>() {
+        Some(trusted) => trusted.resolve(peer, req),
+        None => peer,
+    };
+    req.extensions_mut().insert(ClientIp(ip));
+    Some(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn trusted(cidrs: &[&str]) -> TrustedProxies {
+        TrustedProxies::parse(&cidrs.iter().map(|c| c.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        // IPv4 映射的 IPv6 地址
+        assert!(net.contains(ip("::ffff:10.0.0.1")));
+        let net: IpNet = "fd00::/8".parse().unwrap();
+        assert!(net.contains(ip("fd12::1")));
+        assert!(!net.contains(ip("10.0.0.1")));
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains(ip("8.8.8.8")));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("localhost".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_untrusted_peer_headers_are_ignored() {
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "1.2.3.4"))
+            .to_http_request();
+        assert_eq!(trusted(&["10.0.0.0/8"]).resolve(ip("192.0.2.1"), &req), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn test_x_forwarded_for_skips_trusted_hops() {
+        // 客户端自己伪造的 6.6.6.6 在最左边，不会被采用
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "6.6.6.6, 203.0.113.7, 10.0.0.2"))
+            .to_http_request();
+        assert_eq!(trusted(&["10.0.0.0/8"]).resolve(ip("10.0.0.1"), &req), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn test_forwarded_header_takes_precedence() {
+        let req = TestRequest::get()
+            .insert_header(("forwarded", "for=\"[2001:db8::7]:4711\";proto=https, for=10.0.0.2"))
+            .insert_header(("x-forwarded-for", "1.2.3.4"))
+            .to_http_request();
+        assert_eq!(trusted(&["10.0.0.0/8"]).resolve(ip("10.0.0.1"), &req), ip("2001:db8::7"));
+    }
+
+    #[test]
+    fn test_x_real_ip_and_unknown_hop() {
+        let req = TestRequest::get()
+            .insert_header(("x-real-ip", "198.51.100.9"))
+            .to_http_request();
+        assert_eq!(trusted(&["127.0.0.1"]).resolve(ip("127.0.0.1"), &req), ip("198.51.100.9"));
+        let req = TestRequest::get()
+            .insert_header(("forwarded", "for=unknown"))
+            .to_http_request();
+        assert_eq!(trusted(&["127.0.0.1"]).resolve(ip("127.0.0.1"), &req), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn test_client_ip_uses_app_data() {
+        let req = TestRequest::get()
+            .peer_addr("10.0.0.1:5000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .app_data(web::Data::new(trusted(&["10.0.0.0/8"])))
+            .to_http_request();
+        assert_eq!(client_ip(&req), Some(ip("203.0.113.7")));
+        let req = TestRequest::get()
+            .peer_addr("10.0.0.1:5000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .to_http_request();
+        assert_eq!(client_ip(&req), Some(ip("10.0.0.1")));
+    }
+}
@@ -0,0 +1,332 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use fancy_regex::{Captures, Regex};
+use serde_json::Value;
+
+use crate::cli::{is_site_option, CliOption};
+
+// ${VAR} 形式的环境变量
+static ENV_VAR_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$\{(.*?)\}").unwrap());
+
+/// Replaces `${VAR}` with the value of the environment variable `VAR`,
+/// unknown variables are kept as is.
+/// eg: http://${APP_URL} -> http://localhost:8080 with APP_URL=localhost:8080
+pub fn interpolate_env(value: &str) -> String {
+    ENV_VAR_REGEX
+        .replace_all(value, |caps: &Captures| {
+            env::var(&caps[1]).unwrap_or_else(|_| caps[0].to_string())
+        })
+        .into_owned()
+}
+
+/// Parses the command line and merges it with the `--config` file if given.
+/// Exits the process on invalid arguments or config, like clap does.
+pub fn load_options() -> CliOption {
+    let matches = CliOption::command().get_matches();
+    let options = CliOption::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    apply_config_file(options, &matches).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Like `load_options` but returns errors instead of exiting, used when reloading.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub fn try_load_options() -> Result<CliOption, String> {
+    let matches = CliOption::command()
+        .try_get_matches()
+        .map_err(|e| e.to_string())?;
+    let options = CliOption::from_arg_matches(&matches).map_err(|e| e.to_string())?;
+    apply_config_file(options, &matches)
+}
+
+/// Lists the options that differ, eg: `port: 8080 -> 9000`. Credentials are masked.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub fn diff_options(old: &CliOption, new: &CliOption) -> Vec<String> {
+    let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return vec![];
+    };
+    new.iter()
+        .filter(|(key, value)| old.get(*key) != Some(value))
+        .map(|(key, value)| {
+            if key == "security" {
+                return format!("{}: changed", key);
+            }
+            let old_value = old.get(key).map(|v| v.to_string()).unwrap_or_default();
+            format!("{}: {} -> {}", key, old_value, value)
+        })
+        .collect()
+}
+
+/// Uses the values of the config file for every option not given on the command line.
+pub fn apply_config_file(options: CliOption, matches: &ArgMatches) -> Result<CliOption, String> {
+    let Some(config_path) = options.config.clone() else {
+        return Ok(options);
+    };
+    let mut merged = read_config_file(Path::new(&config_path))?;
+    let Value::Object(file_values) = &mut merged else {
+        return Err(format!("Config file '{}' must contain a table/object at top level", config_path));
+    };
+    // 虚拟主机只接受站点参数，全局参数写在顶层
+    if let Some(Value::Array(vhosts)) = file_values.get("vhosts") {
+        for (i, vhost) in vhosts.iter().enumerate() {
+            let Value::Object(vhost) = vhost else { continue };
+            if let Some(key) = vhost.keys().find(|key| !is_site_option(&key.replace('-', "_"))) {
+                return Err(format!(
+                    "Invalid config file '{}' at key `vhosts[{}].{}`: only applies at top level",
+                    config_path, i, key
+                ));
+            }
+        }
+    }
+    // 命令行显式传入的参数覆盖配置文件
+    let cli_values = serde_json::to_value(&options).map_err(|e| e.to_string())?;
+    for id in matches.ids() {
+        if matches.value_source(id.as_str()) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let key = id.as_str().replace('_', "-");
+        if let Some(value) = cli_values.get(&key) {
+            file_values.insert(key, value.clone());
+        }
+    }
+    let mut merged_options: CliOption = serde_path_to_error::deserialize(merged).map_err(|e| {
+        format!("Invalid config file '{}' at key `{}`: {}", config_path, e.path(), e.inner())
+    })?;
+    merged_options.config = Some(config_path);
+    merged_options.command = options.command;
+    Ok(merged_options)
+}
+
+/// Reads a toml/yaml/json file into a generic value with `${VAR}` already interpolated.
+fn read_config_file(path: &Path) -> Result<Value, String> {
+    let display = path.display();
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Can not read config file '{}': {}", display, e))?;
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    let value: Value = match extension.to_lowercase().as_str() {
+        "toml" => toml::from_str(&content).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        "json" => serde_json::from_str(&content).map_err(|e| e.to_string()),
+        _ => Err(String::from("unsupported format, expected .toml, .yaml, .yml or .json")),
+    }
+    .map_err(|e| format!("Invalid config file '{}': {}", display, e))?;
+    Ok(interpolate_value(value))
+}
+
+fn interpolate_value(value: Value) -> Value {
+    match value {
+        Value::String(s) => Value::String(interpolate_env(&s)),
+        Value::Array(items) => Value::Array(items.into_iter().map(interpolate_value).collect()),
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| (k, interpolate_value(v)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::WorkMode;
+    use tempfile::TempDir;
+
+    fn write_config(dir: &TempDir, name: &str, content: &str) -> String {
+        let path = dir.path().join(name);
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn load_with_args(args: &[&str]) -> Result<CliOption, String> {
+        let matches = CliOption::command().get_matches_from(args);
+        let options = CliOption::from_arg_matches(&matches).unwrap();
+        apply_config_file(options, &matches)
+    }
+
+    #[test]
+    fn test_toml_config_sets_options() {
+        let tmp = TempDir::new().unwrap();
+        let config = write_config(
+            &tmp,
+            "hs.toml",
+            r#"
+mode = "spa"
+port = 9000
+proxies = ["/api->http://127.0.0.1:3000"]
+custom-404 = "404.html"
+"#,
+        );
+        let options = load_with_args(&["hs", "--config", &config]).unwrap();
+        assert_eq!(options.mode, WorkMode::SPA);
+        assert_eq!(options.port, 9000);
+        assert_eq!(options.proxies, vec!["/api->http://127.0.0.1:3000"]);
+        assert_eq!(options.custom_404.as_deref(), Some("404.html"));
+        // 未配置的保持默认值
+        assert_eq!(options.host, "0.0.0.0");
+    }
+
+    #[test]
+    fn test_yaml_and_json_config() {
+        let tmp = TempDir::new().unwrap();
+        let yaml = write_config(&tmp, "hs.yaml", "mode: index\nupload: true\n");
+        let options = load_with_args(&["hs", "--config", &yaml]).unwrap();
+        assert!(options.upload);
+        let json = write_config(&tmp, "hs.json", r#"{"port": 7000, "base": "/app"}"#);
+        let options = load_with_args(&["hs", "--config", &json]).unwrap();
+        assert_eq!(options.port, 7000);
+        assert_eq!(options.base, "/app");
+    }
+
+    #[test]
+    fn test_cli_flags_override_config() {
+        let tmp = TempDir::new().unwrap();
+        let config = write_config(&tmp, "hs.toml", "port = 9000\nmode = \"spa\"\n");
+        let options = load_with_args(&["hs", "--config", &config, "-p", "9100"]).unwrap();
+        assert_eq!(options.port, 9100);
+        assert_eq!(options.mode, WorkMode::SPA);
+    }
+
+    #[test]
+    fn test_env_interpolation_in_config() {
+        std::env::set_var("HS_CONFIG_TEST_UPSTREAM", "10.0.0.1:3000");
+        let tmp = TempDir::new().unwrap();
+        let config = write_config(
+            &tmp,
+            "hs.toml",
+            "proxies = [\"/api->http://${HS_CONFIG_TEST_UPSTREAM}\"]\n",
+        );
+        let options = load_with_args(&["hs", "--config", &config]).unwrap();
+        assert_eq!(options.proxies, vec!["/api->http://10.0.0.1:3000"]);
+    }
+
+    #[test]
+    fn test_invalid_value_points_to_key() {
+        let tmp = TempDir::new().unwrap();
+        let config = write_config(&tmp, "hs.toml", "port = \"eighty\"\n");
+        let err = load_with_args(&["hs", "--config", &config]).unwrap_err();
+        assert!(err.contains("`port`"), "{}", err);
+    }
+
+    #[test]
+    fn test_unknown_key_is_rejected() {
+        let tmp = TempDir::new().unwrap();
+        let config = write_config(&tmp, "hs.toml", "prot = 80\n");
+        let err = load_with_args(&["hs", "--config", &config]).unwrap_err();
+        assert!(err.contains("prot"), "{}", err);
+    }
+
+    #[test]
+    fn test_precompressed_can_be_disabled() {
+        assert!(load_with_args(&["hs"]).unwrap().precompressed);
+        assert!(!load_with_args(&["hs", "--precompressed", "false"]).unwrap().precompressed);
+        assert!(load_with_args(&["hs", "--precompressed", "true"]).unwrap().precompressed);
+    }
+
+    #[test]
+    fn test_size_option() {
+        assert_eq!(load_with_args(&["hs", "--log-max-size", "100M"]).unwrap().log_max_size, 100 << 20);
+        let err = CliOption::command()
+            .try_get_matches_from(["hs", "--log-max-size", "99999999999999G"])
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid size"), "{}", err);
+    }
+
+    #[test]
+    fn test_vhost_option_parsed_as_nested_options() {
+        let options = load_with_args(&[
+            "hs",
+            "--vhost",
+            "--server-name docs.example.com,www.docs.example.com -m index -f ./docs",
+        ])
+        .unwrap();
+        assert_eq!(options.vhosts.len(), 1);
+        assert_eq!(
+            options.vhosts[0].server_name,
+            vec!["docs.example.com", "www.docs.example.com"]
+        );
+        assert_eq!(options.vhosts[0].mode, WorkMode::Index);
+        assert_eq!(options.vhosts[0].path, "./docs");
+    }
+
+    #[test]
+    fn test_vhost_option_quoting() {
+        let options = load_with_args(&[
+            "hs",
+            "--vhost",
+            r#"--server-name docs.example.com -f '/srv/my docs' -P "/api->http://a/;request-header=X-Env: prod" --header X-Note:\ a\ b"#,
+        ])
+        .unwrap();
+        assert_eq!(options.vhosts[0].path, "/srv/my docs");
+        assert_eq!(options.vhosts[0].proxies, vec!["/api->http://a/;request-header=X-Env: prod"]);
+        assert_eq!(options.vhosts[0].headers, vec!["X-Note: a b"]);
+        let err = CliOption::command()
+            .try_get_matches_from(["hs", "--vhost", "--server-name docs.example.com -f '/srv/docs"])
+            .unwrap_err();
+        assert!(err.to_string().contains("unclosed quote"), "{}", err);
+    }
+
+    #[test]
+    fn test_vhost_rejects_server_wide_options() {
+        for flag in ["--port 9000", "--listen 0.0.0.0:80", "--log /tmp/hs.log", "--tls-cert a.pem --tls-key a.key"] {
+            let err = CliOption::command()
+                .try_get_matches_from(["hs", "--vhost", &format!("--server-name docs.example.com {}", flag)])
+                .unwrap_err();
+            let name = flag.split(' ').next().unwrap();
+            assert!(err.to_string().contains(&format!("{} only applies at top level", name)), "{}", err);
+        }
+        let tmp = TempDir::new().unwrap();
+        let config = write_config(&tmp, "hs.toml", "[[vhosts]]\nserver-name = [\"a.example.com\"]\nport = 9000\n");
+        let err = load_with_args(&["hs", "--config", &config]).unwrap_err();
+        assert!(err.contains("`vhosts[0].port`"), "{}", err);
+    }
+
+    #[test]
+    fn test_vhosts_in_config() {
+        let tmp = TempDir::new().unwrap();
+        let config = write_config(
+            &tmp,
+            "hs.toml",
+            r#"
+mode = "spa"
+
+[[vhosts]]
+server-name = ["docs.example.com"]
+mode = "index"
+path = "./docs"
+proxies = ["/api->http://127.0.0.1:3000"]
+"#,
+        );
+        let options = load_with_args(&["hs", "--config", &config]).unwrap();
+        assert_eq!(options.mode, WorkMode::SPA);
+        assert_eq!(options.vhosts[0].server_name, vec!["docs.example.com"]);
+        assert_eq!(options.vhosts[0].mode, WorkMode::Index);
+        assert_eq!(options.vhosts[0].proxies.len(), 1);
+    }
+
+    #[test]
+    fn test_diff_options() {
+        let old = load_with_args(&["hs", "-p", "8080", "-s", "user:old"]).unwrap();
+        let new = load_with_args(&["hs", "-p", "9000", "-s", "user:new"]).unwrap();
+        let changes = diff_options(&old, &new);
+        assert!(changes.contains(&String::from("port: 8080 -> 9000")));
+        assert!(changes.contains(&String::from("security: changed")));
+        assert_eq!(changes.len(), 2);
+        assert!(diff_options(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_extension() {
+        let tmp = TempDir::new().unwrap();
+        let config = write_config(&tmp, "hs.ini", "port=80\n");
+        assert!(load_with_args(&["hs", "--config", &config]).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_http::error::DispatchError;
+use actix_http::Protocol;
+use actix_web::rt::net::TcpStream;
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::timeout;
+
+// RFC 9113 §3.4 客户端的连接前言
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+// 请求头超过这个大小时不再尝试升级
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+// HTTP/2 默认的最大帧长度
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_SETTINGS: u8 = 0x4;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+
+/// A connection of an `h2c` listener, the bytes read while choosing the protocol are replayed first.
+pub struct H2cStream {
+    io: TcpStream,
+    replay: Bytes,
+}
+
+impl H2cStream {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+}
+
+impl AsyncRead for H2cStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.replay.is_empty() {
+            let len = self.replay.len().min(buf.remaining());
+            let replay = self.replay.split_to(len);
+            buf.put_slice(&replay);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for H2cStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Chooses HTTP/2 for the prior knowledge preface and for a first HTTP/1.1 request with
+/// `Upgrade: h2c`. That request is answered with `101 Switching Protocols` and handed to the
+/// HTTP/2 dispatcher as stream 1, its response is sent over HTTP/2 (RFC 7540 §3.2).
+/// Requests with a body and other requests stay on HTTP/1.1.
+pub async fn accept(
+    io: TcpStream,
+    read_timeout: Duration,
+) -> Result<(H2cStream, Protocol, Option<SocketAddr>), DispatchError> {
+    let peer = io.peer_addr().ok();
+    let mut stream = H2cStream { io, replay: Bytes::new() };
+    let protocol = timeout(read_timeout, negotiate(&mut stream))
+        .await
+        .map_err(|_| DispatchError::SlowRequestTimeout)??;
+    Ok((stream, protocol, peer))
+}
+
+async fn negotiate(stream: &mut H2cStream) -> io::Result<Protocol> {
+    let mut buf = BytesMut::new();
+    // 读到能区分前言和完整的 HTTP/1.1 请求头为止
+    let head_len = loop {
+        let preface_len = buf.len().min(PREFACE.len());
+        if buf[..preface_len] == PREFACE[..preface_len] {
+            if buf.len() >= PREFACE.len() {
+                stream.replay = buf.freeze();
+                return Ok(Protocol::Http2);
+            }
+        } else if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        } else if buf.len() >= MAX_HEAD_SIZE {
+            stream.replay = buf.freeze();
+            return Ok(Protocol::Http1);
+        }
+        if stream.io.read_buf(&mut buf).await? == 0 {
+            stream.replay = buf.freeze();
+            return Ok(Protocol::Http1);
+        }
+    };
+    let Some(headers_frame) = upgrade_request(&buf[..head_len]) else {
+        stream.replay = buf.freeze();
+        return Ok(Protocol::Http1);
+    };
+    stream
+        .io
+        .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n")
+        .await?;
+    // 客户端接着发送前言和 SETTINGS 帧，升级的请求作为 stream 1 放在它们之后
+    let mut rest = buf.split_off(head_len);
+    loop {
+        if rest.len() >= PREFACE.len() + 9 {
+            let frame = &rest[PREFACE.len()..];
+            let len = u32::from_be_bytes([0, frame[0], frame[1], frame[2]]) as usize;
+            if !rest.starts_with(PREFACE) || frame[3] != FRAME_SETTINGS || len > MAX_FRAME_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP/2 preface after upgrade"));
+            }
+            let settings_end = PREFACE.len() + 9 + len;
+            if rest.len() >= settings_end {
+                let tail = rest.split_off(settings_end);
+                rest.extend_from_slice(&headers_frame);
+                rest.extend_from_slice(&tail);
+                stream.replay = rest.freeze();
+                return Ok(Protocol::Http2);
+            }
+        }
+        if stream.io.read_buf(&mut rest).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// The HEADERS frame of stream 1 for an HTTP/1.1 `Upgrade: h2c` request head,
+/// `None` when the request can not be upgraded.
+fn upgrade_request(head: &[u8]) -> Option<Vec<u8>> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    if !req.parse(head).ok()?.is_complete() || req.version != Some(1) {
+        return None;
+    }
+    let header = |name: &str| {
+        req.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name)
+            .and_then(|value| std::str::from_utf8(value).ok())
+            .is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    // RFC 7540 §3.2 要求带上 HTTP2-Settings，带请求体的请求留在 HTTP/1.1
+    let has_body = header("transfer-encoding").is_some() || header("content-length").is_some_and(|v| v != b"0");
+    if !has_token("upgrade", "h2c") || !has_token("connection", "upgrade") || header("http2-settings").is_none() || has_body {
+        return None;
+    }
+    let mut block = vec![];
+    hpack_literal(&mut block, b":method", req.method?.as_bytes());
+    hpack_literal(&mut block, b":scheme", b"http");
+    hpack_literal(&mut block, b":authority", header("host")?);
+    hpack_literal(&mut block, b":path", req.path?.as_bytes());
+    for h in req.headers.iter() {
+        let name = h.name.to_ascii_lowercase();
+        // HTTP/2 不允许连接相关的头部
+        let connection_specific = matches!(
+            name.as_str(),
+            "host" | "connection" | "upgrade" | "http2-settings" | "keep-alive" | "proxy-connection" | "te"
+        );
+        if !connection_specific {
+            hpack_literal(&mut block, name.as_bytes(), h.value);
+        }
+    }
+    if block.len() > MAX_FRAME_SIZE {
+        return None;
+    }
+    let mut frame = Vec::with_capacity(9 + block.len());
+    frame.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+    frame.push(FRAME_HEADERS);
+    frame.push(FLAG_END_STREAM | FLAG_END_HEADERS);
+    frame.extend_from_slice(&1u32.to_be_bytes());
+    frame.extend_from_slice(&block);
+    Some(frame)
+}
+
+// RFC 7541 §6.2.2 不加入动态表的字面量，名字和值都不用 Huffman 编码
+fn hpack_literal(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    for string in [name, value] {
+        hpack_length(block, string.len());
+        block.extend_from_slice(string);
+    }
+}
+
+// 7 位前缀的整数，RFC 7541 §5.1
+fn hpack_length(block: &mut Vec<u8>, mut value: usize) {
+    if value < 127 {
+        block.push(value as u8);
+        return;
+    }
+    block.push(127);
+    value -= 127;
+    while value >= 128 {
+        block.push((value % 128 + 128) as u8);
+        value /= 128;
+    }
+    block.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPGRADE: &str = "GET /api/users?page=2 HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\nAccept: application/json\r\n\r\n";
+
+    #[test]
+    fn test_upgrade_request_needs_h2c_and_settings() {
+        assert!(upgrade_request(UPGRADE.as_bytes()).is_some());
+        let without_settings = UPGRADE.replace("HTTP2-Settings: AAMAAABkAAQAAP__\r\n", "");
+        assert!(upgrade_request(without_settings.as_bytes()).is_none());
+        let websocket = UPGRADE.replace("Upgrade: h2c", "Upgrade: websocket");
+        assert!(upgrade_request(websocket.as_bytes()).is_none());
+        let with_body = UPGRADE.replace("Accept:", "Content-Length: 3\r\nAccept:");
+        assert!(upgrade_request(with_body.as_bytes()).is_none());
+        assert!(upgrade_request(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn test_hpack_length() {
+        let encode = |value: usize| {
+            let mut block = vec![];
+            hpack_length(&mut block, value);
+            block
+        };
+        assert_eq!(encode(10), vec![10]);
+        assert_eq!(encode(127), vec![127, 0]);
+        // RFC 7541 C.1.2 的例子换成 7 位前缀
+        assert_eq!(encode(1337), vec![127, 0xba, 0x09]);
+    }
+
+    #[actix_web::test]
+    async fn test_upgraded_request_is_stream_one() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = actix_web::rt::spawn(async move {
+            listener.set_nonblocking(true).unwrap();
+            let (io, _) = actix_web::rt::net::TcpListener::from_std(listener).unwrap().accept().await.unwrap();
+            let (stream, protocol, _) = accept(io, Duration::from_secs(5)).await.unwrap();
+            assert_eq!(protocol, Protocol::Http2);
+            let mut connection = h2::server::handshake(stream).await.unwrap();
+            let (req, _) = connection.accept().await.unwrap().unwrap();
+            req
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(UPGRADE.as_bytes()).await.unwrap();
+        let mut response = vec![0; 128];
+        let len = client.read(&mut response).await.unwrap();
+        assert!(response[..len].starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        // 前言和空的 SETTINGS 帧
+        client.write_all(PREFACE).await.unwrap();
+        client.write_all(&[0, 0, 0, FRAME_SETTINGS, 0, 0, 0, 0, 0]).await.unwrap();
+
+        let req = server.await.unwrap();
+        assert_eq!(req.method(), "GET");
+        assert_eq!(req.uri().to_string(), "http://example.com/api/users?page=2");
+        assert_eq!(req.headers().get("accept").unwrap(), "application/json");
+        assert!(!req.headers().contains_key("upgrade"));
+        drop(client);
+    }
+}
@@ -0,0 +1,218 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    web, Error,
+};
+
+use crate::pattern::{split_rule, PathPattern};
+
+/// 默认的 CSP，目录列表页面需要内联脚本和样式
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; script-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'; img-src 'self' data:; frame-ancestors 'self'";
+
+/// --security-headers 预设，HSTS 只在 HTTPS 上发送
+const SECURITY_HEADERS: [(&str, &str); 4] = [
+    (STRICT_TRANSPORT_SECURITY, "max-age=31536000; includeSubDomains"),
+    ("x-content-type-options", "nosniff"),
+    ("referrer-policy", "strict-origin-when-cross-origin"),
+    ("x-frame-options", "SAMEORIGIN"),
+];
+
+const STRICT_TRANSPORT_SECURITY: &str = "strict-transport-security";
+
+struct HeaderRule {
+    /// 为空时对所有路径生效
+    pattern: Option<PathPattern>,
+    // 明文 HTTP 上的 HSTS 没有意义，浏览器也会忽略
+    secure_only: bool,
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+/// Extra response headers of a site, applied in order so later rules override earlier ones.
+pub struct ResponseHeaders {
+    rules: Vec<HeaderRule>,
+}
+
+impl ResponseHeaders {
+    /// `headers` are `Name: value` or `PATTERN=Name: value`.
+    pub fn new(
+        headers: &[String],
+        security_headers: bool,
+        content_security_policy: Option<&str>,
+    ) -> Result<ResponseHeaders, String> {
+        let mut rules = vec![];
+        if security_headers {
+            for (name, value) in SECURITY_HEADERS {
+                rules.push(HeaderRule {
+                    pattern: None,
+                    secure_only: name == STRICT_TRANSPORT_SECURITY,
+                    name: HeaderName::from_static(name),
+                    value: HeaderValue::from_static(value),
+                });
+            }
+        }
+        let csp = content_security_policy
+            .or(security_headers.then_some(DEFAULT_CONTENT_SECURITY_POLICY));
+        if let Some(csp) = csp {
+            rules.push(HeaderRule {
+                pattern: None,
+                secure_only: false,
+                name: HeaderName::from_static("content-security-policy"),
+                value: HeaderValue::from_str(csp)
+                    .map_err(|_| format!("Invalid Content-Security-Policy '{}'", csp))?,
+            });
+        }
+        for header in headers {
+            rules.push(parse_header_rule(header)?);
+        }
+        Ok(ResponseHeaders { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+fn parse_header_rule(rule: &str) -> Result<HeaderRule, String> {
+    // 头名称中不会出现 = 和 ~，第一个 : 前有 = 或者以 ~ 开头说明带路径规则，正则里可以有 :
+    let has_pattern = rule.trim_start().starts_with('~')
+        || rule.split_once(':').is_some_and(|(name, _)| name.contains('='));
+    let (pattern, header) = if has_pattern {
+        let (pattern, header) = split_rule(rule)?;
+        (Some(pattern), header)
+    } else {
+        (None, rule)
+    };
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid header '{}', expected \"Name: value\"", rule))?;
+    Ok(HeaderRule {
+        pattern,
+        secure_only: false,
+        name: HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| format!("Invalid header name in '{}'", rule))?,
+        value: HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("Invalid header value in '{}'", rule))?,
+    })
+}
+
+/// Adds the site's `ResponseHeaders` to every response, including proxied ones and errors.
+/// The HSTS header of `--security-headers` is only sent on HTTPS listeners.
+pub async fn response_headers_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let headers = req.app_data::<web::Data<ResponseHeaders>>().cloned();
+    let path = req.path().to_string();
+    let secure = req.app_config().secure();
+    let mut res = next.call(req).await?;
+    if let Some(headers) = headers {
+        for rule in &headers.rules {
+            if rule.secure_only && !secure {
+                continue;
+            }
+            if rule.pattern.as_ref().is_none_or(|p| p.is_match(&path)) {
+                res.headers_mut().insert(rule.name.clone(), rule.value.clone());
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, test, App, HttpResponse};
+
+    async fn call(headers: ResponseHeaders, uri: &str) -> ServiceResponse<impl MessageBody> {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(headers))
+                .wrap(from_fn(response_headers_middleware))
+                .default_service(web::to(|| async {
+                    HttpResponse::Ok().insert_header(("x-frame-options", "DENY")).finish()
+                })),
+        )
+        .await;
+        test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await
+    }
+
+    #[actix_web::test]
+    async fn test_global_header() {
+        let headers = ResponseHeaders::new(&["X-Custom: hello: world".to_string()], false, None).unwrap();
+        let resp = call(headers, "/any").await;
+        assert_eq!(resp.headers().get("x-custom").unwrap(), "hello: world");
+        assert!(!resp.headers().contains_key("content-security-policy"));
+    }
+
+    #[actix_web::test]
+    async fn test_path_scoped_header() {
+        let headers = ResponseHeaders::new(
+            &["/fonts/**=Access-Control-Allow-Origin: *".to_string()],
+            false,
+            None,
+        )
+        .unwrap();
+        let resp = call(headers, "/fonts/a.woff2").await;
+        assert_eq!(resp.headers().get("access-control-allow-origin").unwrap(), "*");
+        let headers = ResponseHeaders::new(
+            &["/fonts/**=Access-Control-Allow-Origin: *".to_string()],
+            false,
+            None,
+        )
+        .unwrap();
+        let resp = call(headers, "/index.html").await;
+        assert!(!resp.headers().contains_key("access-control-allow-origin"));
+    }
+
+    #[actix_web::test]
+    async fn test_regex_scoped_header_with_colon() {
+        let rule = r"~^/(?:api|v2)/=X-A: 1".to_string();
+        let headers = ResponseHeaders::new(std::slice::from_ref(&rule), false, None).unwrap();
+        let resp = call(headers, "/v2/users").await;
+        assert_eq!(resp.headers().get("x-a").unwrap(), "1");
+        let headers = ResponseHeaders::new(&[rule], false, None).unwrap();
+        let resp = call(headers, "/other").await;
+        assert!(!resp.headers().contains_key("x-a"));
+        // 没有路径规则时值里可以有 =
+        let headers = ResponseHeaders::new(&["Cache-Control: max-age=60".to_string()], false, None).unwrap();
+        let resp = call(headers, "/any").await;
+        assert_eq!(resp.headers().get("cache-control").unwrap(), "max-age=60");
+    }
+
+    #[actix_web::test]
+    async fn test_security_headers_preset() {
+        let headers = ResponseHeaders::new(&[], true, None).unwrap();
+        let resp = call(headers, "/").await;
+        // 测试请求不是 HTTPS，不发送 HSTS
+        assert!(!resp.headers().contains_key("strict-transport-security"));
+        assert_eq!(resp.headers().get("x-content-type-options").unwrap(), "nosniff");
+        // 预设覆盖响应中已有的值
+        assert_eq!(resp.headers().get("x-frame-options").unwrap(), "SAMEORIGIN");
+        assert_eq!(
+            resp.headers().get("content-security-policy").unwrap(),
+            DEFAULT_CONTENT_SECURITY_POLICY
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_custom_csp_and_override_preset() {
+        let headers = ResponseHeaders::new(
+            &["X-Frame-Options: DENY".to_string()],
+            true,
+            Some("default-src 'none'"),
+        )
+        .unwrap();
+        let resp = call(headers, "/").await;
+        assert_eq!(resp.headers().get("content-security-policy").unwrap(), "default-src 'none'");
+        assert_eq!(resp.headers().get("x-frame-options").unwrap(), "DENY");
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_header_rule() {
+        assert!(ResponseHeaders::new(&["NoColon".to_string()], false, None).is_err());
+        assert!(ResponseHeaders::new(&["Bad Name: v".to_string()], false, None).is_err());
+    }
+}
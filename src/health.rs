@@ -0,0 +1,228 @@
+use std::fs::read_dir;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+use futures::future::join_all;
+use serde_json::{json, Map, Value};
+use url::Url;
+
+use crate::cli::ReadyCheck;
+use crate::upstream::{Upstream, UpstreamPool};
+
+// 探测上游的超时时间
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What `/_ready` verifies, collected from every site.
+pub struct HealthCheck {
+    pub checks: Vec<ReadyCheck>,
+    pub root_paths: Vec<PathBuf>,
+    pub upstreams: Vec<Upstream>,
+}
+
+/// Liveness: the process is up and serving requests.
+pub async fn health() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+/// Readiness: runs the configured checks, 503 if any of them fails.
+pub async fn ready(health_check: web::Data<HealthCheck>) -> HttpResponse {
+    let mut results = Map::new();
+    let mut ok = true;
+    if health_check.checks.contains(&ReadyCheck::Root) {
+        for path in &health_check.root_paths {
+            let readable = read_dir(path).is_ok();
+            ok &= readable;
+            results.insert(path.display().to_string(), status(readable));
+        }
+    }
+    if health_check.checks.contains(&ReadyCheck::Upstreams) {
+        let answers = join_all(health_check.upstreams.iter().map(|upstream| async {
+            let client = upstream.client(UPSTREAM_TIMEOUT);
+            client.get(upstream_probe_url(&upstream.parsed).as_str()).send().await
+        }))
+        .await;
+        // 上游有任何 HTTP 响应即视为可用
+        for (upstream, answer) in health_check.upstreams.iter().zip(answers) {
+            ok &= answer.is_ok();
+            results.insert(upstream.url.clone(), status(answer.is_ok()));
+        }
+    }
+    let body = json!({
+        "status": if ok { "ok" } else { "fail" },
+        "checks": results,
+    });
+    if ok {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// Proxy rules listed by the upstream status endpoint.
+pub struct UpstreamStatus {
+    /// (origin path, websocket, upstreams)
+    pub proxies: Vec<(String, bool, UpstreamPool)>,
+}
+
+/// Status of every proxy upstream: up, down (active check) or ejected (failed requests).
+pub async fn upstreams(status: web::Data<UpstreamStatus>) -> HttpResponse {
+    let proxies: Vec<Value> = status
+        .proxies
+        .iter()
+        .map(|(origin, websocket, pool)| {
+            let upstreams: Vec<Value> = pool
+                .upstreams
+                .iter()
+                .map(|upstream| {
+                    let state = if upstream.is_down() {
+                        "down"
+                    } else if upstream.is_ejected() {
+                        "ejected"
+                    } else {
+                        "up"
+                    };
+                    json!({
+                        "url": upstream.url,
+                        "status": state,
+                        "active_connections": upstream.active_connections(),
+                        "consecutive_fails": upstream.consecutive_fails(),
+                        "health_check": upstream.last_check(),
+                    })
+                })
+                .collect();
+            json!({
+                "origin": origin,
+                "websocket": websocket,
+                "health_check": pool.active_check.as_ref().map(|check| check.path.clone()),
+                "upstreams": upstreams,
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({ "proxies": proxies }))
+}
+
+fn status(ok: bool) -> Value {
+    Value::from(if ok { "ok" } else { "fail" })
+}
+
+/// Maps websocket targets to the HTTP URL that answers the probe.
+pub fn upstream_probe_url(target: &Url) -> Url {
+    let mut url = target.clone();
+    let scheme = match target.scheme() {
+        "ws" => "http",
+        "wss" => "https",
+        other => other,
+    };
+    let _ = url.set_scheme(scheme);
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::CheckResult;
+    use actix_web::{test, App};
+
+    fn make_app_data(checks: Vec<ReadyCheck>, root: PathBuf, upstreams: Vec<Upstream>) -> web::Data<HealthCheck> {
+        web::Data::new(HealthCheck {
+            checks,
+            root_paths: vec![root],
+            upstreams,
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_health_returns_ok_json() {
+        let app = test::init_service(App::new().route("/_health", web::get().to(health))).await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/_health").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "ok");
+    }
+
+    #[actix_web::test]
+    async fn test_ready_checks_root_path() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(make_app_data(vec![ReadyCheck::Root], tmp.path().to_path_buf(), vec![]))
+                .route("/_ready", web::get().to(ready)),
+        )
+        .await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/_ready").to_request()).await;
+        assert_eq!(resp.status(), 200);
+
+        let missing = tmp.path().join("missing");
+        let app = test::init_service(
+            App::new()
+                .app_data(make_app_data(vec![ReadyCheck::Root], missing, vec![]))
+                .route("/_ready", web::get().to(ready)),
+        )
+        .await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/_ready").to_request()).await;
+        assert_eq!(resp.status(), 503);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "fail");
+    }
+
+    #[actix_web::test]
+    async fn test_ready_reports_unreachable_upstream() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        // 端口 1 上没有服务，连接会被拒绝
+        let upstream = Upstream::parse("http://127.0.0.1:1/").unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(make_app_data(
+                    vec![ReadyCheck::Upstreams],
+                    tmp.path().to_path_buf(),
+                    vec![upstream],
+                ))
+                .route("/_ready", web::get().to(ready)),
+        )
+        .await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/_ready").to_request()).await;
+        assert_eq!(resp.status(), 503);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["checks"]["http://127.0.0.1:1/"], "fail");
+    }
+
+    #[actix_web::test]
+    async fn test_upstreams_status() {
+        let pool = UpstreamPool::new(vec![
+            Upstream::parse("http://127.0.0.1:3000/").unwrap(),
+            Upstream::parse("http://127.0.0.1:3001/").unwrap(),
+        ]);
+        pool.upstreams[1].record_check(CheckResult {
+            ok: false,
+            detail: String::from("status 503"),
+            at: chrono::Local::now(),
+        });
+        let status = web::Data::new(UpstreamStatus {
+            proxies: vec![(String::from("/api"), false, pool)],
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(status)
+                .route("/_upstreams", web::get().to(upstreams)),
+        )
+        .await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/_upstreams").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = test::read_body_json(resp).await;
+        let list = &body["proxies"][0]["upstreams"];
+        assert_eq!(body["proxies"][0]["origin"], "/api");
+        assert_eq!(list[0]["status"], "up");
+        assert_eq!(list[1]["status"], "down");
+        assert_eq!(list[1]["health_check"]["detail"], "status 503");
+    }
+
+    #[actix_web::test]
+    async fn test_upstream_probe_url_for_websocket() {
+        let url = upstream_probe_url(&Url::parse("wss://echo.example.com/ws").unwrap());
+        assert_eq!(url.as_str(), "https://echo.example.com/ws");
+    }
+}
@@ -0,0 +1,427 @@
+use std::fmt;
+#[cfg(unix)]
+use std::fs::remove_file;
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpResponse};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::cli::CliOption;
+use crate::proxy_headers::request_host;
+
+/// Where the server accepts connections: `HOST:PORT` or `unix:/run/hs.sock` (unix only).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(String, u16),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn parse(value: &str) -> Result<ListenAddr, String> {
+        let value = value.trim();
+        #[cfg(not(unix))]
+        if value.starts_with("unix:") {
+            return Err(format!(
+                "Invalid listen address '{}', unix sockets are not supported on this platform",
+                value
+            ));
+        }
+        #[cfg(unix)]
+        if let Some(path) = value.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("Invalid listen address '{}', missing socket path", value));
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        let invalid = || format!("Invalid listen address '{}', expected HOST:PORT or unix:PATH", value);
+        let (host, port) = value.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        // [::1]:8080
+        let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ListenAddr::Tcp(host.to_string(), port))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(host, port) if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            ListenAddr::Tcp(host, port) => write!(f, "{}:{}", host, port),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// One `--listen` entry, eg: `[::]:443;tls;ipv6-only`, `0.0.0.0:8080;h2c` or `0.0.0.0:80;redirect-https`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenSpec {
+    pub addr: ListenAddr,
+    /// HTTPS，证书默认使用 --tls-cert/--tls-key
+    pub tls: bool,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub h2c: bool,
+    /// `[::]` 默认同时接受 IPv4 连接
+    pub ipv6_only: bool,
+    /// 明文请求重定向到第一个 HTTPS 监听
+    pub redirect_https: bool,
+}
+
+impl ListenSpec {
+    pub fn new(addr: ListenAddr) -> ListenSpec {
+        ListenSpec {
+            addr,
+            tls: false,
+            tls_cert: None,
+            tls_key: None,
+            h2c: false,
+            ipv6_only: false,
+            redirect_https: false,
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<ListenSpec, String> {
+        let mut parts = value.split(';');
+        let mut spec = ListenSpec::new(ListenAddr::parse(parts.next().unwrap_or_default())?);
+        for option in parts.map(str::trim).filter(|option| !option.is_empty()) {
+            let (key, option_value) = option.split_once('=').unwrap_or((option, ""));
+            match (key.trim(), option_value.trim()) {
+                ("tls", "") => spec.tls = true,
+                ("tls-cert", path) if !path.is_empty() => spec.tls_cert = Some(path.to_string()),
+                ("tls-key", path) if !path.is_empty() => spec.tls_key = Some(path.to_string()),
+                ("h2c", "") => spec.h2c = true,
+                ("ipv6-only", "") => spec.ipv6_only = true,
+                ("redirect-https", "") => spec.redirect_https = true,
+                _ => return Err(format!("Invalid listen option '{}' in '{}'", option, value)),
+            }
+        }
+        // 单独给出证书即表示 HTTPS
+        spec.tls |= spec.tls_cert.is_some() || spec.tls_key.is_some();
+        if spec.tls_cert.is_some() != spec.tls_key.is_some() {
+            return Err(format!("Listen '{}' needs both tls-cert and tls-key", value));
+        }
+        #[cfg(unix)]
+        if let ListenAddr::Unix(_) = spec.addr {
+            if spec.tls || spec.h2c {
+                return Err(format!("Listen '{}': unix sockets only serve plain HTTP/1.1", value));
+            }
+        }
+        if spec.tls && spec.redirect_https {
+            return Err(format!("Listen '{}' already serves HTTPS, remove redirect-https", value));
+        }
+        // HTTPS 通过 ALPN 协商 HTTP/2
+        if spec.tls && spec.h2c {
+            return Err(format!("Listen '{}' already serves HTTP/2 over HTTPS, remove h2c", value));
+        }
+        Ok(spec)
+    }
+
+    /// Certificate and key of an HTTPS listener, its own or `--tls-cert`/`--tls-key`.
+    pub fn certificate<'a>(&'a self, options: &'a CliOption) -> Option<(&'a str, &'a str)> {
+        if !self.tls {
+            return None;
+        }
+        let cert = self.tls_cert.as_ref().or(options.tls_cert.as_ref())?;
+        let key = self.tls_key.as_ref().or(options.tls_key.as_ref())?;
+        Some((cert, key))
+    }
+}
+
+/// Every listener of `options`: the `--listen` entries, or `--host` and `--port`.
+pub fn listen_specs(options: &CliOption) -> Result<Vec<ListenSpec>, String> {
+    if options.listen.is_empty() {
+        let mut spec = ListenSpec::new(ListenAddr::Tcp(options.host.clone(), options.port));
+        spec.tls = options.tls_cert.is_some();
+        spec.h2c = options.h2c && !spec.tls;
+        return Ok(vec![spec]);
+    }
+    let mut specs = options
+        .listen
+        .iter()
+        .map(|value| ListenSpec::parse(value))
+        .collect::<Result<Vec<_>, _>>()?;
+    for spec in specs.iter_mut() {
+        if spec.tls && spec.certificate(options).is_none() {
+            return Err(format!("Listen {} uses TLS without a certificate, set --tls-cert and --tls-key", spec.addr));
+        }
+        // --h2c 对所有明文 TCP 监听生效
+        spec.h2c |= options.h2c && !spec.tls && matches!(spec.addr, ListenAddr::Tcp(..));
+    }
+    let has_https = specs.iter().any(|spec| spec.tls);
+    if let Some(spec) = specs.iter().find(|spec| spec.redirect_https && !has_https) {
+        return Err(format!("Listen {} redirects to HTTPS but no listener uses tls", spec.addr));
+    }
+    Ok(specs)
+}
+
+/// A bound listening socket, kept across reloads so no connection is refused.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn bind(spec: &ListenSpec) -> io::Result<Listener> {
+        match &spec.addr {
+            ListenAddr::Tcp(host, port) => bind_tcp(host, *port, spec.ipv6_only).map(Listener::Tcp),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => Ok(Listener::Unix(bind_unix(path)?, path.clone())),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Listener> {
+        match self {
+            Listener::Tcp(listener) => listener.try_clone().map(Listener::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => Ok(Listener::Unix(listener.try_clone()?, path.clone())),
+        }
+    }
+
+    /// Removes the socket file of a unix listener, called on exit.
+    pub fn remove_socket(&self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+/// Binds every listener, the unix socket files already created are removed when one fails.
+pub fn bind_all(specs: &[ListenSpec]) -> io::Result<Vec<Listener>> {
+    let mut listeners = vec![];
+    for spec in specs {
+        match Listener::bind(spec) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                listeners.iter().for_each(Listener::remove_socket);
+                return Err(io::Error::new(e.kind(), format!("can not listen on {}: {}", spec.addr, e)));
+            }
+        }
+    }
+    Ok(listeners)
+}
+
+fn bind_tcp(host: &str, port: u16, ipv6_only: bool) -> io::Result<TcpListener> {
+    let addr = (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid host '{}'", host))
+    })?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // 不依赖系统的 net.ipv6.bindv6only 设置
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    // 上次没有正常退出时残留的 socket 文件，没有进程在监听才删除
+    let stale = path.metadata().is_ok_and(|meta| meta.file_type().is_socket());
+    if stale {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is used by another process", path.display()),
+            ));
+        }
+        remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Connection data of a plain listener with `redirect-https`: the port of the HTTPS listener.
+#[derive(Clone, Copy)]
+pub struct HttpsRedirect(pub u16);
+
+/// Local addresses of the listeners redirecting to HTTPS.
+#[derive(Clone, Default)]
+pub struct HttpsRedirects(pub Vec<(SocketAddr, u16)>);
+
+impl HttpsRedirects {
+    /// The HTTPS port when a connection accepted on `local` has to be redirected.
+    pub fn port_for(&self, local: SocketAddr) -> Option<u16> {
+        // 监听 0.0.0.0/[::] 时连接的本地地址是具体的 IP
+        self.0
+            .iter()
+            .find(|(addr, _)| {
+                addr.port() == local.port() && (addr.ip().is_unspecified() || addr.ip() == local.ip())
+            })
+            .map(|(_, port)| *port)
+    }
+}
+
+/// Redirects requests received on a `redirect-https` listener to the same URL over HTTPS.
+/// ACME HTTP-01 challenges are still answered over HTTP.
+pub async fn https_redirect_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let redirect = req.conn_data::<HttpsRedirect>().copied();
+    let Some(HttpsRedirect(port)) = redirect.filter(|_| !req.path().starts_with("/.well-known/acme-challenge/"))
+    else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    // 不能用 X-Forwarded-Host/Forwarded，客户端可以伪造成任意地址
+    let host = request_host(req.request()).unwrap_or_else(|| req.app_config().host().to_string());
+    // 去掉 Host 里的端口，IPv6 保留方括号
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !name.is_empty() && !port.contains(']') => name.to_string(),
+        _ => host,
+    };
+    let port = if port == 443 { String::new() } else { format!(":{}", port) };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    let location = format!("https://{}{}{}", host, port, path);
+    // 非 GET/HEAD 用 308 保留请求方法和请求体
+    let mut res = if matches!(*req.method(), Method::GET | Method::HEAD) {
+        HttpResponse::MovedPermanently()
+    } else {
+        HttpResponse::PermanentRedirect()
+    };
+    let res = res.insert_header((header::LOCATION, location)).finish();
+    Ok(req.into_response(res).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_parse_listen_addr() {
+        #[cfg(unix)]
+        assert_eq!(
+            ListenAddr::parse("unix:/run/hs.sock").unwrap(),
+            ListenAddr::Unix(PathBuf::from("/run/hs.sock"))
+        );
+        #[cfg(not(unix))]
+        assert!(ListenAddr::parse("unix:/run/hs.sock").unwrap_err().contains("not supported on this platform"));
+        assert_eq!(
+            ListenAddr::parse("127.0.0.1:8080").unwrap(),
+            ListenAddr::Tcp(String::from("127.0.0.1"), 8080)
+        );
+        let ipv6 = ListenAddr::parse("[::1]:443").unwrap();
+        assert_eq!(ipv6, ListenAddr::Tcp(String::from("::1"), 443));
+        assert_eq!(ipv6.to_string(), "[::1]:443");
+        assert!(ListenAddr::parse("unix:").is_err());
+        assert!(ListenAddr::parse("8080").is_err());
+        assert!(ListenAddr::parse(":8080").is_err());
+        assert!(ListenAddr::parse("localhost:http").is_err());
+    }
+
+    #[test]
+    fn test_parse_listen_spec() {
+        let spec = ListenSpec::parse("[::]:443;tls;ipv6-only").unwrap();
+        assert_eq!(spec.addr, ListenAddr::Tcp(String::from("::"), 443));
+        assert!(spec.tls && !spec.h2c && spec.ipv6_only && !spec.redirect_https);
+        assert!(ListenSpec::parse("0.0.0.0:8080;h2c").unwrap().h2c);
+        let spec = ListenSpec::parse("0.0.0.0:8443; tls-cert=a.pem; tls-key=a.key").unwrap();
+        assert!(spec.tls);
+        assert_eq!(spec.tls_key.as_deref(), Some("a.key"));
+        assert!(ListenSpec::parse("0.0.0.0:80;redirect-https").unwrap().redirect_https);
+        assert!(ListenSpec::parse("0.0.0.0:80;gzip").is_err());
+        assert!(ListenSpec::parse("0.0.0.0:443;tls-cert=a.pem").is_err());
+        assert!(ListenSpec::parse("0.0.0.0:443;tls;redirect-https").is_err());
+        assert!(ListenSpec::parse("[::]:443;tls;h2c").is_err());
+        assert!(ListenSpec::parse("0.0.0.0:8443;tls-cert=a.pem;tls-key=a.key;h2c").is_err());
+        assert!(ListenSpec::parse("unix:/run/hs.sock;tls").is_err());
+    }
+
+    #[test]
+    fn test_listen_specs() {
+        let options = |args: &[&str]| CliOption::parse_from(std::iter::once("hs").chain(args.iter().copied()));
+        // 没有 --listen 时使用 --host 和 --port
+        let specs = listen_specs(&options(&["--port", "9000", "--h2c"])).unwrap();
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].addr, ListenAddr::Tcp(String::from("0.0.0.0"), 9000));
+        assert!(specs[0].h2c && !specs[0].tls);
+
+        let specs = listen_specs(&options(&[
+            "--tls-cert",
+            "c.pem",
+            "--tls-key",
+            "k.pem",
+            "--h2c",
+            "--listen",
+            "0.0.0.0:80;redirect-https",
+            "--listen",
+            "[::]:443;tls",
+        ]))
+        .unwrap();
+        assert_eq!(specs.len(), 2);
+        assert!(specs[0].h2c && !specs[1].h2c);
+        assert_eq!(specs[1].certificate(&options(&["--tls-cert", "c.pem", "--tls-key", "k.pem"])), Some(("c.pem", "k.pem")));
+        // unix socket 只用 HTTP/1.1
+        #[cfg(unix)]
+        assert!(!listen_specs(&options(&["--h2c", "--listen", "unix:/run/hs.sock"])).unwrap()[0].h2c);
+
+        assert!(listen_specs(&options(&["--listen", "0.0.0.0:443;tls"])).is_err());
+        assert!(listen_specs(&options(&["--listen", "0.0.0.0:80;redirect-https"])).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_all_reports_address_in_use() {
+        let used = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = used.local_addr().unwrap().port();
+        let tmp = tempfile::TempDir::new().unwrap();
+        let socket = tmp.path().join("hs.sock");
+        let specs = [
+            ListenSpec::new(ListenAddr::Unix(socket.clone())),
+            ListenSpec::new(ListenAddr::Tcp(String::from("127.0.0.1"), port)),
+        ];
+        let err = bind_all(&specs).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(err.to_string().starts_with(&format!("can not listen on 127.0.0.1:{}", port)), "{}", err);
+        // 已创建的 socket 文件被清理
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn test_https_redirect_port_for() {
+        let redirects = HttpsRedirects(vec![
+            ("0.0.0.0:80".parse().unwrap(), 443),
+            ("127.0.0.1:8080".parse().unwrap(), 8443),
+        ]);
+        assert_eq!(redirects.port_for("10.0.0.5:80".parse().unwrap()), Some(443));
+        assert_eq!(redirects.port_for("127.0.0.1:8080".parse().unwrap()), Some(8443));
+        assert_eq!(redirects.port_for("10.0.0.5:8080".parse().unwrap()), None);
+        assert_eq!(redirects.port_for("10.0.0.5:443".parse().unwrap()), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_unix_replaces_stale_socket() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("hs.sock");
+        let spec = ListenSpec::new(ListenAddr::Unix(path.clone()));
+        let listener = Listener::bind(&spec).unwrap();
+        // 仍在监听时不能再绑定
+        assert_eq!(Listener::bind(&spec).err().unwrap().kind(), io::ErrorKind::AddrInUse);
+        // 进程异常退出后残留的文件
+        drop(listener);
+        assert!(path.exists());
+        let listener = Listener::bind(&spec).unwrap();
+        listener.remove_socket();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,329 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, IsTerminal, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use chrono::{DateTime, Local};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::cli::{CliOption, LogRotate};
+use crate::logger::LogLevel;
+
+/// When and how log files are rotated.
+#[derive(Clone, Copy, Debug)]
+pub struct RotateOptions {
+    /// 0 表示不按大小切分
+    pub max_size: u64,
+    pub rotate: LogRotate,
+    /// 保留的历史文件个数：file.1 ... file.N
+    pub retain: usize,
+    pub compress: bool,
+}
+
+impl RotateOptions {
+    // 当前时间段，变化时切分
+    fn period(&self, time: DateTime<Local>) -> Option<String> {
+        match self.rotate {
+            LogRotate::Never => None,
+            LogRotate::Hourly => Some(time.format("%Y%m%d%H").to_string()),
+            LogRotate::Daily => Some(time.format("%Y%m%d").to_string()),
+        }
+    }
+}
+
+/// Log file that moves itself to `file.1` (or `file.1.gz`) when it grows too big or a new
+/// hour/day starts, older files are shifted to `file.2` ... and dropped after `retain`.
+pub struct RotatingFile {
+    path: PathBuf,
+    options: RotateOptions,
+    writer: BufWriter<File>,
+    size: u64,
+    period: Option<String>,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, options: RotateOptions) -> io::Result<RotatingFile> {
+        let file = open_append(path)?;
+        let metadata = file.metadata()?;
+        // 以文件最后修改时间所在的时间段为准，重启后也能按时切分
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Local>::from)
+            .unwrap_or_else(|_| Local::now());
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            options,
+            writer: BufWriter::new(file),
+            size: metadata.len(),
+            period: options.period(modified),
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let period = self.options.period(Local::now());
+        let too_big = self.options.max_size > 0 && self.size > 0 && self.size + len > self.options.max_size;
+        if too_big || period != self.period {
+            self.rotate()?;
+            self.period = period;
+        }
+        writeln!(self.writer, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Opens the path again, after an external tool like logrotate moved the file away.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let file = open_append(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.writer = BufWriter::new(file);
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let suffix = if self.options.compress { ".gz" } else { "" };
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}{}", self.path.display(), n, suffix));
+        if self.options.retain > 0 {
+            let _ = fs::remove_file(rotated(self.options.retain));
+            for n in (1..self.options.retain).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            let first = PathBuf::from(format!("{}.1", self.path.display()));
+            fs::rename(&self.path, &first)?;
+            if self.options.compress {
+                gzip(&first, &rotated(1))?;
+                fs::remove_file(&first)?;
+            }
+        }
+        // retain 为 0 时直接清空
+        self.writer = BufWriter::new(File::create(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn gzip(source: &Path, target: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(target)?, Compression::default());
+    io::copy(&mut File::open(source)?, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+// stdout 是否为终端，只检查一次
+static STDOUT_IS_TERMINAL: LazyLock<bool> = LazyLock::new(|| io::stdout().is_terminal());
+
+/// Where log lines go.
+pub enum Output {
+    Stdout(BufWriter<Stdout>),
+    File(RotatingFile),
+}
+
+impl Output {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stdout(writer) => writeln!(writer, "{}", line),
+            Output::File(file) => file.write_line(line),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(writer) => writer.flush(),
+            Output::File(file) => file.flush(),
+        }
+    }
+
+    fn is_terminal(&self) -> bool {
+        match self {
+            Output::Stdout(_) => *STDOUT_IS_TERMINAL,
+            Output::File(_) => false,
+        }
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(_) => Ok(()),
+            Output::File(file) => file.reopen(),
+        }
+    }
+}
+
+/// Outputs of the logger thread: access and info lines go to `main`,
+/// warn and error lines to `error` when `--error-log` is set.
+pub struct LogOutputs {
+    main: Output,
+    error: Option<Output>,
+}
+
+impl LogOutputs {
+    pub fn stdout() -> LogOutputs {
+        LogOutputs {
+            main: Output::Stdout(BufWriter::new(io::stdout())),
+            error: None,
+        }
+    }
+
+    /// Opens the `--log`/`--error-log` files, so a bad path is reported before anything changes.
+    pub fn open(options: &CliOption) -> io::Result<LogOutputs> {
+        let rotate = RotateOptions {
+            max_size: options.log_max_size,
+            rotate: options.log_rotate,
+            retain: options.log_retain,
+            compress: options.log_compress,
+        };
+        let open = |path: &str| {
+            RotatingFile::open(Path::new(path), rotate)
+                .map(Output::File)
+                .map_err(|e| io::Error::new(e.kind(), format!("Can not open log file '{}': {}", path, e)))
+        };
+        let main = match &options.log {
+            Some(path) => open(path)?,
+            None => Output::Stdout(BufWriter::new(io::stdout())),
+        };
+        // 与 --log 相同时共用一个文件
+        let error = match &options.error_log {
+            Some(path) if Some(path) != options.log.as_ref() => Some(open(path)?),
+            _ => None,
+        };
+        Ok(LogOutputs { main, error })
+    }
+
+    pub fn write_line(&mut self, level: LogLevel, line: &str) -> io::Result<()> {
+        match (&mut self.error, level) {
+            (Some(error), LogLevel::Warn | LogLevel::Error) => error.write_line(line),
+            _ => self.main.write_line(line),
+        }
+    }
+
+    /// Whether a line of this level goes to a terminal, where the pretty format adds colors.
+    pub fn is_terminal(&self, level: LogLevel) -> bool {
+        match (&self.error, level) {
+            (Some(error), LogLevel::Warn | LogLevel::Error) => error.is_terminal(),
+            _ => self.main.is_terminal(),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.main.flush()?;
+        self.error.as_mut().map_or(Ok(()), Output::flush)
+    }
+
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.main.reopen()?;
+        self.error.as_mut().map_or(Ok(()), Output::reopen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+    use tempfile::TempDir;
+
+    fn options(max_size: u64, retain: usize, compress: bool) -> RotateOptions {
+        RotateOptions {
+            max_size,
+            rotate: LogRotate::Never,
+            retain,
+            compress,
+        }
+    }
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn test_rotate_by_size_keeps_retain_files() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("access.log");
+        let mut file = RotatingFile::open(&path, options(10, 2, false)).unwrap();
+        for line in ["line-1", "line-2", "line-3", "line-4"] {
+            file.write_line(line).unwrap();
+        }
+        file.flush().unwrap();
+        assert_eq!(read(path.clone()), "line-4\n");
+        assert_eq!(read(tmp.path().join("access.log.1")), "line-3\n");
+        assert_eq!(read(tmp.path().join("access.log.2")), "line-2\n");
+        assert!(!tmp.path().join("access.log.3").exists());
+    }
+
+    #[test]
+    fn test_rotated_files_are_gzipped() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("access.log");
+        let mut file = RotatingFile::open(&path, options(10, 3, true)).unwrap();
+        file.write_line("first-line").unwrap();
+        file.write_line("second").unwrap();
+        file.flush().unwrap();
+        assert!(!tmp.path().join("access.log.1").exists());
+        let mut content = String::new();
+        GzDecoder::new(File::open(tmp.path().join("access.log.1.gz")).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "first-line\n");
+    }
+
+    #[test]
+    fn test_rotate_when_period_changes() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("access.log");
+        let mut rotate = options(0, 1, false);
+        rotate.rotate = LogRotate::Daily;
+        let mut file = RotatingFile::open(&path, rotate).unwrap();
+        file.write_line("today").unwrap();
+        // 模拟文件属于前一天
+        file.period = Some(String::from("19700101"));
+        file.write_line("tomorrow").unwrap();
+        file.flush().unwrap();
+        assert_eq!(read(tmp.path().join("access.log.1")), "today\n");
+        assert_eq!(read(path), "tomorrow\n");
+    }
+
+    #[test]
+    fn test_reopen_after_external_move() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("access.log");
+        let mut file = RotatingFile::open(&path, options(0, 0, false)).unwrap();
+        file.write_line("before").unwrap();
+        file.flush().unwrap();
+        fs::rename(&path, tmp.path().join("moved.log")).unwrap();
+        file.reopen().unwrap();
+        file.write_line("after").unwrap();
+        file.flush().unwrap();
+        assert_eq!(read(tmp.path().join("moved.log")), "before\n");
+        assert_eq!(read(path), "after\n");
+    }
+
+    #[test]
+    fn test_warn_and_error_go_to_error_log() {
+        let tmp = TempDir::new().unwrap();
+        let log = tmp.path().join("access.log");
+        let error_log = tmp.path().join("error.log");
+        let cli = CliOption {
+            log: Some(log.to_str().unwrap().to_string()),
+            error_log: Some(error_log.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let mut outputs = LogOutputs::open(&cli).unwrap();
+        outputs.write_line(LogLevel::Info, "GET /").unwrap();
+        outputs.write_line(LogLevel::Warn, "slow").unwrap();
+        outputs.write_line(LogLevel::Error, "failed").unwrap();
+        outputs.flush().unwrap();
+        assert_eq!(read(log), "GET /\n");
+        assert_eq!(read(error_log), "slow\nfailed\n");
+    }
+}
@@ -1,10 +1,20 @@
 use std::fmt;
-use std::io::{self, BufWriter, Write};
-use std::sync::LazyLock;
+use std::pin::Pin;
+use std::sync::{LazyLock, RwLock};
+use std::task::{Context, Poll};
 use std::thread;
 
+use std::time::{Duration, Instant};
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::web::Bytes;
 use chrono::Local;
 use crossbeam_channel::Sender;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::cli::LogFormat;
+use crate::log_file::LogOutputs;
 
 // 定义日志级别
 #[derive(Debug, Clone, Copy)]
@@ -30,7 +40,7 @@ impl LogLevel {
         }
     }
     // 转换为字符串
-    fn to_str(&self) -> &'static str {
+    fn to_str(self) -> &'static str {
         match self {
             LogLevel::Info => "INFO",
             LogLevel::Warn => "WARN",
@@ -39,25 +49,142 @@ impl LogLevel {
     }
 }
 
+/// One access log record, written when the response body is done.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AccessRecord {
+    pub remote_ip: String,
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub protocol: String,
+    pub status: u16,
+    pub bytes: u64,
+    pub latency_ms: f64,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    /// basic auth 认证通过的用户名
+    pub user: Option<String>,
+    /// static、spa、listing、upload、proxy、websocket 或 other
+    pub served_by: &'static str,
+}
+
+impl AccessRecord {
+    fn request_line(&self) -> String {
+        if self.query.is_empty() {
+            format!("{} {} {}", self.method, self.path, self.protocol)
+        } else {
+            format!("{} {}?{} {}", self.method, self.path, self.query, self.protocol)
+        }
+    }
+}
+
+// 日志内容
+enum LogBody {
+    Text(String),
+    Access(Box<AccessRecord>),
+}
+
 // 定义日志消息结构
 pub struct LogMessage {
     timestamp: chrono::DateTime<Local>,
     level: LogLevel,
-    message: String
+    format: LogFormat,
+    body: LogBody,
+    // 仅输出到终端时加颜色，由日志线程设置
+    color: bool,
 }
 
 // 实现日志消息的格式化
 impl fmt::Display for LogMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let timestamp = self.timestamp.to_rfc3339();
-        write!(f, "[{} \x1b[32m{}\x1b[0m] {}", timestamp, self.level.to_str(), self.message)
+        match (&self.body, self.format) {
+            (body, LogFormat::Json) => {
+                let mut line = match body {
+                    LogBody::Text(message) => json!({ "message": message }),
+                    LogBody::Access(record) => {
+                        let mut value = serde_json::to_value(record).unwrap_or(Value::Null);
+                        value["type"] = Value::from("access");
+                        value
+                    }
+                };
+                line["timestamp"] = Value::from(timestamp);
+                line["level"] = Value::from(self.level.to_str());
+                write!(f, "{}", line)
+            }
+            (LogBody::Access(record), LogFormat::Common | LogFormat::Combined) => {
+                // 10/Oct/2000:13:55:36 +0800
+                write!(
+                    f,
+                    "{} - {} [{}] \"{}\" {} {}",
+                    record.remote_ip,
+                    record.user.as_deref().unwrap_or("-"),
+                    self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+                    escape_quoted(&record.request_line()),
+                    record.status,
+                    if record.bytes == 0 { String::from("-") } else { record.bytes.to_string() },
+                )?;
+                if self.format == LogFormat::Combined {
+                    write!(
+                        f,
+                        " \"{}\" \"{}\"",
+                        escape_quoted(record.referer.as_deref().unwrap_or("-")),
+                        escape_quoted(record.user_agent.as_deref().unwrap_or("-")),
+                    )?;
+                }
+                Ok(())
+            }
+            (LogBody::Access(record), _) => write!(
+                f,
+                "[{} {}] {} \"{} {}\" {} {:.0}ms {}",
+                timestamp,
+                self.level_tag(),
+                record.remote_ip,
+                record.method,
+                record.path,
+                record.status,
+                record.latency_ms,
+                record.served_by,
+            ),
+            (LogBody::Text(message), _) => {
+                write!(f, "[{} {}] {}", timestamp, self.level_tag(), message)
+            }
+        }
+    }
+}
+
+impl LogMessage {
+    fn level_tag(&self) -> String {
+        if self.color {
+            format!("\x1b[32m{}\x1b[0m", self.level.to_str())
+        } else {
+            self.level.to_str().to_string()
+        }
     }
 }
 
+// 引号内的 " 和 \ 需要转义
+fn escape_quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// 发给日志线程的指令
+enum LogCommand {
+    Message(LogMessage),
+    // 写完之前的日志后回复
+    Flush(Sender<()>),
+    // 切换到新的输出（启动和热加载时）
+    Outputs(Box<LogOutputs>),
+    // 重新打开日志文件（SIGUSR1，仅 unix）
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Reopen,
+}
+
 // 日志记录器
 pub struct Logger {
     level: LogLevel,
-    sender: Sender<LogMessage>,
+    format: RwLock<LogFormat>,
+    sender: Sender<LogCommand>,
 }
 
 impl Logger {
@@ -70,30 +197,90 @@ impl Logger {
         // 启动日志线程
         thread::spawn(move || {
             // BufWriter 减少 write syscall 次数
-            let mut output = BufWriter::new(io::stdout());
+            let mut outputs = LogOutputs::stdout();
 
-            // 从通道接收日志消息并写入
-            for msg in receiver {
-                writeln!(output, "{}", msg).expect("Failed to write log");
+            // 从通道接收日志消息并写入，通道空闲时刷新
+            for command in receiver.iter() {
+                let result = match command {
+                    LogCommand::Message(mut msg) => {
+                        msg.color = outputs.is_terminal(msg.level);
+                        outputs
+                            .write_line(msg.level, &msg.to_string())
+                            .and_then(|_| if receiver.is_empty() { outputs.flush() } else { Ok(()) })
+                    }
+                    LogCommand::Flush(done) => {
+                        let result = outputs.flush();
+                        let _ = done.send(());
+                        result
+                    }
+                    LogCommand::Outputs(new_outputs) => {
+                        let _ = outputs.flush();
+                        outputs = *new_outputs;
+                        Ok(())
+                    }
+                    LogCommand::Reopen => outputs.reopen(),
+                };
+                // 日志本身写不进去时只能输出到 stderr
+                if let Err(e) = result {
+                    eprintln!("Failed to write log: {}", e);
+                }
             }
         });
 
         Logger {
             sender,
             level: LogLevel::from_str(log_level),
+            format: RwLock::new(LogFormat::Pretty),
         }
     }
 
-    // 记录日志
-    pub fn log(&self, level: LogLevel, message: String) {
+    // 设置输出格式，启动和热加载时调用
+    pub fn set_format(&self, format: LogFormat) {
+        *self.format.write().unwrap() = format;
+    }
+
+    fn send(&self, level: LogLevel, body: LogBody) {
         if (level as i32) >= (self.level as i32) {
             let msg = LogMessage {
                 level,
-                message,
+                format: *self.format.read().unwrap(),
+                body,
+                color: false,
                 // 直接获取本地时间，避免 Utc→Local 二次转换
                 timestamp: Local::now(),
             };
-            self.sender.send(msg).expect("Failed to send log message");
+            self.sender
+                .send(LogCommand::Message(msg))
+                .expect("Failed to send log message");
+        }
+    }
+
+    // 记录日志
+    pub fn log(&self, level: LogLevel, message: String) {
+        self.send(level, LogBody::Text(message));
+    }
+
+    // 记录一条访问日志
+    pub fn access(&self, record: AccessRecord) {
+        self.send(LogLevel::Info, LogBody::Access(Box::new(record)));
+    }
+
+    // 之后的日志写到新的输出
+    pub fn set_outputs(&self, outputs: LogOutputs) {
+        let _ = self.sender.send(LogCommand::Outputs(Box::new(outputs)));
+    }
+
+    // 重新打开日志文件，配合外部 logrotate 使用
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn reopen(&self) {
+        let _ = self.sender.send(LogCommand::Reopen);
+    }
+
+    // 等待已提交的日志写完，退出前调用
+    pub fn flush(&self) {
+        let (done, wait) = crossbeam_channel::bounded(1);
+        if self.sender.send(LogCommand::Flush(done)).is_ok() {
+            let _ = wait.recv_timeout(Duration::from_secs(1));
         }
     }
 
@@ -103,17 +290,140 @@ impl Logger {
     }
 
     // 快捷方法：记录 WARN 日志
-    // pub fn warn(&self, message: String) {
-    //     self.log(LogLevel::Warn, message);
-    // }
+    pub fn warn(&self, message: String) {
+        self.log(LogLevel::Warn, message);
+    }
 
-    // // 快捷方法：记录 ERROR 日志
-    // pub fn error(&self, message: String) {
-    //     self.log(LogLevel::Error, message);
-    // }
+    // 快捷方法：记录 ERROR 日志
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn error(&self, message: String) {
+        self.log(LogLevel::Error, message);
+    }
 }
 
 // 日志单例
 
 // 定义日志单例
-pub static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger::init_from_env());
+pub static LOGGER: LazyLock<Logger> = LazyLock::new(Logger::init_from_env);
+
+/// Response body that counts the bytes sent and writes the access record when dropped,
+/// so the record has the final size and the full latency, also for aborted downloads.
+pub struct AccessLogBody {
+    inner: BoxBody,
+    record: AccessRecord,
+    start: Instant,
+}
+
+impl AccessLogBody {
+    pub fn new(inner: BoxBody, record: AccessRecord, start: Instant) -> Self {
+        AccessLogBody { inner, record, start }
+    }
+}
+
+impl MessageBody for AccessLogBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(bytes))) = &poll {
+            self.record.bytes += bytes.len() as u64;
+        }
+        poll
+    }
+}
+
+impl Drop for AccessLogBody {
+    fn drop(&mut self) {
+        let mut record = std::mem::take(&mut self.record);
+        record.latency_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        LOGGER.access(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(format: LogFormat) -> String {
+        let record = AccessRecord {
+            remote_ip: String::from("10.0.0.1"),
+            method: String::from("GET"),
+            path: String::from("/app.js"),
+            query: String::from("v=1"),
+            protocol: String::from("HTTP/1.1"),
+            status: 200,
+            bytes: 1234,
+            latency_ms: 1.5,
+            user_agent: Some(String::from("curl/8.0 \"x\"")),
+            referer: None,
+            user: Some(String::from("admin")),
+            served_by: "static",
+        };
+        LogMessage {
+            timestamp: Local::now(),
+            level: LogLevel::Info,
+            format,
+            body: LogBody::Access(Box::new(record)),
+            color: false,
+        }
+        .to_string()
+    }
+
+    #[test]
+    fn test_json_access_record() {
+        let line: Value = serde_json::from_str(&message(LogFormat::Json)).unwrap();
+        assert_eq!(line["type"], "access");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["remote_ip"], "10.0.0.1");
+        assert_eq!(line["query"], "v=1");
+        assert_eq!(line["bytes"], 1234);
+        assert_eq!(line["user"], "admin");
+        assert_eq!(line["served_by"], "static");
+        assert!(line["referer"].is_null());
+    }
+
+    #[test]
+    fn test_common_and_combined_access_record() {
+        let common = message(LogFormat::Common);
+        assert!(common.starts_with("10.0.0.1 - admin ["), "{}", common);
+        assert!(common.ends_with("] \"GET /app.js?v=1 HTTP/1.1\" 200 1234"), "{}", common);
+        let combined = message(LogFormat::Combined);
+        assert!(combined.ends_with("1234 \"-\" \"curl/8.0 \\\"x\\\"\""), "{}", combined);
+    }
+
+    #[test]
+    fn test_json_text_message() {
+        let msg = LogMessage {
+            timestamp: Local::now(),
+            level: LogLevel::Warn,
+            format: LogFormat::Json,
+            body: LogBody::Text(String::from("reload rejected")),
+            color: false,
+        };
+        let line: Value = serde_json::from_str(&msg.to_string()).unwrap();
+        assert_eq!(line["message"], "reload rejected");
+        assert_eq!(line["level"], "WARN");
+    }
+
+    #[test]
+    fn test_pretty_color_only_for_terminal() {
+        let mut msg = LogMessage {
+            timestamp: Local::now(),
+            level: LogLevel::Info,
+            format: LogFormat::Pretty,
+            body: LogBody::Text(String::from("started")),
+            color: false,
+        };
+        assert!(!msg.to_string().contains('\x1b'), "{}", msg);
+        assert!(msg.to_string().ends_with(" INFO] started"), "{}", msg);
+        msg.color = true;
+        assert!(msg.to_string().contains("\x1b[32mINFO\x1b[0m"), "{}", msg);
+    }
+}
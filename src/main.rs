@@ -1,17 +1,34 @@
 mod server;
 mod cli;
+mod client_ip;
+mod config;
 mod proxy;
+mod proxy_cache;
+mod proxy_mock;
+mod proxy_headers;
+mod proxy_rewrite;
+mod proxy_route;
 mod ws_proxy;
 mod logger;
+mod log_file;
+mod metrics;
+mod headers;
+mod listen;
+mod health;
+mod pattern;
+mod tls;
+mod upstream;
+mod upstream_h2c;
+mod h2c;
 
-use clap::Parser;
-use cli::{CliOption, Commands};
+use cli::Commands;
 
+use crate::config::load_options;
 use crate::server::start_server;
 
 #[actix_web::main]
 async fn main() {
-    let cli_option = CliOption::parse();
+    let cli_option = load_options();
     match cli_option.command {
         Some(Commands::Update {  }) => {
             // TODO
@@ -19,7 +36,10 @@ async fn main() {
         },
         None => {
             let opt = &cli_option;
-            _ = start_server(opt).await
+            if let Err(e) = start_server(opt).await {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
         }
     }
 }
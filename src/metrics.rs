@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::{LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web::Bytes,
+    Error, HttpMessage, HttpRequest, HttpResponse,
+};
+
+use crate::ws_proxy;
+
+// Prometheus 默认的延迟分桶（秒）
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// What kind of route answered a request, set by the handlers in the request extensions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RouteClass {
+    Static,
+    Spa,
+    Listing,
+    Upload,
+    Proxy,
+    Websocket,
+    // 404、认证失败、内置接口等
+    Other,
+}
+
+impl RouteClass {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RouteClass::Static => "static",
+            RouteClass::Spa => "spa",
+            RouteClass::Listing => "listing",
+            RouteClass::Upload => "upload",
+            RouteClass::Proxy => "proxy",
+            RouteClass::Websocket => "websocket",
+            RouteClass::Other => "other",
+        }
+    }
+}
+
+/// Marks the request so the metrics middleware can label it.
+pub fn set_route_class(req: &HttpRequest, class: RouteClass) {
+    req.extensions_mut().insert(class);
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                self.buckets[i] += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Metrics {
+    requests: BTreeMap<(RouteClass, u16), u64>,
+    latency: BTreeMap<RouteClass, Histogram>,
+    bytes_sent: BTreeMap<RouteClass, u64>,
+    proxy_errors: BTreeMap<String, u64>,
+}
+
+static METRICS: LazyLock<Mutex<Metrics>> = LazyLock::new(|| Mutex::new(Metrics::default()));
+
+/// Counts a failed request to a proxy upstream.
+pub fn record_proxy_error(upstream: &str) {
+    *METRICS
+        .lock()
+        .unwrap()
+        .proxy_errors
+        .entry(upstream.to_string())
+        .or_default() += 1;
+}
+
+fn record_request(class: RouteClass, status: u16, seconds: f64) {
+    let mut metrics = METRICS.lock().unwrap();
+    *metrics.requests.entry((class, status)).or_default() += 1;
+    metrics.latency.entry(class).or_default().observe(seconds);
+}
+
+fn record_bytes(class: RouteClass, bytes: usize) {
+    *METRICS.lock().unwrap().bytes_sent.entry(class).or_default() += bytes as u64;
+}
+
+/// Records count, latency and body size of every request, should be the outermost middleware
+/// so the bytes are counted after compression.
+pub async fn metrics_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let res = next.call(req).await?;
+    let class = res
+        .request()
+        .extensions()
+        .get::<RouteClass>()
+        .copied()
+        .unwrap_or(RouteClass::Other);
+    record_request(class, res.status().as_u16(), start.elapsed().as_secs_f64());
+    Ok(res.map_body(|_, body| CountingBody {
+        inner: body.boxed(),
+        class,
+    }))
+}
+
+/// Counts the bytes of a response body while it is streamed.
+struct CountingBody {
+    inner: BoxBody,
+    class: RouteClass,
+}
+
+impl MessageBody for CountingBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let class = self.class;
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(bytes))) = &poll {
+            record_bytes(class, bytes.len());
+        }
+        poll
+    }
+}
+
+/// Prometheus text exposition of all metrics.
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(render())
+}
+
+fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+
+    out.push_str("# HELP hs_requests_total Requests handled, by route class and status.\n");
+    out.push_str("# TYPE hs_requests_total counter\n");
+    for ((class, status), count) in &metrics.requests {
+        let _ = writeln!(
+            out,
+            "hs_requests_total{{class=\"{}\",status=\"{}\"}} {}",
+            class.as_str(),
+            status,
+            count
+        );
+    }
+
+    out.push_str("# HELP hs_request_duration_seconds Time until the response head, by route class.\n");
+    out.push_str("# TYPE hs_request_duration_seconds histogram\n");
+    for (class, histogram) in &metrics.latency {
+        let class = class.as_str();
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            let _ = writeln!(
+                out,
+                "hs_request_duration_seconds_bucket{{class=\"{}\",le=\"{}\"}} {}",
+                class, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "hs_request_duration_seconds_bucket{{class=\"{}\",le=\"+Inf\"}} {}",
+            class, histogram.count
+        );
+        let _ = writeln!(out, "hs_request_duration_seconds_sum{{class=\"{}\"}} {}", class, histogram.sum);
+        let _ = writeln!(out, "hs_request_duration_seconds_count{{class=\"{}\"}} {}", class, histogram.count);
+    }
+
+    out.push_str("# HELP hs_response_bytes_total Response body bytes sent, by route class.\n");
+    out.push_str("# TYPE hs_response_bytes_total counter\n");
+    for (class, bytes) in &metrics.bytes_sent {
+        let _ = writeln!(out, "hs_response_bytes_total{{class=\"{}\"}} {}", class.as_str(), bytes);
+    }
+
+    out.push_str("# HELP hs_proxy_errors_total Failed requests to a proxy upstream.\n");
+    out.push_str("# TYPE hs_proxy_errors_total counter\n");
+    for (upstream, count) in &metrics.proxy_errors {
+        let _ = writeln!(
+            out,
+            "hs_proxy_errors_total{{upstream=\"{}\"}} {}",
+            escape_label(upstream),
+            count
+        );
+    }
+
+    out.push_str("# HELP hs_websocket_sessions Active websocket proxy sessions.\n");
+    out.push_str("# TYPE hs_websocket_sessions gauge\n");
+    let _ = writeln!(out, "hs_websocket_sessions {}", ws_proxy::active_sessions());
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{middleware::from_fn, web, App};
+
+    #[actix_web::test]
+    async fn test_requests_counted_by_route_class() {
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(metrics_middleware))
+                .route(
+                    "/listing",
+                    web::get().to(|req: HttpRequest| async move {
+                        set_route_class(&req, RouteClass::Listing);
+                        HttpResponse::Ok().body("12345")
+                    }),
+                )
+                .route("/_metrics", web::get().to(metrics)),
+        )
+        .await;
+        let resp = call_service(&app, TestRequest::get().uri("/listing").to_request()).await;
+        assert_eq!(read_body(resp).await, "12345");
+
+        let resp = call_service(&app, TestRequest::get().uri("/_metrics").to_request()).await;
+        let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("hs_requests_total{class=\"listing\",status=\"200\"}"), "{}", body);
+        assert!(body.contains("hs_request_duration_seconds_bucket{class=\"listing\",le=\"+Inf\"}"));
+        assert!(body.contains("hs_response_bytes_total{class=\"listing\"}"));
+        assert!(body.contains("hs_websocket_sessions "));
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.003);
+        histogram.observe(0.2);
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[5], 2);
+        assert_eq!(histogram.count, 2);
+    }
+
+    #[test]
+    fn test_proxy_error_label_is_escaped() {
+        record_proxy_error("http://a\"b");
+        assert!(render().contains("hs_proxy_errors_total{upstream=\"http://a\\\"b\"}"));
+    }
+}
@@ -0,0 +1,91 @@
+use fancy_regex::Regex;
+use globset::{GlobBuilder, GlobMatcher};
+
+/// Path matcher used by rules on the command line.
+/// `~` prefix means a regex, otherwise a glob where `*` stays inside one segment and `**`
+/// crosses segments. Globs without a leading `/` match in any directory.
+/// eg: `/assets/**`, `*.html`, `~\.[0-9a-f]{8}\.js$`
+#[derive(Clone, Debug)]
+pub enum PathPattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> Result<PathPattern, String> {
+        if let Some(regex) = pattern.strip_prefix('~') {
+            return Regex::new(regex.trim())
+                .map(PathPattern::Regex)
+                .map_err(|e| format!("Invalid regex '{}': {}", regex, e));
+        }
+        let glob = if pattern.starts_with('/') {
+            pattern.to_string()
+        } else {
+            format!("/**/{}", pattern)
+        };
+        GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .build()
+            .map(|g| PathPattern::Glob(g.compile_matcher()))
+            .map_err(|e| format!("Invalid glob '{}': {}", pattern, e))
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        match self {
+            PathPattern::Glob(glob) => glob.is_match(path),
+            PathPattern::Regex(regex) => regex.is_match(path).unwrap_or(false),
+        }
+    }
+}
+
+/// Splits a `PATTERN=VALUE` rule at the first `=`.
+pub fn split_rule(rule: &str) -> Result<(PathPattern, &str), String> {
+    let (pattern, value) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid rule '{}', expected PATTERN=VALUE", rule))?;
+    Ok((PathPattern::parse(pattern.trim())?, value.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_absolute_glob() {
+        let p = PathPattern::parse("/assets/**").unwrap();
+        assert!(p.is_match("/assets/app.123.js"));
+        assert!(p.is_match("/assets/img/logo.png"));
+        assert!(!p.is_match("/index.html"));
+    }
+
+    #[test]
+    fn test_single_star_stays_in_segment() {
+        let p = PathPattern::parse("/assets/*.js").unwrap();
+        assert!(p.is_match("/assets/app.js"));
+        assert!(!p.is_match("/assets/sub/app.js"));
+    }
+
+    #[test]
+    fn test_relative_glob_matches_any_directory() {
+        let p = PathPattern::parse("*.html").unwrap();
+        assert!(p.is_match("/index.html"));
+        assert!(p.is_match("/docs/guide.html"));
+        assert!(!p.is_match("/app.js"));
+    }
+
+    #[test]
+    fn test_regex_pattern() {
+        let p = PathPattern::parse(r"~\.[0-9a-f]{8}\.js$").unwrap();
+        assert!(p.is_match("/assets/app.1a2b3c4d.js"));
+        assert!(!p.is_match("/assets/app.js"));
+    }
+
+    #[test]
+    fn test_split_rule() {
+        let (p, value) = split_rule("/assets/** = public, max-age=31536000").unwrap();
+        assert!(p.is_match("/assets/a.js"));
+        assert_eq!(value, "public, max-age=31536000");
+        assert!(split_rule("/assets/**").is_err());
+        assert!(split_rule("~(=no-cache").is_err());
+    }
+}
@@ -1,108 +1,952 @@
+use std::cell::Cell;
+#[cfg(unix)]
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
 use actix_web::{
-    error::{self},
-    web, Error, HttpRequest, HttpResponse,
+    error::PayloadError,
+    rt,
+    http::header::{self, HeaderMap, HeaderName, HeaderValue},
+    http::{Method, StatusCode, Version},
+    web::{self, Bytes},
+    Error, HttpRequest, HttpResponse,
 };
-use awc::Client;
+use awc::error::{ConnectError, SendRequestError};
+use awc::{Client, Connector};
+use futures::stream::{self, LocalBoxStream};
+use futures::{Stream, StreamExt};
+use tokio::time::{timeout_at, Instant};
 use url::Url;
 
+use crate::cli::parse_size;
+use crate::client_ip::{client_ip, peer_ip, peer_is_trusted};
+use crate::logger::LOGGER;
+#[cfg(unix)]
+use crate::upstream::unix_connector;
+use crate::upstream::{ActiveCheck, Balancer, StatusMatch, Upstream, UpstreamPool};
+use crate::config::interpolate_env;
+use crate::proxy_cache::{CacheEntry, CacheOptions, Freshness, ProxyCache};
+use crate::upstream_h2c::H2cClient;
+use crate::proxy_mock::{MockFixtures, MockMode};
+use crate::proxy_headers::{request_host, HeaderAction, HostHeader};
+use crate::proxy_rewrite::{PathMapping, ResponseRewrites};
+use crate::proxy_route::RouteMatch;
+use crate::metrics::{record_proxy_error, set_route_class, RouteClass};
 use crate::ws_proxy;
 
+/// Which forwarding headers are sent to the upstream, all by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ForwardedHeaders {
+    pub x_forwarded_for: bool,
+    pub x_forwarded_proto: bool,
+    pub x_forwarded_host: bool,
+    pub x_forwarded_prefix: bool,
+    pub forwarded: bool,
+}
+
+impl Default for ForwardedHeaders {
+    fn default() -> Self {
+        ForwardedHeaders::all(true)
+    }
+}
+
+impl ForwardedHeaders {
+    fn all(enabled: bool) -> Self {
+        ForwardedHeaders {
+            x_forwarded_for: enabled,
+            x_forwarded_proto: enabled,
+            x_forwarded_host: enabled,
+            x_forwarded_prefix: enabled,
+            forwarded: enabled,
+        }
+    }
+
+    /// `all`, `none` or a comma separated list of header names.
+    fn parse(value: &str) -> Result<ForwardedHeaders, String> {
+        match value.trim() {
+            "all" => return Ok(ForwardedHeaders::all(true)),
+            "none" => return Ok(ForwardedHeaders::all(false)),
+            _ => {}
+        }
+        let mut headers = ForwardedHeaders::all(false);
+        for name in value.split(',') {
+            match name.trim().to_lowercase().as_str() {
+                "x-forwarded-for" => headers.x_forwarded_for = true,
+                "x-forwarded-proto" => headers.x_forwarded_proto = true,
+                "x-forwarded-host" => headers.x_forwarded_host = true,
+                "x-forwarded-prefix" => headers.x_forwarded_prefix = true,
+                "forwarded" => headers.forwarded = true,
+                other => return Err(format!("Unknown forwarded header '{}'", other)),
+            }
+        }
+        Ok(headers)
+    }
+}
+
 #[derive(Clone)]
 pub struct ProxyItem {
+    /// 路径前缀，或者 `~` 开头的正则
     pub origin_path: String,
-    /// 原始字符串，仅用于显示/日志
-    pub target_url: String,
-    /// 启动时预解析，避免每次请求重复 parse
-    pub target_url_parsed: Url,
+    pub route: RouteMatch,
+    pub pool: UpstreamPool,
+    /// 幂等请求失败后换其他上游重试的次数
+    pub retries: usize,
+    pub forwarded: ForwardedHeaders,
+    /// 为空时使用默认：HTTP 保留客户端的 Host，websocket 使用目标地址
+    pub host: Option<HostHeader>,
+    pub request_headers: Vec<HeaderAction>,
+    pub response_headers: Vec<HeaderAction>,
+    pub limits: ProxyLimits,
+    pub rewrites: ResponseRewrites,
+    /// `cache=SIZE` 开启，所有 worker 共用
+    pub cache: Option<ProxyCache>,
+    pub mock: Option<MockFixtures>,
+    /// 到上游的 HTTP 版本，为空时 https 通过 ALPN 协商，http 使用 HTTP/1.1
+    pub http_version: Option<Version>,
+}
+
+/// Connections of one worker to the upstreams of a proxy.
+pub struct ProxyClient {
+    http: Client,
+    /// `http-version=2` 时用于 http:// 上游
+    h2c: Option<H2cClient>,
+    /// unix socket 上游各自的连接池
+    #[cfg(unix)]
+    unix: HashMap<PathBuf, Client>,
+}
+
+impl ProxyClient {
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    fn http(&self, upstream: &Upstream) -> &Client {
+        #[cfg(unix)]
+        if let Some(socket) = &upstream.socket {
+            return &self.unix[socket];
+        }
+        &self.http
+    }
+}
+
+/// Timeouts and limits of an HTTP proxy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProxyLimits {
+    pub connect_timeout: Duration,
+    /// 等待响应头以及两次读取响应体之间的最长时间
+    pub read_timeout: Duration,
+    /// 整个请求（包括响应体）的最长时间
+    pub timeout: Option<Duration>,
+    /// 请求体的最大字节数，0 表示不限制
+    pub max_body_size: u64,
+    /// 每个 worker 到上游的最大连接数，0 表示不限制
+    pub max_connections: usize,
+    /// 空闲连接保留的时间
+    pub idle_timeout: Duration,
+}
+
+impl Default for ProxyLimits {
+    fn default() -> Self {
+        ProxyLimits {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(60),
+            timeout: None,
+            max_body_size: 0,
+            max_connections: 100,
+            idle_timeout: Duration::from_secs(15),
+        }
+    }
 }
 
 impl ProxyItem {
-    pub fn new(origin_path: String, target_url: String) -> Self {
-        let target_url_parsed = Url::parse(&target_url)
-            .unwrap_or_else(|e| panic!("Invalid proxy target URL '{}': {}", target_url, e));
-        Self { origin_path, target_url, target_url_parsed }
+    /// Parses `ORIGIN->TARGET[,TARGET...][;option=value...]`, `${VAR}` in the targets and options
+    /// is replaced, a `;` inside a value is written `\;`.
+    /// eg: `/api->http://10.0.0.1:3000,http://10.0.0.2:3000;lb=least-conn;request-header=X-Env: prod`
+    pub fn parse(spec: &str) -> Result<ProxyItem, String> {
+        let (origin_path, rest) = spec
+            .split_once("->")
+            .ok_or_else(|| format!("Invalid proxy '{}', expected eg: /api->http://127.0.0.1:3000", spec))?;
+        let rest = interpolate_env(rest);
+        let mut parts = split_options(&rest).into_iter();
+        let targets = parts.next().unwrap_or_default();
+        let regex = RouteMatch::parse_origin(origin_path.trim())?;
+        // 只有正则规则的目标地址可以引用捕获组
+        let parse_upstream = if regex.is_some() { Upstream::parse_template } else { Upstream::parse };
+        // 只有 mock 时可以不写目标地址
+        let upstreams = if targets.trim().is_empty() {
+            vec![]
+        } else {
+            targets.split(',').map(parse_upstream).collect::<Result<Vec<_>, _>>()?
+        };
+        // 同一规则的上游只能是不同的主机，路径必须一致
+        if upstreams
+            .iter()
+            .any(|u| u.parsed.path() != upstreams[0].parsed.path())
+        {
+            return Err(format!("Upstreams of proxy '{}' must have the same path", spec));
+        }
+        let mut proxy = ProxyItem {
+            origin_path: origin_path.trim().to_string(),
+            route: RouteMatch {
+                regex,
+                ..RouteMatch::default()
+            },
+            pool: UpstreamPool::new(upstreams),
+            retries: 1,
+            forwarded: ForwardedHeaders::default(),
+            host: None,
+            request_headers: vec![],
+            response_headers: vec![],
+            limits: ProxyLimits::default(),
+            rewrites: ResponseRewrites::default(),
+            cache: None,
+            mock: None,
+            http_version: None,
+        };
+        // health-* 选项需要和 health-check 一起使用，cache-* 需要和 cache 一起使用
+        let mut active_check = ActiveCheck::new("");
+        let mut active_check_options = vec![];
+        let mut cache = CacheOptions::new(0);
+        let mut cache_options = vec![];
+        let mut mock_mode = None;
+        for option in parts.filter(|p| !p.trim().is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid proxy option '{}' in '{}', expected key=value", option, spec))?;
+            let in_spec = |e: String| format!("{} in proxy '{}'", e, spec);
+            let number = |value: &str| {
+                value
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| in_spec(format!("Invalid number '{}' for {}", value.trim(), key.trim())))
+            };
+            match key.trim() {
+                "method" => proxy.route.methods = RouteMatch::parse_methods(value).map_err(in_spec)?,
+                "header" => proxy.route.headers.push(RouteMatch::parse_header(value).map_err(in_spec)?),
+                "priority" => {
+                    proxy.route.priority = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| in_spec(format!("Invalid priority '{}'", value.trim())))?
+                }
+                "lb" => proxy.pool.balancer = Balancer::parse(value).map_err(in_spec)?,
+                "max-fails" => proxy.pool.health.max_fails = number(value)? as u32,
+                "fail-timeout" => proxy.pool.health.fail_timeout = Duration::from_secs(number(value)?),
+                "retries" => proxy.retries = number(value)? as usize,
+                "connect-timeout" => proxy.limits.connect_timeout = Duration::from_secs(number(value)?),
+                "read-timeout" => proxy.limits.read_timeout = Duration::from_secs(number(value)?),
+                "timeout" => proxy.limits.timeout = Some(number(value)?).filter(|t| *t > 0).map(Duration::from_secs),
+                "max-body-size" => proxy.limits.max_body_size = parse_size(value).map_err(in_spec)?,
+                "max-connections" => proxy.limits.max_connections = number(value)? as usize,
+                "idle-timeout" => proxy.limits.idle_timeout = Duration::from_secs(number(value)?),
+                "health-check" => {
+                    if !value.trim().starts_with('/') {
+                        return Err(in_spec(format!("Invalid health-check path '{}'", value.trim())));
+                    }
+                    active_check.path = value.trim().to_string();
+                }
+                "health-interval" => active_check.interval = Duration::from_secs(number(value)?.max(1)),
+                "health-timeout" => active_check.timeout = Duration::from_secs(number(value)?.max(1)),
+                "health-status" => active_check.status = StatusMatch::parse(value).map_err(in_spec)?,
+                "cache" => cache.max_size = parse_size(value).map_err(in_spec)?,
+                "cache-max-entry" => cache.max_entry_size = parse_size(value).map_err(in_spec)?,
+                "cache-dir" => cache.dir = Some(PathBuf::from(value.trim())),
+                "cache-dir-size" => cache.dir_size = parse_size(value).map_err(in_spec)?,
+                "cache-ttl" => cache.ttl = Duration::from_secs(number(value)?),
+                "cache-stale-while-revalidate" => cache.stale_while_revalidate = Duration::from_secs(number(value)?),
+                "cache-stale-if-error" => cache.stale_if_error = Duration::from_secs(number(value)?),
+                "mock" => {
+                    proxy.mock = Some(MockFixtures {
+                        dir: PathBuf::from(value.trim()),
+                        mode: MockMode::Fallback,
+                    })
+                }
+                "http-version" => {
+                    proxy.http_version = match value.trim() {
+                        "1.1" => Some(Version::HTTP_11),
+                        "2" | "2.0" => Some(Version::HTTP_2),
+                        other => return Err(in_spec(format!("Invalid http-version '{}', expected 1.1 or 2", other))),
+                    }
+                }
+                "mock-mode" => mock_mode = Some(MockMode::parse(value).map_err(in_spec)?),
+                "redirect" => proxy.rewrites.set("redirect", value).map_err(in_spec)?,
+                "cookie-path" => proxy.rewrites.set("cookie-path", value).map_err(in_spec)?,
+                "cookie-domain" => proxy.rewrites.set("cookie-domain", value).map_err(in_spec)?,
+                "forwarded" => proxy.forwarded = ForwardedHeaders::parse(value).map_err(in_spec)?,
+                "host" => proxy.host = Some(HostHeader::parse(value)),
+                "request-header" => proxy.request_headers.push(HeaderAction::parse("set", value).map_err(in_spec)?),
+                "request-header-add" => proxy.request_headers.push(HeaderAction::parse("add", value).map_err(in_spec)?),
+                "request-header-remove" => {
+                    proxy.request_headers.push(HeaderAction::parse("remove", value).map_err(in_spec)?)
+                }
+                "response-header" => proxy.response_headers.push(HeaderAction::parse("set", value).map_err(in_spec)?),
+                "response-header-add" => {
+                    proxy.response_headers.push(HeaderAction::parse("add", value).map_err(in_spec)?)
+                }
+                "response-header-remove" => {
+                    proxy.response_headers.push(HeaderAction::parse("remove", value).map_err(in_spec)?)
+                }
+                other => return Err(format!("Unknown proxy option '{}' in '{}'", other, spec)),
+            }
+            if key.trim().starts_with("health-") {
+                active_check_options.push(key.trim().to_string());
+            } else if key.trim().starts_with("cache-") {
+                cache_options.push(key.trim().to_string());
+            }
+        }
+        if !active_check.path.is_empty() {
+            proxy.pool.active_check = Some(active_check);
+        } else if let Some(key) = active_check_options.first() {
+            return Err(format!("{} requires health-check in proxy '{}'", key, spec));
+        }
+        if cache.max_size > 0 {
+            let dir = cache.dir.clone().unwrap_or_default();
+            let cache = ProxyCache::new(cache)
+                .map_err(|e| format!("Failed to create cache-dir '{}' of proxy '{}': {}", dir.display(), spec, e))?;
+            proxy.cache = Some(cache);
+        } else if let Some(key) = cache_options.first() {
+            return Err(format!("{} requires cache in proxy '{}'", key, spec));
+        }
+        match (&mut proxy.mock, mock_mode) {
+            (Some(mock), Some(mode)) => mock.mode = mode,
+            (None, Some(_)) => return Err(format!("mock-mode requires mock in proxy '{}'", spec)),
+            _ => {}
+        }
+        if proxy.pool.upstreams.is_empty() && proxy.mock.is_none() {
+            return Err(format!("Proxy '{}' has no target", spec));
+        }
+        Ok(proxy)
+    }
+
+    /// HTTP client with the timeouts and connection pool of this proxy, one per worker.
+    pub fn client(&self) -> ProxyClient {
+        let mut connector = Connector::new()
+            .timeout(self.limits.connect_timeout)
+            .limit(self.limits.max_connections)
+            .conn_keep_alive(self.limits.idle_timeout);
+        if self.http_version == Some(Version::HTTP_11) {
+            connector = connector.max_http_version(Version::HTTP_11);
+        }
+        let http = Client::builder()
+            .connector(connector)
+            .timeout(self.limits.read_timeout)
+            .finish();
+        let h2c = (self.http_version == Some(Version::HTTP_2))
+            .then(|| H2cClient::new(self.limits.connect_timeout, self.limits.read_timeout));
+        #[cfg(unix)]
+        let unix = self
+            .pool
+            .upstreams
+            .iter()
+            .filter_map(|upstream| upstream.socket.clone())
+            .map(|socket| {
+                // 经 unix socket 的明文连接只会是 HTTP/1.1
+                let connector = unix_connector(&socket)
+                    .timeout(self.limits.connect_timeout)
+                    .limit(self.limits.max_connections)
+                    .conn_keep_alive(self.limits.idle_timeout);
+                let client = Client::builder()
+                    .connector(connector)
+                    .timeout(self.limits.read_timeout)
+                    .finish();
+                (socket, client)
+            })
+            .collect();
+        ProxyClient {
+            http,
+            h2c,
+            #[cfg(unix)]
+            unix,
+        }
+    }
+
+    /// Upstream URLs for logs, eg: `http://a:3000, http://b:3000`
+    pub fn targets(&self) -> String {
+        if let (true, Some(mock)) = (self.pool.upstreams.is_empty(), &self.mock) {
+            return format!("mock {}", mock.dir.display());
+        }
+        self.pool
+            .upstreams
+            .iter()
+            .map(|u| u.url.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Path prefix the rule is mounted on, empty for a regex rule which checks the path itself.
+    pub fn scope_path(&self) -> &str {
+        if self.route.regex.is_some() {
+            ""
+        } else {
+            &self.origin_path
+        }
+    }
+
+    /// Whether the origin path is replaced by the target path, ie: the targets end with `/`.
+    fn strips_prefix(&self) -> bool {
+        self.route.regex.is_none() && self.pool.upstreams.first().is_some_and(|u| u.url.ends_with('/'))
+    }
+
+    fn path_mapping(&self) -> PathMapping<'_> {
+        PathMapping {
+            origin_path: &self.origin_path,
+            strips_prefix: self.strips_prefix(),
+            target_path: self.pool.upstreams.first().map_or("/", |u| u.parsed.path()),
+            upstreams: &self.pool.upstreams,
+        }
     }
 }
 
+// 按 ; 拆分，\; 不拆分
+fn split_options(value: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&';') => {
+                parts.last_mut().unwrap().push(';');
+                chars.next();
+            }
+            ';' => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+/// Request headers for the upstream: forwarding headers, then Host, then the proxy's own rules.
+/// Forwarding headers sent by an untrusted client are dropped, also the ones disabled by `forwarded=`.
+pub fn apply_request_headers(req: &HttpRequest, proxy: &ProxyItem, headers: &mut HeaderMap) {
+    // 可信代理给出的头部即使关闭了也原样转发
+    if !peer_is_trusted(req) {
+        for name in FORWARDING_HEADERS {
+            headers.remove(name);
+        }
+    }
+    for (name, value) in forwarded_headers(req, proxy) {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+    if let Some(host) = &proxy.host {
+        host.apply(req, headers);
+    }
+    for action in &proxy.request_headers {
+        action.apply(req, headers);
+    }
+}
+
+fn apply_response_headers(req: &HttpRequest, proxy: &ProxyItem, response: &mut HttpResponse) {
+    for action in &proxy.response_headers {
+        action.apply(req, response.headers_mut());
+    }
+}
+
+// forwarded_headers 可能生成的头部
+const FORWARDING_HEADERS: [&str; 5] = [
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "x-forwarded-prefix",
+    "forwarded",
+];
+
+/// X-Forwarded-* and Forwarded headers for the upstream request.
+/// Values received from a trusted proxy are kept and extended, otherwise they are replaced,
+/// so clients can not inject them.
+pub fn forwarded_headers(req: &HttpRequest, proxy: &ProxyItem) -> Vec<(HeaderName, String)> {
+    let trusted = peer_is_trusted(req);
+    let incoming = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .filter(|_| trusted)
+            .map(String::from)
+    };
+    let peer = peer_ip(req);
+    let scheme = if req.app_config().secure() { "https" } else { "http" };
+    let proto = incoming("x-forwarded-proto").unwrap_or_else(|| scheme.to_string());
+    let host = incoming("x-forwarded-host").or_else(|| request_host(req));
+    let forwarded = proxy.forwarded;
+    let mut headers = vec![];
+    if let (true, Some(peer)) = (forwarded.x_forwarded_for, peer) {
+        // 每一跳追加自己看到的对端地址
+        let value = match incoming("x-forwarded-for") {
+            Some(chain) => format!("{}, {}", chain, peer),
+            None => peer.to_string(),
+        };
+        headers.push((HeaderName::from_static("x-forwarded-for"), value));
+    }
+    if forwarded.x_forwarded_proto {
+        headers.push((HeaderName::from_static("x-forwarded-proto"), proto.clone()));
+    }
+    if let (true, Some(host)) = (forwarded.x_forwarded_host, &host) {
+        headers.push((HeaderName::from_static("x-forwarded-host"), host.clone()));
+    }
+    // 只有去掉了前缀时才需要告诉后端
+    if forwarded.x_forwarded_prefix && proxy.origin_path != "/" && proxy.strips_prefix() {
+        let prefix = format!(
+            "{}{}",
+            incoming("x-forwarded-prefix").unwrap_or_default().trim_end_matches('/'),
+            proxy.origin_path.trim_end_matches('/')
+        );
+        headers.push((HeaderName::from_static("x-forwarded-prefix"), prefix));
+    }
+    if forwarded.forwarded {
+        // RFC 7239，IPv6 需要加引号和方括号
+        let mut element = match peer {
+            Some(IpAddr::V6(ip)) => format!("for=\"[{}]\"", ip),
+            Some(ip) => format!("for={}", ip),
+            None => String::from("for=unknown"),
+        };
+        if let Some(host) = &host {
+            element.push_str(&format!(";host=\"{}\"", host.replace('"', "")));
+        }
+        element.push_str(&format!(";proto={}", proto));
+        let value = match incoming("forwarded") {
+            Some(chain) => format!("{}, {}", chain, element),
+            None => element,
+        };
+        headers.push((header::FORWARDED, value));
+    }
+    headers
+}
+
 /// Forwards the incoming HTTP request using `awc`.
 /// /api->http://example.com/ means /api/users -> http://example.com/users
 /// /api->http://example.com/api means /api/users -> http://example.com/api/users
-/// /api->http://example.com/app means /api/users -> http://example.com/app/api/users
+/// /api->http://example.com/app means /api/users -> http://example.com/api/users
 /// /api->http://example.com/app/ means /api/users -> http://example.com/app/users
 pub async fn forward_request(
     req: HttpRequest,
     payload: web::Payload,
     proxy_config: web::Data<ProxyItem>,
-    client: web::Data<Client>,
+    client: web::Data<ProxyClient>,
 ) -> Result<HttpResponse, Error> {
-    if let Ok(proxy_url) = get_proxy_path(&req, &proxy_config) {
-        let forwarded_req = client
+    set_route_class(&req, RouteClass::Proxy);
+    let limits = proxy_config.limits;
+    if limits.max_body_size > 0 && content_length(&req) > limits.max_body_size {
+        return Ok(error_response(&req, StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    if let Some(mock) = proxy_config.mock.as_ref().filter(|mock| mock.mode == MockMode::Always) {
+        if let Some(response) = mock_response(&req, &proxy_config, mock).await {
+            return Ok(response);
+        }
+    }
+    if let Some(cache) = &proxy_config.cache {
+        if let Some(key) = cache.key(&req) {
+            return Ok(forward_cached(req, proxy_config.clone(), client, cache, key).await);
+        }
+    }
+    Ok(
+        match send_upstream(&req, Some(payload), &proxy_config, &client, None).await {
+            Ok(res) => proxied_response(&req, &proxy_config, res, None),
+            Err(status) => failed_response(&req, &proxy_config, status).await,
+        },
+    )
+}
+
+/// Serves a cacheable GET from the proxy cache, revalidating or filling it from the upstream.
+async fn forward_cached(
+    req: HttpRequest,
+    proxy_config: web::Data<ProxyItem>,
+    client: web::Data<ProxyClient>,
+    cache: &ProxyCache,
+    key: String,
+) -> HttpResponse {
+    let cached = cache.lookup(&key, &req).await;
+    let mut conditional = None;
+    if let Some(entry) = &cached {
+        match entry.freshness() {
+            Freshness::Fresh => return cached_response(&req, &proxy_config, entry, "HIT"),
+            Freshness::Revalidate => {
+                if cache.begin_revalidation(&key) {
+                    rt::spawn(revalidate(req.clone(), proxy_config.clone(), client, key, entry.clone()));
+                }
+                return cached_response(&req, &proxy_config, entry, "STALE");
+            }
+            Freshness::Stale => conditional = Some(entry.conditional_headers()),
+        }
+    }
+    let stale = cached.as_ref().filter(|entry| entry.usable_on_error());
+    match send_upstream(&req, None, &proxy_config, &client, conditional.as_ref()).await {
+        Ok(res) => match (&cached, stale) {
+            // 有缓存时只发送缓存的校验字段，304 一定是对缓存的确认
+            (Some(entry), _) if res.status == StatusCode::NOT_MODIFIED => {
+                let entry = cache.refresh(entry, &res.headers);
+                cached_response(&req, &proxy_config, &entry, "HIT")
+            }
+            (_, Some(entry)) if res.status.is_server_error() => cached_response(&req, &proxy_config, entry, "STALE"),
+            _ => proxied_response(&req, &proxy_config, res, Some((cache, &key))),
+        },
+        Err(status) => match stale {
+            Some(entry) => cached_response(&req, &proxy_config, entry, "STALE"),
+            None => failed_response(&req, &proxy_config, status).await,
+        },
+    }
+}
+
+// stale-while-revalidate 的后台更新
+async fn revalidate(
+    req: HttpRequest,
+    proxy_config: web::Data<ProxyItem>,
+    client: web::Data<ProxyClient>,
+    key: String,
+    entry: Arc<CacheEntry>,
+) {
+    let Some(cache) = &proxy_config.cache else {
+        return;
+    };
+    match send_upstream(&req, None, &proxy_config, &client, Some(&entry.conditional_headers())).await {
+        Ok(res) if res.status == StatusCode::NOT_MODIFIED => {
+            cache.refresh(&entry, &res.headers);
+        }
+        Ok(res) => {
+            let headers = upstream_headers(&proxy_config, &res.headers);
+            if let Some(entry) = cache.entry(&key, &req, res.status, &headers) {
+                let mut body = cache.fill(entry, res.body);
+                while body.next().await.is_some() {}
+            }
+        }
+        Err(status) => LOGGER.warn(format!("proxy cache: failed to revalidate {}: {}", key, status)),
+    }
+    cache.end_revalidation(&key);
+}
+
+fn cached_response(req: &HttpRequest, proxy_config: &ProxyItem, entry: &CacheEntry, x_cache: &'static str) -> HttpResponse {
+    let mut response = entry.response(req);
+    response
+        .headers_mut()
+        .insert(HeaderName::from_static("x-cache"), HeaderValue::from_static(x_cache));
+    apply_response_headers(req, proxy_config, &mut response);
+    response
+}
+
+async fn mock_response(req: &HttpRequest, proxy_config: &ProxyItem, mock: &MockFixtures) -> Option<HttpResponse> {
+    let mut response = mock.response(req).await?;
+    apply_response_headers(req, proxy_config, &mut response);
+    Some(response)
+}
+
+/// The `mock` fixture of a request the upstreams failed to answer, or the error page.
+async fn failed_response(req: &HttpRequest, proxy_config: &ProxyItem, status: StatusCode) -> HttpResponse {
+    if let Some(mock) = proxy_config.mock.as_ref().filter(|_| status != StatusCode::PAYLOAD_TOO_LARGE) {
+        if let Some(response) = mock_response(req, proxy_config, mock).await {
+            return response;
+        }
+    }
+    error_response(req, status)
+}
+
+/// An upstream response whose body keeps the connection counted as active until it is dropped.
+struct UpstreamResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: LocalBoxStream<'static, Result<Bytes, PayloadError>>,
+}
+
+/// Sends the request to an upstream picked by the balancer, idempotent requests without a body
+/// are retried on the next one. `conditional` headers replace the client's `If-None-Match` and
+/// `If-Modified-Since` to revalidate a cached response.
+async fn send_upstream(
+    req: &HttpRequest,
+    payload: Option<web::Payload>,
+    proxy_config: &ProxyItem,
+    client: &ProxyClient,
+    conditional: Option<&HeaderMap>,
+) -> Result<UpstreamResponse, StatusCode> {
+    let limits = proxy_config.limits;
+    let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+    let client_ip = client_ip(req);
+    // 没有请求体的幂等请求可以换一个上游重试
+    let retryable = is_idempotent(req.method()) && !has_body(req);
+    let mut payload = payload;
+    // 请求体超过 max-body-size 时由上传流标记
+    let overflowed = Rc::new(Cell::new(false));
+    let mut tried = vec![];
+    let mut last_status = StatusCode::BAD_GATEWAY;
+    while let Some(index) = proxy_config.pool.select(client_ip, &tried) {
+        tried.push(index);
+        let upstream = &proxy_config.pool.upstreams[index];
+        let Ok(proxy_url) = get_proxy_path(req, proxy_config, upstream) else {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        let mut forwarded_req = client
+            .http(upstream)
             .request_from(proxy_url.as_str(), req.head())
             .no_decompress();
+        apply_request_headers(req, proxy_config, forwarded_req.headers_mut());
+        // 录制时要求上游不压缩，保存的文件才可以编辑
+        if proxy_config.mock.as_ref().is_some_and(|mock| mock.mode == MockMode::Record) {
+            forwarded_req.headers_mut().remove(header::ACCEPT_ENCODING);
+        }
+        if let Some(conditional) = conditional {
+            // 客户端的校验字段对应的不是缓存的版本
+            forwarded_req.headers_mut().remove(header::IF_NONE_MATCH);
+            forwarded_req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+            for (name, value) in conditional {
+                forwarded_req.headers_mut().insert(name.clone(), value.clone());
+            }
+        }
 
-        // TODO: This forwarded implementation is incomplete as it only handles the unofficial
-        // X-Forwarded-For header but not the official Forwarded one.
-        //   let forwarded_req = match peer_addr {
-        //       Some(PeerAddr(addr)) => {
-        //           forwarded_req.insert_header(("x-forwarded-for", addr.ip().to_string()))
-        //       }
-        //       None => forwarded_req,
-        //   };
-
-        let res = forwarded_req
-            .send_stream(payload)
-            .await
-            .map_err(error::ErrorInternalServerError)?;
+        let connection = upstream.connect();
+        let body = match payload.take() {
+            Some(payload) if !retryable => {
+                Some(limited_body(payload, limits.max_body_size, overflowed.clone()).boxed_local())
+            }
+            _ => None,
+        };
+        let send = async {
+            if let Some(h2c) = client.h2c.as_ref().filter(|_| proxy_url.scheme() == "http") {
+                #[cfg(unix)]
+                let socket = upstream.socket.as_deref();
+                #[cfg(not(unix))]
+                let socket = None;
+                return h2c.send(&forwarded_req, socket, body).await;
+            }
+            let res = match body {
+                Some(body) => forwarded_req.send_stream(body).await?,
+                None => forwarded_req.send().await?,
+            };
+            Ok((res.status(), res.headers().clone(), res.boxed_local()))
+        };
+        let result = match deadline {
+            Some(deadline) => timeout_at(deadline, send)
+                .await
+                .unwrap_or(Err(SendRequestError::Timeout)),
+            None => send.await,
+        };
+        let (status, headers, body) = match result {
+            Ok(res) => res,
+            Err(_) if overflowed.get() => return Err(StatusCode::PAYLOAD_TOO_LARGE),
+            Err(e) => {
+                LOGGER.warn(format!("proxy: {} {} failed: {}", req.method(), proxy_url, e));
+                record_proxy_error(&upstream.url);
+                if upstream.record_failure(&proxy_config.pool.health) {
+                    LOGGER.warn(format!("proxy: upstream {} ejected after repeated failures", upstream.url));
+                }
+                last_status = error_status(&e);
+                if retryable && tried.len() <= proxy_config.retries {
+                    continue;
+                }
+                break;
+            }
+        };
+        upstream.record_success();
 
-        let mut client_resp = HttpResponse::build(res.status());
-        // Remove `Connection` as per
-        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Connection#Directives
-        for (header_name, header_value) in res.headers().iter().filter(|(h, _)| *h != "connection")
-        {
-            client_resp.insert_header((header_name.clone(), header_value.clone()));
-        }
+        // 响应体传输完之前都算作活跃连接
+        let body = timed_body(body, limits.read_timeout, deadline)
+            .map(move |chunk| {
+                let _ = &connection;
+                chunk
+            })
+            .boxed_local();
+        return Ok(UpstreamResponse { status, headers, body });
+    }
+    Err(last_status)
+}
 
-        Ok(client_resp.streaming(res))
-    } else {
-        Ok(HttpResponse::InternalServerError().body("Invalid proxy configuration"))
+// Remove `Connection` as per
+// https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Connection#Directives
+fn upstream_headers(proxy_config: &ProxyItem, headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    headers.remove(header::CONNECTION);
+    proxy_config.rewrites.apply(&proxy_config.path_mapping(), &mut headers);
+    headers
+}
+
+/// Streams an upstream response to the client, storing it when `cache` is given and it is cacheable.
+fn proxied_response(
+    req: &HttpRequest,
+    proxy_config: &ProxyItem,
+    res: UpstreamResponse,
+    cache: Option<(&ProxyCache, &str)>,
+) -> HttpResponse {
+    let headers = upstream_headers(proxy_config, &res.headers);
+    let mut client_resp = HttpResponse::build(res.status);
+    // 用 append 保留多个 Set-Cookie
+    for (header_name, header_value) in &headers {
+        client_resp.append_header((header_name.clone(), header_value.clone()));
     }
+    let entry = cache.and_then(|(cache, key)| Some((cache, cache.entry(key, req, res.status, &headers)?)));
+    let body = match entry {
+        Some((cache, entry)) => cache.fill(entry, res.body).boxed_local(),
+        None => res.body,
+    };
+    let mut response = match proxy_config.mock.as_ref().filter(|mock| mock.mode == MockMode::Record) {
+        Some(mock) => client_resp.streaming(mock.record(req, res.status, &headers, body)),
+        None => client_resp.streaming(body),
+    };
+    if cache.is_some() {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-cache"), HeaderValue::from_static("MISS"));
+    }
+    apply_response_headers(req, proxy_config, &mut response);
+    response
+}
+
+/// 502 when the upstream can not be reached or answers garbage, 504 when it is too slow.
+fn error_status(error: &SendRequestError) -> StatusCode {
+    match error {
+        SendRequestError::Timeout | SendRequestError::Connect(ConnectError::Timeout) => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// The `--proxy-error-page` of the site, a file served for failed proxy requests.
+/// It is read when the site is built, so a failing upstream never waits on the disk.
+pub struct ProxyErrorPage(pub Option<Bytes>);
+
+fn error_response(req: &HttpRequest, status: StatusCode) -> HttpResponse {
+    let page = req
+        .app_data::<web::Data<ProxyErrorPage>>()
+        .and_then(|page| page.0.clone());
+    match page {
+        Some(page) => HttpResponse::build(status)
+            .content_type("text/html; charset=utf-8")
+            .body(page),
+        None => HttpResponse::build(status).body(status.canonical_reason().unwrap_or_default()),
+    }
+}
+
+// 上传的请求体超过 limit 时中断，0 表示不限制
+fn limited_body(
+    payload: web::Payload,
+    limit: u64,
+    overflowed: Rc<Cell<bool>>,
+) -> impl Stream<Item = Result<Bytes, PayloadError>> {
+    let mut received = 0;
+    payload.map(move |chunk| {
+        let chunk = chunk?;
+        received += chunk.len() as u64;
+        if limit > 0 && received > limit {
+            overflowed.set(true);
+            return Err(PayloadError::Overflow);
+        }
+        Ok(chunk)
+    })
+}
+
+/// Upstream response body, fails when no chunk arrives within `read_timeout`
+/// or after the `deadline` of the whole request.
+fn timed_body<S>(
+    body: S,
+    read_timeout: Duration,
+    deadline: Option<Instant>,
+) -> impl Stream<Item = Result<Bytes, PayloadError>>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        let read_deadline = Instant::now() + read_timeout;
+        let until = deadline.map_or(read_deadline, |deadline| deadline.min(read_deadline));
+        match timeout_at(until, body.next()).await {
+            Ok(Some(chunk)) => Some((chunk, Some(body))),
+            Ok(None) => None,
+            Err(_) => {
+                let timeout = io::Error::new(io::ErrorKind::TimedOut, "upstream read timeout");
+                Some((Err(PayloadError::Io(timeout)), None))
+            }
+        }
+    })
+}
+
+fn content_length(req: &HttpRequest) -> u64 {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0)
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE
+    )
+}
+
+fn has_body(req: &HttpRequest) -> bool {
+    content_length(req) > 0 || req.headers().contains_key(header::TRANSFER_ENCODING)
 }
 
+// 与 awc 默认的超时时间一致
+const WS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub async fn ws_forward_request(
     req: HttpRequest,
     payload: web::Payload,
     proxy_config: web::Data<ProxyItem>,
 ) -> Result<HttpResponse, Error> {
-    if let Ok(proxy_url) = get_proxy_path(&req, &proxy_config) {
-        ws_proxy::start(&req, proxy_url.to_string(), payload).await
+    set_route_class(&req, RouteClass::Websocket);
+    let Some(index) = proxy_config.pool.select(client_ip(&req), &[]) else {
+        return Ok(HttpResponse::InternalServerError().body("Invalid websocket proxy configuration"));
+    };
+    let upstream = &proxy_config.pool.upstreams[index];
+    if let Ok(proxy_url) = get_proxy_path(&req, &proxy_config, upstream) {
+        let mut headers = HeaderMap::new();
+        apply_request_headers(&req, &proxy_config, &mut headers);
+        // 会话结束（客户端的流结束）前都算作活跃连接
+        let connection = upstream.connect();
+        let payload = payload.map(move |chunk| {
+            let _ = &connection;
+            chunk
+        });
+        let client = upstream.client(WS_CONNECT_TIMEOUT);
+        let mut response = ws_proxy::start(&req, &client, proxy_url.to_string(), headers, payload)
+            .await
+            .inspect_err(|_| {
+                record_proxy_error(&upstream.url);
+                upstream.record_failure(&proxy_config.pool.health);
+            })?;
+        upstream.record_success();
+        apply_response_headers(&req, &proxy_config, &mut response);
+        Ok(response)
     } else {
         Ok(HttpResponse::InternalServerError().body("Invalid websocket proxy configuration"))
     }
 }
 
-fn get_proxy_path(req: &HttpRequest, proxy_config: &ProxyItem) -> Result<Url, bool> {
+fn get_proxy_path(req: &HttpRequest, proxy_config: &ProxyItem, upstream: &Upstream) -> Result<Url, bool> {
     // 直接 clone 预解析好的 Url，避免每次请求重新 parse 字符串
-    let mut new_url = proxy_config.target_url_parsed.clone();
+    let mut new_url = upstream.parsed.clone();
+    if proxy_config.route.regex.is_some() {
+        // 目标地址里有捕获组时替换后就是完整地址，否则原样转发请求路径
+        if upstream.is_template() {
+            let expanded = proxy_config
+                .route
+                .expand(upstream.target(), req.uri().path())
+                .ok_or(false)?;
+            new_url = Url::parse(&expanded).map_err(|_| false)?;
+            if new_url.query().is_none() {
+                new_url.set_query(req.uri().query());
+            }
+        } else {
+            new_url.set_path(req.uri().path());
+            new_url.set_query(req.uri().query());
+        }
+        return Ok(new_url);
+    }
     // 去除代理url前缀
     let _left_path = req.uri().path().strip_prefix(&proxy_config.origin_path);
-    if let Some(mut left_path) = _left_path {
+    if let Some(left_path) = _left_path {
         // 补全开头的/
-        let left_path_str = if !left_path.starts_with("/") {
+        let left_path = if !left_path.starts_with("/") {
             format!("/{}", left_path)
         } else {
             left_path.to_string()
         };
-        left_path = left_path_str.as_str();
-        // 如果代理url是以/结尾的，那么就只追加left_path
-        // 否则追加整个path
-        let joined_path = new_url.join(if proxy_config.target_url.ends_with("/") {
-            left_path
+        // 如果代理url是以/结尾的，那么就把前缀换成代理url的路径
+        // 否则转发整个path
+        if upstream.url.ends_with("/") {
+            let base = upstream.parsed.path().trim_end_matches('/').to_string();
+            new_url.set_path(&format!("{}{}", base, left_path));
         } else {
-            req.uri().path()
-        });
-
-        if let Ok(joined_url) = joined_path {
-            new_url = joined_url;
+            new_url.set_path(req.uri().path());
         }
 
         new_url.set_query(req.uri().query());
@@ -115,10 +959,11 @@ fn get_proxy_path(req: &HttpRequest, proxy_config: &ProxyItem) -> Result<Url, bo
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::proxy_rewrite::Rewrite;
     use actix_web::test::TestRequest;
 
     fn make_proxy(origin: &str, target: &str) -> ProxyItem {
-        ProxyItem::new(origin.to_string(), target.to_string())
+        ProxyItem::parse(&format!("{}->{}", origin, target)).unwrap()
     }
 
     /// /api -> http://example.com/  =>  /api/users  ->  http://example.com/users
@@ -128,7 +973,7 @@ mod tests {
             .uri("/api/users")
             .to_http_request();
         let proxy = make_proxy("/api", "http://example.com/");
-        let url = get_proxy_path(&req, &proxy).unwrap();
+        let url = get_proxy_path(&req, &proxy, &proxy.pool.upstreams[0]).unwrap();
         assert_eq!(url.as_str(), "http://example.com/users");
     }
 
@@ -139,20 +984,19 @@ mod tests {
             .uri("/api/users")
             .to_http_request();
         let proxy = make_proxy("/api", "http://example.com/api");
-        let url = get_proxy_path(&req, &proxy).unwrap();
+        let url = get_proxy_path(&req, &proxy, &proxy.pool.upstreams[0]).unwrap();
         assert_eq!(url.as_str(), "http://example.com/api/users");
     }
 
-    /// /api -> http://example.com/app/  =>  /api/users  ->  http://example.com/users
-    /// (left_path="/users", url::Url::join with absolute path replaces all path segments)
+    /// /api -> http://example.com/app/  =>  /api/users  ->  http://example.com/app/users
     #[test]
     fn test_proxy_path_sub_path_trailing_slash() {
         let req = TestRequest::get()
             .uri("/api/users")
             .to_http_request();
         let proxy = make_proxy("/api", "http://example.com/app/");
-        let url = get_proxy_path(&req, &proxy).unwrap();
-        assert_eq!(url.as_str(), "http://example.com/users");
+        let url = get_proxy_path(&req, &proxy, &proxy.pool.upstreams[0]).unwrap();
+        assert_eq!(url.as_str(), "http://example.com/app/users");
     }
 
     /// query string should be preserved
@@ -162,7 +1006,7 @@ mod tests {
             .uri("/api/search?q=hello&page=1")
             .to_http_request();
         let proxy = make_proxy("/api", "http://example.com/");
-        let url = get_proxy_path(&req, &proxy).unwrap();
+        let url = get_proxy_path(&req, &proxy, &proxy.pool.upstreams[0]).unwrap();
         assert_eq!(url.path(), "/search");
         assert_eq!(url.query(), Some("q=hello&page=1"));
     }
@@ -174,7 +1018,7 @@ mod tests {
             .uri("/other/path")
             .to_http_request();
         let proxy = make_proxy("/api", "http://example.com/");
-        assert!(get_proxy_path(&req, &proxy).is_err());
+        assert!(get_proxy_path(&req, &proxy, &proxy.pool.upstreams[0]).is_err());
     }
 
     /// exact match on prefix root  =>  /api  ->  http://example.com/
@@ -184,7 +1028,323 @@ mod tests {
             .uri("/api")
             .to_http_request();
         let proxy = make_proxy("/api", "http://example.com/");
-        let url = get_proxy_path(&req, &proxy).unwrap();
+        let url = get_proxy_path(&req, &proxy, &proxy.pool.upstreams[0]).unwrap();
         assert_eq!(url.path(), "/");
     }
+
+    fn header<'a>(headers: &'a [(HeaderName, String)], name: &str) -> Option<&'a str> {
+        headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn test_parse_proxy_options() {
+        let proxy = ProxyItem::parse("/api->http://example.com/;forwarded=x-forwarded-for,forwarded").unwrap();
+        assert_eq!(proxy.targets(), "http://example.com/");
+        assert!(proxy.forwarded.x_forwarded_for && proxy.forwarded.forwarded);
+        assert!(!proxy.forwarded.x_forwarded_host);
+        let proxy = ProxyItem::parse("/api->http://example.com/;forwarded=none").unwrap();
+        assert_eq!(proxy.forwarded, ForwardedHeaders::all(false));
+        assert!(ProxyItem::parse("/api->http://example.com/;forwarded=x-real").is_err());
+        assert!(ProxyItem::parse("/api->http://example.com/;unknown=3").is_err());
+        assert!(ProxyItem::parse("/api").is_err());
+        assert!(ProxyItem::parse("/api->not a url").is_err());
+    }
+
+    #[test]
+    fn test_parse_header_options() {
+        let proxy = ProxyItem::parse(
+            "/boot->http://10.0.0.1:8081;host=upstream;request-header=X_GATEWAY_BASE_PATH: https://$host/boot\\;v=1;request-header-remove=Cookie;response-header-add=Cache-Control: no-store",
+        )
+        .unwrap();
+        assert!(matches!(proxy.host, Some(HostHeader::Upstream)));
+        assert_eq!(proxy.request_headers.len(), 2);
+        assert_eq!(proxy.response_headers.len(), 1);
+        let req = TestRequest::get()
+            .insert_header((header::HOST, "oa.example.com"))
+            .insert_header((header::COOKIE, "a=1"))
+            .to_http_request();
+        let mut headers = req.headers().clone();
+        apply_request_headers(&req, &proxy, &mut headers);
+        assert!(!headers.contains_key(header::HOST));
+        assert!(!headers.contains_key(header::COOKIE));
+        assert_eq!(headers.get("x_gateway_base_path").unwrap(), "https://oa.example.com/boot;v=1");
+        assert!(ProxyItem::parse("/api->http://a/;request-header=Bad").is_err());
+    }
+
+    #[test]
+    fn test_parse_upstream_options() {
+        let proxy = ProxyItem::parse(
+            "/api->http://10.0.0.1:3000/, http://10.0.0.2:3000/;lb=least-conn;max-fails=5;fail-timeout=30;retries=2",
+        )
+        .unwrap();
+        assert_eq!(proxy.targets(), "http://10.0.0.1:3000/, http://10.0.0.2:3000/");
+        assert_eq!(proxy.pool.balancer, Balancer::LeastConn);
+        assert_eq!(proxy.pool.health.max_fails, 5);
+        assert_eq!(proxy.pool.health.fail_timeout, Duration::from_secs(30));
+        assert_eq!(proxy.retries, 2);
+        let req = TestRequest::get().uri("/api/users").to_http_request();
+        let url = get_proxy_path(&req, &proxy, &proxy.pool.upstreams[1]).unwrap();
+        assert_eq!(url.as_str(), "http://10.0.0.2:3000/users");
+        assert!(ProxyItem::parse("/api->http://a/v1/,http://b/v2/").is_err());
+        assert!(ProxyItem::parse("/api->http://a/,http://b/;lb=fastest").is_err());
+        assert!(ProxyItem::parse("/api->http://a/;retries=-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_health_check_options() {
+        let proxy = ProxyItem::parse(
+            "/api->http://a/,http://b/;health-check=/healthz;health-interval=10;health-timeout=1;health-status=200,204",
+        )
+        .unwrap();
+        let check = proxy.pool.active_check.unwrap();
+        assert_eq!(check.path, "/healthz");
+        assert_eq!(check.interval, Duration::from_secs(10));
+        assert_eq!(check.timeout, Duration::from_secs(1));
+        assert!(check.status.matches(204) && !check.status.matches(301));
+        assert!(ProxyItem::parse("/api->http://a/").unwrap().pool.active_check.is_none());
+        assert!(ProxyItem::parse("/api->http://a/;health-interval=3").is_err());
+        assert!(ProxyItem::parse("/api->http://a/;health-check=healthz").is_err());
+        assert!(ProxyItem::parse("/api->http://a/;health-check=/;health-status=ok").is_err());
+    }
+
+    #[test]
+    fn test_parse_limit_options() {
+        let proxy = ProxyItem::parse(
+            "/api->http://a/;connect-timeout=2;read-timeout=30;timeout=120;max-body-size=10M;max-connections=8;idle-timeout=5",
+        )
+        .unwrap();
+        assert_eq!(
+            proxy.limits,
+            ProxyLimits {
+                connect_timeout: Duration::from_secs(2),
+                read_timeout: Duration::from_secs(30),
+                timeout: Some(Duration::from_secs(120)),
+                max_body_size: 10 << 20,
+                max_connections: 8,
+                idle_timeout: Duration::from_secs(5),
+            }
+        );
+        assert_eq!(ProxyItem::parse("/api->http://a/").unwrap().limits, ProxyLimits::default());
+        assert!(ProxyItem::parse("/api->http://a/;max-body-size=10X").is_err());
+    }
+
+    #[test]
+    fn test_parse_cache_options() {
+        let proxy = ProxyItem::parse(
+            "/api->http://a/;cache=64M;cache-max-entry=2M;cache-ttl=60;cache-stale-while-revalidate=10;cache-stale-if-error=300",
+        )
+        .unwrap();
+        let cache = proxy.cache.unwrap();
+        assert_eq!(cache.options.max_size, 64 << 20);
+        assert_eq!(cache.options.max_entry_size, 2 << 20);
+        assert_eq!(cache.options.dir, None);
+        assert_eq!(cache.options.ttl, Duration::from_secs(60));
+        assert_eq!(cache.options.stale_while_revalidate, Duration::from_secs(10));
+        assert_eq!(cache.options.stale_if_error, Duration::from_secs(300));
+        assert!(ProxyItem::parse("/api->http://a/").unwrap().cache.is_none());
+        assert!(ProxyItem::parse("/api->http://a/;cache-ttl=60").is_err());
+        assert!(ProxyItem::parse("/api->http://a/;cache=1G;cache-ttl=forever").is_err());
+    }
+
+    #[test]
+    fn test_parse_http_version() {
+        let proxy = ProxyItem::parse("/api->http://a/;http-version=2").unwrap();
+        assert_eq!(proxy.http_version, Some(Version::HTTP_2));
+        let proxy = ProxyItem::parse("/api->https://a/;http-version=1.1").unwrap();
+        assert_eq!(proxy.http_version, Some(Version::HTTP_11));
+        assert_eq!(ProxyItem::parse("/api->http://a/").unwrap().http_version, None);
+        assert!(ProxyItem::parse("/api->http://a/;http-version=3").is_err());
+    }
+
+    #[test]
+    fn test_parse_mock_options() {
+        let proxy = ProxyItem::parse("/api->http://a/;mock=./mocks;mock-mode=record").unwrap();
+        assert_eq!(
+            proxy.mock,
+            Some(MockFixtures {
+                dir: PathBuf::from("./mocks"),
+                mode: MockMode::Record,
+            })
+        );
+        // 只有 mock 时可以不配置上游
+        let proxy = ProxyItem::parse("/api->;mock=mocks").unwrap();
+        assert!(proxy.pool.upstreams.is_empty());
+        assert_eq!(proxy.mock.unwrap().mode, MockMode::Fallback);
+        assert!(ProxyItem::parse("/api->").is_err());
+        assert!(ProxyItem::parse("/api->http://a/;mock-mode=always").is_err());
+        assert!(ProxyItem::parse("/api->;mock=mocks;mock-mode=replay").is_err());
+    }
+
+    #[test]
+    fn test_error_status() {
+        assert_eq!(error_status(&SendRequestError::Timeout), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            error_status(&SendRequestError::Connect(ConnectError::Timeout)),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            error_status(&SendRequestError::Connect(ConnectError::Disconnected)),
+            StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_error_page() {
+        let req = TestRequest::get().to_http_request();
+        let resp = error_response(&req, StatusCode::BAD_GATEWAY);
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "Bad Gateway");
+
+        let page = Bytes::from_static(b"<h1>Down for maintenance</h1>");
+        let req = TestRequest::get()
+            .app_data(web::Data::new(ProxyErrorPage(Some(page))))
+            .to_http_request();
+        let resp = error_response(&req, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "<h1>Down for maintenance</h1>");
+    }
+
+    #[test]
+    fn test_parse_rewrite_options() {
+        let proxy = ProxyItem::parse("/app->http://backend/;redirect=/ /app/;redirect=default;cookie-domain=off").unwrap();
+        assert_eq!(proxy.rewrites.redirect.len(), 2);
+        assert_eq!(proxy.rewrites.cookie_path, vec![Rewrite::Default]);
+        assert!(proxy.rewrites.cookie_domain.is_empty());
+        assert!(ProxyItem::parse("/app->http://backend/;cookie-path=/").is_err());
+    }
+
+    #[test]
+    fn test_regex_proxy_path() {
+        let proxy = make_proxy(r"~^/api/v(\d+)/(.*)", "http://svc-v$1:8080/$2");
+        assert_eq!(proxy.scope_path(), "");
+        let req = TestRequest::get().uri("/api/v2/users/7?full=1").to_http_request();
+        let url = get_proxy_path(&req, &proxy, &proxy.pool.upstreams[0]).unwrap();
+        assert_eq!(url.as_str(), "http://svc-v2:8080/users/7?full=1");
+        let req = TestRequest::get().uri("/api/latest/users").to_http_request();
+        assert!(get_proxy_path(&req, &proxy, &proxy.pool.upstreams[0]).is_err());
+        // 没有捕获组时原样转发请求路径
+        let proxy = make_proxy(r"~\.php$", "http://127.0.0.1:9000/");
+        let req = TestRequest::get().uri("/admin/index.php?a=1").to_http_request();
+        let url = get_proxy_path(&req, &proxy, &proxy.pool.upstreams[0]).unwrap();
+        assert_eq!(url.as_str(), "http://127.0.0.1:9000/admin/index.php?a=1");
+        // 前缀规则的 $ 是普通字符，仍然做健康检查
+        let proxy = make_proxy("/api", "http://example.com/$1/");
+        assert!(!proxy.pool.upstreams[0].is_template());
+        let req = TestRequest::get().uri("/api/users").to_http_request();
+        let url = get_proxy_path(&req, &proxy, &proxy.pool.upstreams[0]).unwrap();
+        assert_eq!(url.as_str(), "http://example.com/$1/users");
+    }
+
+    #[test]
+    fn test_parse_route_options() {
+        let proxy = ProxyItem::parse("/api->http://a/;method=GET,HEAD;header=X-Canary: 1;priority=5").unwrap();
+        assert_eq!(proxy.route.methods, vec![Method::GET, Method::HEAD]);
+        assert_eq!(proxy.route.headers.len(), 1);
+        assert_eq!(proxy.route.priority, 5);
+        assert!(ProxyItem::parse("/api->http://a/;priority=high").is_err());
+        assert!(ProxyItem::parse("~^/api/(->http://a/").is_err());
+    }
+
+    #[test]
+    fn test_retry_only_without_body() {
+        assert!(is_idempotent(&Method::GET) && is_idempotent(&Method::PUT));
+        assert!(!is_idempotent(&Method::POST) && !is_idempotent(&Method::PATCH));
+        let req = TestRequest::get().to_http_request();
+        assert!(!has_body(&req));
+        let req = TestRequest::put()
+            .insert_header((header::CONTENT_LENGTH, "12"))
+            .to_http_request();
+        assert!(has_body(&req));
+        let req = TestRequest::delete()
+            .insert_header((header::TRANSFER_ENCODING, "chunked"))
+            .to_http_request();
+        assert!(has_body(&req));
+    }
+
+    #[test]
+    fn test_forwarded_headers_replace_untrusted_values() {
+        let req = TestRequest::get()
+            .uri("/api/users")
+            .peer_addr("192.0.2.10:5000".parse().unwrap())
+            .insert_header((header::HOST, "app.example.com"))
+            .insert_header(("x-forwarded-for", "6.6.6.6"))
+            .insert_header(("x-forwarded-proto", "https"))
+            .to_http_request();
+        let headers = forwarded_headers(&req, &make_proxy("/api", "http://example.com/"));
+        assert_eq!(header(&headers, "x-forwarded-for"), Some("192.0.2.10"));
+        assert_eq!(header(&headers, "x-forwarded-proto"), Some("http"));
+        assert_eq!(header(&headers, "x-forwarded-host"), Some("app.example.com"));
+        assert_eq!(header(&headers, "x-forwarded-prefix"), Some("/api"));
+        assert_eq!(
+            header(&headers, "forwarded"),
+            Some("for=192.0.2.10;host=\"app.example.com\";proto=http")
+        );
+    }
+
+    #[test]
+    fn test_forwarded_headers_extend_trusted_values() {
+        let trusted = crate::client_ip::TrustedProxies::parse(&[String::from("10.0.0.0/8")]).unwrap();
+        let req = TestRequest::get()
+            .uri("/api/users")
+            .peer_addr("10.0.0.1:5000".parse().unwrap())
+            .insert_header((header::HOST, "internal"))
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .insert_header(("x-forwarded-proto", "https"))
+            .insert_header(("x-forwarded-host", "app.example.com"))
+            .insert_header(("forwarded", "for=203.0.113.7;proto=https"))
+            .app_data(web::Data::new(trusted))
+            .to_http_request();
+        // 不去掉前缀时不发送 X-Forwarded-Prefix
+        let headers = forwarded_headers(&req, &make_proxy("/api", "http://example.com"));
+        assert_eq!(header(&headers, "x-forwarded-for"), Some("203.0.113.7, 10.0.0.1"));
+        assert_eq!(header(&headers, "x-forwarded-proto"), Some("https"));
+        assert_eq!(header(&headers, "x-forwarded-host"), Some("app.example.com"));
+        assert_eq!(header(&headers, "x-forwarded-prefix"), None);
+        assert_eq!(
+            header(&headers, "forwarded"),
+            Some("for=203.0.113.7;proto=https, for=10.0.0.1;host=\"app.example.com\";proto=https")
+        );
+    }
+
+    #[test]
+    fn test_forwarded_headers_disabled() {
+        let req = TestRequest::get()
+            .uri("/api/users")
+            .peer_addr("[2001:db8::1]:5000".parse().unwrap())
+            .to_http_request();
+        let proxy = ProxyItem::parse("/api->http://example.com/;forwarded=forwarded").unwrap();
+        let headers = forwarded_headers(&req, &proxy);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(header(&headers, "forwarded"), Some("for=\"[2001:db8::1]\";proto=http"));
+    }
+
+    #[test]
+    fn test_disabled_forwarded_headers_are_not_passed_through() {
+        let req = TestRequest::get()
+            .uri("/api/users")
+            .peer_addr("192.0.2.10:5000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "6.6.6.6"))
+            .insert_header(("x-forwarded-host", "evil.example.com"))
+            .insert_header(("forwarded", "for=6.6.6.6"))
+            .to_http_request();
+        let proxy = ProxyItem::parse("/api->http://example.com/;forwarded=none").unwrap();
+        let mut headers = req.headers().clone();
+        apply_request_headers(&req, &proxy, &mut headers);
+        assert!(FORWARDING_HEADERS.iter().all(|name| !headers.contains_key(*name)));
+
+        // 可信代理给出的原样保留
+        let trusted = crate::client_ip::TrustedProxies::parse(&[String::from("192.0.2.0/24")]).unwrap();
+        let req = TestRequest::get()
+            .uri("/api/users")
+            .peer_addr("192.0.2.10:5000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .app_data(web::Data::new(trusted))
+            .to_http_request();
+        let mut headers = req.headers().clone();
+        apply_request_headers(&req, &proxy, &mut headers);
+        assert_eq!(headers.get("x-forwarded-for").unwrap(), "203.0.113.7");
+    }
 }
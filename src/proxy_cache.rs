@@ -0,0 +1,799 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::error::PayloadError;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue, HttpDate};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::{self, Bytes};
+use actix_web::{rt, HttpRequest, HttpResponse};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+
+use crate::logger::LOGGER;
+
+/// The `cache=SIZE` options of a proxy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheOptions {
+    /// 内存中缓存的最大字节数
+    pub max_size: u64,
+    /// 超过这个大小的响应不缓存
+    pub max_entry_size: u64,
+    /// 同时保存到磁盘，重启后仍然可用
+    pub dir: Option<PathBuf>,
+    pub dir_size: u64,
+    /// 响应没有 max-age 或 Expires 时的缓存时间
+    pub ttl: Duration,
+    /// 响应没有对应的 Cache-Control 指令时使用
+    pub stale_while_revalidate: Duration,
+    pub stale_if_error: Duration,
+}
+
+impl CacheOptions {
+    pub fn new(max_size: u64) -> CacheOptions {
+        CacheOptions {
+            max_size,
+            max_entry_size: 1 << 20,
+            dir: None,
+            dir_size: 1 << 30,
+            ttl: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+        }
+    }
+}
+
+/// How a cached response can be used right now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    /// 过期但在 stale-while-revalidate 内，先返回旧的再在后台更新
+    Revalidate,
+    Stale,
+}
+
+/// A cached upstream response, headers are stored after the proxy's Location and cookie rewrites.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    key: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Vary 列出的请求头以及缓存时请求中的值
+    vary: Vec<(String, Option<String>)>,
+    // 以下时间都是 unix 秒
+    stored_at: u64,
+    fresh_until: u64,
+    stale_while_revalidate: u64,
+    stale_if_error: u64,
+    must_revalidate: bool,
+    #[serde(skip)]
+    body: Bytes,
+}
+
+impl CacheEntry {
+    pub fn freshness(&self) -> Freshness {
+        let now = now();
+        if now < self.fresh_until {
+            Freshness::Fresh
+        } else if !self.must_revalidate && now < self.fresh_until + self.stale_while_revalidate {
+            Freshness::Revalidate
+        } else {
+            Freshness::Stale
+        }
+    }
+
+    /// Whether it may be served when the upstream fails (stale-if-error).
+    pub fn usable_on_error(&self) -> bool {
+        !self.must_revalidate && now() < self.fresh_until + self.stale_if_error
+    }
+
+    fn header(&self, name: &HeaderName) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name.as_str())
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// `If-None-Match`/`If-Modified-Since` to revalidate this entry with the upstream.
+    pub fn conditional_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let validators = [
+            (header::ETAG, header::IF_NONE_MATCH),
+            (header::LAST_MODIFIED, header::IF_MODIFIED_SINCE),
+        ];
+        for (validator, condition) in validators {
+            if let Some(value) = self.header(&validator).and_then(|v| HeaderValue::from_str(v).ok()) {
+                headers.insert(condition, value);
+            }
+        }
+        headers
+    }
+
+    fn matches_vary(&self, req: &HttpRequest) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request_header(req, name) == *value)
+    }
+
+    fn size(&self) -> u64 {
+        let headers: usize = self.headers.iter().map(|(n, v)| n.len() + v.len()).sum();
+        (self.key.len() + headers + self.body.len()) as u64
+    }
+
+    /// The cached response, `304 Not Modified` when the client already has it.
+    pub fn response(&self, req: &HttpRequest) -> HttpResponse {
+        let not_modified = match (req.headers().get(header::IF_NONE_MATCH), self.header(&header::ETAG)) {
+            (Some(condition), Some(etag)) => condition
+                .to_str()
+                .map(|c| c.trim() == "*" || c.split(',').any(|tag| tag.trim() == etag))
+                .unwrap_or(false),
+            _ => false,
+        };
+        let status = if not_modified {
+            StatusCode::NOT_MODIFIED
+        } else {
+            StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK)
+        };
+        let mut response = HttpResponse::build(status);
+        for (name, value) in &self.headers {
+            if not_modified && name == header::CONTENT_LENGTH.as_str() {
+                continue;
+            }
+            response.append_header((name.as_str(), value.as_str()));
+        }
+        response.insert_header((header::AGE, now().saturating_sub(self.stored_at).to_string()));
+        if not_modified {
+            response.finish()
+        } else {
+            response.body(self.body.clone())
+        }
+    }
+}
+
+#[derive(Default)]
+struct CacheStore {
+    entries: HashMap<String, (Arc<CacheEntry>, u64)>,
+    size: u64,
+    // 递增的访问序号，淘汰最久没有使用的
+    tick: u64,
+    // 访问序号到 key 的索引，第一个就是最久没有使用的
+    recent: BTreeMap<u64, String>,
+    revalidating: HashSet<String>,
+    disk_size: u64,
+}
+
+impl CacheStore {
+    // 命中时更新访问序号
+    fn touch(&mut self, key: &str) -> Option<Arc<CacheEntry>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (entry, used) = self.entries.get_mut(key)?;
+        let previous = std::mem::replace(used, tick);
+        let entry = entry.clone();
+        self.recent.remove(&previous);
+        self.recent.insert(tick, key.to_string());
+        Some(entry)
+    }
+}
+
+/// Response cache of a proxy, shared by the workers of a server generation.
+#[derive(Clone)]
+pub struct ProxyCache {
+    pub options: CacheOptions,
+    store: Arc<Mutex<CacheStore>>,
+}
+
+impl ProxyCache {
+    pub fn new(options: CacheOptions) -> io::Result<ProxyCache> {
+        let mut store = CacheStore::default();
+        if let Some(dir) = &options.dir {
+            fs::create_dir_all(dir)?;
+            store.disk_size = disk_usage(dir).iter().map(|(_, size, _)| size).sum();
+        }
+        Ok(ProxyCache {
+            options,
+            store: Arc::new(Mutex::new(store)),
+        })
+    }
+
+    /// Cache key of a request, `None` when it must not be served from the cache.
+    pub fn key(&self, req: &HttpRequest) -> Option<String> {
+        if req.method() != Method::GET || req.headers().contains_key(header::AUTHORIZATION) {
+            return None;
+        }
+        let cache_control = CacheControl::parse(req.headers());
+        if cache_control.no_store || cache_control.no_cache {
+            return None;
+        }
+        let host = req.headers().get(header::HOST).and_then(|v| v.to_str().ok());
+        let uri = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        Some(format!("{}{}", host.unwrap_or_default(), uri))
+    }
+
+    /// Any stored response for the key whose Vary headers match the request, fresh or not.
+    /// Entries only on disk are read on the blocking thread pool.
+    pub async fn lookup(&self, key: &str, req: &HttpRequest) -> Option<Arc<CacheEntry>> {
+        let entry = self.store.lock().unwrap().touch(key);
+        let entry = match entry {
+            Some(entry) => entry,
+            None => {
+                let dir = self.options.dir.clone()?;
+                let key = key.to_string();
+                let entry = Arc::new(web::block(move || read_disk(&dir, &key)).await.ok()??);
+                self.insert_memory(entry.clone());
+                entry
+            }
+        };
+        entry.matches_vary(req).then_some(entry)
+    }
+
+    /// A cache entry for an upstream response, `None` when it is not cacheable.
+    /// The body is added by `fill`.
+    pub fn entry(&self, key: &str, req: &HttpRequest, status: StatusCode, headers: &HeaderMap) -> Option<CacheEntry> {
+        if !matches!(status.as_u16(), 200 | 203 | 204 | 300 | 301 | 308 | 404 | 410)
+            || headers.contains_key(header::SET_COOKIE)
+        {
+            return None;
+        }
+        let vary: Vec<String> = headers
+            .get_all(header::VARY)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if vary.iter().any(|name| name == "*") {
+            return None;
+        }
+        let mut entry = CacheEntry {
+            key: key.to_string(),
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter(|(name, _)| !is_hop_by_hop(name))
+                .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                .collect(),
+            vary: vary
+                .into_iter()
+                .map(|name| {
+                    let value = request_header(req, &name);
+                    (name, value)
+                })
+                .collect(),
+            stored_at: now(),
+            fresh_until: 0,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+            must_revalidate: false,
+            body: Bytes::new(),
+        };
+        self.set_freshness(&mut entry, headers).then_some(entry)
+    }
+
+    // 按响应头计算过期时间，既没有有效期也没有校验字段的不值得缓存
+    fn set_freshness(&self, entry: &mut CacheEntry, headers: &HeaderMap) -> bool {
+        let cache_control = CacheControl::parse(headers);
+        if cache_control.no_store || cache_control.private {
+            return false;
+        }
+        let date = http_date(headers, header::DATE).unwrap_or(entry.stored_at);
+        let age = headers
+            .get(header::AGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        let lifetime = if cache_control.no_cache {
+            0
+        } else if let Some(max_age) = cache_control.s_maxage.or(cache_control.max_age) {
+            max_age.saturating_sub(age)
+        } else if let Some(expires) = http_date(headers, header::EXPIRES) {
+            expires.saturating_sub(date)
+        } else {
+            self.options.ttl.as_secs()
+        };
+        entry.fresh_until = entry.stored_at + lifetime;
+        entry.stale_while_revalidate = cache_control
+            .stale_while_revalidate
+            .unwrap_or(self.options.stale_while_revalidate.as_secs());
+        entry.stale_if_error = cache_control
+            .stale_if_error
+            .unwrap_or(self.options.stale_if_error.as_secs());
+        // no-cache 每次使用前都要向上游确认，不能当作过期可用的副本
+        entry.must_revalidate = cache_control.must_revalidate || cache_control.no_cache;
+        let has_validator = headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED);
+        lifetime > 0 || has_validator
+    }
+
+    /// Updates an entry from the headers of a `304 Not Modified`.
+    pub fn refresh(&self, entry: &CacheEntry, headers: &HeaderMap) -> Arc<CacheEntry> {
+        let mut entry = entry.clone();
+        let mut merged = HeaderMap::new();
+        for (name, value) in &entry.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                merged.append(name, value);
+            }
+        }
+        for name in headers.keys().filter(|name| !is_hop_by_hop(name) && *name != header::CONTENT_LENGTH) {
+            merged.remove(name);
+            for value in headers.get_all(name) {
+                merged.append(name.clone(), value.clone());
+            }
+        }
+        entry.headers = merged
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        entry.stored_at = now();
+        self.set_freshness(&mut entry, &merged);
+        let entry = Arc::new(entry);
+        self.store(entry.clone());
+        entry
+    }
+
+    /// Passes the upstream body through and stores the entry once it is complete.
+    pub fn fill<S>(&self, entry: CacheEntry, body: S) -> CacheFill<S> {
+        CacheFill {
+            body,
+            buffer: Some(Vec::new()),
+            entry: Some(entry),
+            cache: self.clone(),
+        }
+    }
+
+    /// Only one background revalidation per key, returns false when one is running.
+    pub fn begin_revalidation(&self, key: &str) -> bool {
+        self.store.lock().unwrap().revalidating.insert(key.to_string())
+    }
+
+    pub fn end_revalidation(&self, key: &str) {
+        self.store.lock().unwrap().revalidating.remove(key);
+    }
+
+    fn store(&self, entry: Arc<CacheEntry>) {
+        if let Some(dir) = self.options.dir.clone() {
+            // 在线程池里写磁盘，不阻塞 worker
+            let cache = self.clone();
+            let disk_entry = entry.clone();
+            rt::spawn(async move {
+                let path = dir.clone();
+                let result = web::block(move || cache.write_disk(&dir, &disk_entry)).await;
+                if let Err(e) = result.map_err(io::Error::other).and_then(|result| result) {
+                    LOGGER.warn(format!("proxy cache: failed to write {}: {}", path.display(), e));
+                }
+            });
+        }
+        self.insert_memory(entry);
+    }
+
+    fn insert_memory(&self, entry: Arc<CacheEntry>) {
+        let size = entry.size();
+        if size > self.options.max_size {
+            return;
+        }
+        let mut store = self.store.lock().unwrap();
+        store.tick += 1;
+        let tick = store.tick;
+        store.recent.insert(tick, entry.key.clone());
+        if let Some((old, used)) = store.entries.insert(entry.key.clone(), (entry, tick)) {
+            store.size -= old.size();
+            store.recent.remove(&used);
+        }
+        store.size += size;
+        while store.size > self.options.max_size {
+            let Some((_, oldest)) = store.recent.pop_first() else {
+                break;
+            };
+            if let Some((old, _)) = store.entries.remove(&oldest) {
+                store.size -= old.size();
+            }
+        }
+    }
+
+    // 文件格式：第一行是 JSON 元数据，后面是响应体，在线程池中调用
+    fn write_disk(&self, dir: &Path, entry: &CacheEntry) -> io::Result<()> {
+        // 同一个 key 可能同时有多个写入，各自写到自己的临时文件再替换
+        static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let path = disk_path(dir, &entry.key);
+        let counter = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tmp = path.with_extension(format!("{}-{}.tmp", std::process::id(), counter));
+        let written = fs::File::create(&tmp).and_then(|mut file| {
+            serde_json::to_writer(&mut file, entry)?;
+            file.write_all(b"\n")?;
+            file.write_all(&entry.body)
+        });
+        let old_size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        if let Err(e) = written.and_then(|_| fs::rename(&tmp, &path)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        let size = fs::metadata(&path)?.len();
+        // 只在更新计数时持有锁，文件操作都在锁外
+        let over_limit = {
+            let mut store = self.store.lock().unwrap();
+            store.disk_size = (store.disk_size + size).saturating_sub(old_size);
+            store.disk_size > self.options.dir_size
+        };
+        if over_limit {
+            // 从最早写入的开始删除，直到低于上限的 90%
+            let mut files = disk_usage(dir);
+            files.sort_by_key(|(_, _, modified)| *modified);
+            let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+            for (file, size, _) in files {
+                if total <= self.options.dir_size / 10 * 9 {
+                    break;
+                }
+                if fs::remove_file(&file).is_ok() {
+                    total -= size;
+                }
+            }
+            self.store.lock().unwrap().disk_size = total;
+        }
+        Ok(())
+    }
+}
+
+// 文件名用 FNV-1a，不随 Rust 版本变化，升级后仍能找到原来的文件
+fn disk_path(dir: &Path, key: &str) -> PathBuf {
+    let hash = key
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3));
+    dir.join(format!("{:016x}.cache", hash))
+}
+
+// 在线程池中调用
+fn read_disk(dir: &Path, key: &str) -> Option<CacheEntry> {
+    let data = fs::read(disk_path(dir, key)).ok()?;
+    let split = data.iter().position(|b| *b == b'\n')?;
+    let mut entry: CacheEntry = serde_json::from_slice(&data[..split]).ok()?;
+    // 哈希冲突时不是同一个 key
+    if entry.key != key {
+        return None;
+    }
+    entry.body = Bytes::copy_from_slice(&data[split + 1..]);
+    Some(entry)
+}
+
+fn disk_usage(dir: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "cache"))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((entry.path(), metadata.len(), metadata.modified().ok()?))
+        })
+        .collect()
+}
+
+/// Upstream body stream storing the response in the cache when it completes,
+/// bodies larger than `max_entry_size` or ending with an error are not stored.
+pub struct CacheFill<S> {
+    body: S,
+    buffer: Option<Vec<u8>>,
+    entry: Option<CacheEntry>,
+    cache: ProxyCache,
+}
+
+impl<S> Stream for CacheFill<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(item) => item,
+        };
+        match &item {
+            Some(Ok(chunk)) => {
+                let max = this.cache.options.max_entry_size as usize;
+                if let Some(buffer) = &mut this.buffer {
+                    if buffer.len() + chunk.len() > max {
+                        this.buffer = None;
+                    } else {
+                        buffer.extend_from_slice(chunk);
+                    }
+                }
+            }
+            Some(Err(_)) => this.buffer = None,
+            None => {
+                if let (Some(buffer), Some(mut entry)) = (this.buffer.take(), this.entry.take()) {
+                    entry.body = Bytes::from(buffer);
+                    this.cache.store(Arc::new(entry));
+                }
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[derive(Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    stale_while_revalidate: Option<u64>,
+    stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> CacheControl {
+        let mut cache_control = CacheControl::default();
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = value.and_then(|v| v.parse::<u64>().ok());
+            match name.trim().to_lowercase().as_str() {
+                "no-store" => cache_control.no_store = true,
+                "no-cache" => cache_control.no_cache = true,
+                "private" => cache_control.private = true,
+                "must-revalidate" | "proxy-revalidate" => cache_control.must_revalidate = true,
+                "max-age" => cache_control.max_age = seconds,
+                "s-maxage" => cache_control.s_maxage = seconds,
+                "stale-while-revalidate" => cache_control.stale_while_revalidate = seconds,
+                "stale-if-error" => cache_control.stale_if_error = seconds,
+                _ => {}
+            }
+        }
+        cache_control
+    }
+}
+
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    matches!(
+        name.as_str(),
+        "connection" | "keep-alive" | "transfer-encoding" | "te" | "trailer" | "upgrade" | "age"
+    ) || name.as_str().starts_with("proxy-")
+}
+
+fn request_header(req: &HttpRequest, name: &str) -> Option<String> {
+    let values: Vec<&str> = req
+        .headers()
+        .get_all(name)
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+fn http_date(headers: &HeaderMap, name: HeaderName) -> Option<u64> {
+    let date: HttpDate = headers.get(name)?.to_str().ok()?.parse().ok()?;
+    SystemTime::from(date)
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use actix_web::test::TestRequest;
+    use futures::{stream, StreamExt};
+    use tempfile::TempDir;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        map
+    }
+
+    async fn fill(cache: &ProxyCache, entry: CacheEntry, body: String) {
+        let chunks = stream::iter(vec![Ok::<_, PayloadError>(Bytes::from(body))]);
+        let mut fill = cache.fill(entry, chunks);
+        while fill.next().await.is_some() {}
+    }
+
+    #[test]
+    fn test_cacheable_requests_and_responses() {
+        let cache = ProxyCache::new(CacheOptions::new(1 << 20)).unwrap();
+        let req = TestRequest::get()
+            .uri("/a?x=1")
+            .insert_header(("host", "example.com"))
+            .to_http_request();
+        assert_eq!(cache.key(&req).as_deref(), Some("example.com/a?x=1"));
+        assert!(cache.key(&TestRequest::post().to_http_request()).is_none());
+        let req_no_cache = TestRequest::get()
+            .insert_header(("cache-control", "no-cache"))
+            .to_http_request();
+        assert!(cache.key(&req_no_cache).is_none());
+        let req_auth = TestRequest::get()
+            .insert_header(("authorization", "Bearer t"))
+            .to_http_request();
+        assert!(cache.key(&req_auth).is_none());
+
+        let entry = |status: u16, pairs: &[(&str, &str)]| {
+            cache.entry("k", &req, StatusCode::from_u16(status).unwrap(), &headers(pairs))
+        };
+        assert!(entry(200, &[("cache-control", "max-age=60")]).is_some());
+        assert!(entry(200, &[("etag", "\"v1\"")]).is_some());
+        // 既没有有效期也没有校验字段
+        assert!(entry(200, &[]).is_none());
+        assert!(entry(500, &[("cache-control", "max-age=60")]).is_none());
+        assert!(entry(200, &[("cache-control", "private, max-age=60")]).is_none());
+        assert!(entry(200, &[("cache-control", "no-store")]).is_none());
+        assert!(entry(200, &[("cache-control", "max-age=60"), ("set-cookie", "a=1")]).is_none());
+        assert!(entry(200, &[("cache-control", "max-age=60"), ("vary", "*")]).is_none());
+    }
+
+    #[test]
+    fn test_freshness() {
+        let mut options = CacheOptions::new(1 << 20);
+        options.stale_if_error = Duration::from_secs(60);
+        let cache = ProxyCache::new(options).unwrap();
+        let req = TestRequest::get().to_http_request();
+        let entry = |pairs: &[(&str, &str)]| cache.entry("k", &req, StatusCode::OK, &headers(pairs)).unwrap();
+
+        let fresh = entry(&[("cache-control", "public, s-maxage=60, max-age=0")]);
+        assert_eq!(fresh.freshness(), Freshness::Fresh);
+        assert_eq!(entry(&[("cache-control", "max-age=60"), ("age", "60"), ("etag", "\"v1\"")]).freshness(), Freshness::Stale);
+        let expires = entry(&[
+            ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("expires", "Sun, 06 Nov 1994 08:50:37 GMT"),
+        ]);
+        assert_eq!(expires.freshness(), Freshness::Fresh);
+        let swr = entry(&[("cache-control", "max-age=0, stale-while-revalidate=30"), ("etag", "\"v1\"")]);
+        assert_eq!(swr.freshness(), Freshness::Revalidate);
+        assert!(swr.usable_on_error());
+        let strict = entry(&[("cache-control", "no-cache, must-revalidate"), ("etag", "\"v1\"")]);
+        assert_eq!(strict.freshness(), Freshness::Stale);
+        assert!(!strict.usable_on_error());
+        assert_eq!(strict.conditional_headers().get("if-none-match").unwrap(), "\"v1\"");
+        // 单独的 no-cache 也不能在出错或后台更新时使用
+        let no_cache = entry(&[("cache-control", "no-cache, stale-while-revalidate=30"), ("etag", "\"v1\"")]);
+        assert_eq!(no_cache.freshness(), Freshness::Stale);
+        assert!(!no_cache.usable_on_error());
+    }
+
+    #[actix_web::test]
+    async fn test_lookup_vary_and_revalidate() {
+        let cache = ProxyCache::new(CacheOptions::new(1 << 20)).unwrap();
+        let gzip = TestRequest::get()
+            .insert_header(("accept-encoding", "gzip"))
+            .to_http_request();
+        let plain = TestRequest::get().to_http_request();
+        let entry = cache
+            .entry(
+                "k",
+                &gzip,
+                StatusCode::OK,
+                &headers(&[("cache-control", "max-age=0"), ("etag", "\"v1\""), ("vary", "Accept-Encoding")]),
+            )
+            .unwrap();
+        fill(&cache, entry, "hello".to_string()).await;
+        assert!(cache.lookup("k", &plain).await.is_none());
+        let entry = cache.lookup("k", &gzip).await.unwrap();
+        assert_eq!(entry.freshness(), Freshness::Stale);
+
+        // 304 更新有效期，保留原来的响应体
+        let entry = cache.refresh(&entry, &headers(&[("cache-control", "max-age=60")]));
+        assert_eq!(entry.freshness(), Freshness::Fresh);
+        let res = entry.response(&gzip);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("etag").unwrap(), "\"v1\"");
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), "hello");
+        let conditional = TestRequest::get()
+            .insert_header(("if-none-match", "\"v0\", \"v1\""))
+            .to_http_request();
+        assert_eq!(entry.response(&conditional).status(), StatusCode::NOT_MODIFIED);
+        assert!(cache.begin_revalidation("k"));
+        assert!(!cache.begin_revalidation("k"));
+        cache.end_revalidation("k");
+        assert!(cache.begin_revalidation("k"));
+    }
+
+    #[actix_web::test]
+    async fn test_lru_eviction_and_max_entry() {
+        let mut options = CacheOptions::new(150);
+        options.max_entry_size = 60;
+        let cache = ProxyCache::new(options).unwrap();
+        let req = TestRequest::get().to_http_request();
+        let store = |key: &'static str, body: String| {
+            let entry = cache
+                .entry(key, &req, StatusCode::OK, &headers(&[("cache-control", "max-age=60")]))
+                .unwrap();
+            fill(&cache, entry, body)
+        };
+        store("a", "a".repeat(40)).await;
+        store("b", "b".repeat(40)).await;
+        assert!(cache.lookup("a", &req).await.is_some());
+        // 放入 c 时淘汰最久没有使用的 b
+        store("c", "c".repeat(40)).await;
+        assert!(cache.lookup("a", &req).await.is_some());
+        assert!(cache.lookup("b", &req).await.is_none());
+        assert!(cache.lookup("c", &req).await.is_some());
+        store("d", "d".repeat(80)).await;
+        assert!(cache.lookup("d", &req).await.is_none());
+        // 再次访问 a 后，放入 e 时淘汰的是 c
+        assert!(cache.lookup("a", &req).await.is_some());
+        store("e", "e".repeat(60)).await;
+        assert!(cache.lookup("c", &req).await.is_none());
+        assert!(cache.lookup("a", &req).await.is_some());
+        assert!(cache.lookup("e", &req).await.is_some());
+        assert_eq!(cache.store.lock().unwrap().recent.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_disk_cache() {
+        let dir = TempDir::new().unwrap();
+        let mut options = CacheOptions::new(1 << 20);
+        options.dir = Some(dir.path().to_path_buf());
+        let cache = ProxyCache::new(options.clone()).unwrap();
+        let req = TestRequest::get().to_http_request();
+        let entry = cache
+            .entry("k", &req, StatusCode::OK, &headers(&[("cache-control", "max-age=60")]))
+            .unwrap();
+        fill(&cache, entry, "line1\nline2".to_string()).await;
+        // 等待后台写完
+        for _ in 0..100 {
+            if cache.store.lock().unwrap().disk_size > 0 {
+                break;
+            }
+            rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        // 重启后从磁盘读取
+        let cache = ProxyCache::new(options).unwrap();
+        let entry = cache.lookup("k", &req).await.unwrap();
+        assert_eq!(entry.freshness(), Freshness::Fresh);
+        assert_eq!(to_bytes(entry.response(&req).into_body()).await.unwrap(), "line1\nline2");
+        assert!(cache.lookup("other", &req).await.is_none());
+    }
+
+    #[test]
+    fn test_concurrent_disk_writes_of_one_key() {
+        let dir = TempDir::new().unwrap();
+        let mut options = CacheOptions::new(1 << 20);
+        options.dir = Some(dir.path().to_path_buf());
+        let cache = ProxyCache::new(options).unwrap();
+        let req = TestRequest::get().to_http_request();
+        let writers: Vec<_> = (0..8u8)
+            .map(|i| {
+                let mut entry = cache
+                    .entry("k", &req, StatusCode::OK, &headers(&[("cache-control", "max-age=60")]))
+                    .unwrap();
+                entry.body = Bytes::from(vec![b'a' + i; 64 << 10]);
+                let cache = cache.clone();
+                let dir = dir.path().to_path_buf();
+                std::thread::spawn(move || cache.write_disk(&dir, &entry).unwrap())
+            })
+            .collect();
+        writers.into_iter().for_each(|writer| writer.join().unwrap());
+        // 读到的是某一次完整的写入，没有残留的临时文件
+        let body = read_disk(dir.path(), "k").unwrap().body;
+        assert_eq!(body.len(), 64 << 10);
+        assert!(body.iter().all(|b| *b == body[0]));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        // 文件名不随 Rust 版本变化
+        assert_eq!(disk_path(Path::new("/c"), "a"), Path::new("/c/af63dc4c8601ec8c.cache"));
+    }
+}
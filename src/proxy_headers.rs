@@ -0,0 +1,216 @@
+use std::sync::LazyLock;
+
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::HttpRequest;
+use fancy_regex::{Captures, Regex};
+
+use crate::client_ip::client_ip;
+use crate::logger::LOGGER;
+
+// $host、$http_x_request_id 形式的请求变量
+static REQUEST_VAR_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$([a-z_][a-z0-9_]*)").unwrap());
+
+/// Header value with nginx-like request variables, rendered for every request.
+/// `$remote_addr`, `$host`, `$scheme`, `$request_method`, `$request_uri`, `$uri`, `$args`
+/// and `$http_<name>` for any request header, unknown variables are kept as is.
+#[derive(Clone, Debug)]
+pub struct HeaderTemplate(String);
+
+impl HeaderTemplate {
+    pub fn render(&self, req: &HttpRequest) -> String {
+        if !self.0.contains('$') {
+            return self.0.clone();
+        }
+        REQUEST_VAR_REGEX
+            .replace_all(&self.0, |caps: &Captures| {
+                request_var(req, &caps[1]).unwrap_or_else(|| caps[0].to_string())
+            })
+            .into_owned()
+    }
+}
+
+fn request_var(req: &HttpRequest, name: &str) -> Option<String> {
+    let value = match name {
+        "remote_addr" => client_ip(req).map(|ip| ip.to_string()).unwrap_or_default(),
+        "host" => request_host(req).unwrap_or_default(),
+        "scheme" => String::from(if req.app_config().secure() { "https" } else { "http" }),
+        "request_method" => req.method().to_string(),
+        "request_uri" => req
+            .uri()
+            .path_and_query()
+            .map(|p| p.to_string())
+            .unwrap_or_else(|| req.path().to_string()),
+        "uri" => req.path().to_string(),
+        "args" => req.query_string().to_string(),
+        _ => {
+            let header = name.strip_prefix("http_")?.replace('_', "-");
+            req.headers()
+                .get(header.as_str())
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        }
+    };
+    Some(value)
+}
+
+/// Host header of the client request, from the authority for HTTP/2.
+pub fn request_host(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .or_else(|| req.uri().authority().map(|a| a.to_string()))
+}
+
+#[derive(Clone, Debug)]
+pub enum HeaderAction {
+    Set(HeaderName, HeaderTemplate),
+    Append(HeaderName, HeaderTemplate),
+    Remove(HeaderName),
+}
+
+impl HeaderAction {
+    /// `kind` is `set`, `add` or `remove`, `value` is `Name: value` or only `Name` for `remove`.
+    pub fn parse(kind: &str, value: &str) -> Result<HeaderAction, String> {
+        let parse_name = |name: &str| {
+            HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| format!("Invalid header name '{}'", name.trim()))
+        };
+        if kind == "remove" {
+            return Ok(HeaderAction::Remove(parse_name(value)?));
+        }
+        let (name, template) = value
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid header '{}', expected \"Name: value\"", value))?;
+        let name = parse_name(name)?;
+        let template = HeaderTemplate(template.trim().to_string());
+        Ok(match kind {
+            "add" => HeaderAction::Append(name, template),
+            _ => HeaderAction::Set(name, template),
+        })
+    }
+
+    /// Applies the action, values that are not valid header values after rendering are skipped.
+    pub fn apply(&self, req: &HttpRequest, headers: &mut HeaderMap) {
+        let render = |name: &HeaderName, template: &HeaderTemplate| {
+            let value = template.render(req);
+            HeaderValue::from_str(&value)
+                .inspect_err(|_| {
+                    LOGGER.warn(format!("proxy: skip invalid value for header {}: {:?}", name, value))
+                })
+                .ok()
+        };
+        match self {
+            HeaderAction::Set(name, template) => {
+                if let Some(value) = render(name, template) {
+                    headers.insert(name.clone(), value);
+                }
+            }
+            HeaderAction::Append(name, template) => {
+                if let Some(value) = render(name, template) {
+                    headers.append(name.clone(), value);
+                }
+            }
+            HeaderAction::Remove(name) => {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+/// Host header sent upstream.
+#[derive(Clone, Debug, Default)]
+pub enum HostHeader {
+    /// 客户端请求的 Host（HTTP 代理默认）
+    #[default]
+    Preserve,
+    /// 目标地址的 host:port
+    Upstream,
+    Custom(HeaderTemplate),
+}
+
+impl HostHeader {
+    pub fn parse(value: &str) -> HostHeader {
+        match value.trim() {
+            "preserve" => HostHeader::Preserve,
+            "upstream" => HostHeader::Upstream,
+            custom => HostHeader::Custom(HeaderTemplate(custom.to_string())),
+        }
+    }
+
+    /// Sets or removes the Host header, without one the client uses the upstream host.
+    pub fn apply(&self, req: &HttpRequest, headers: &mut HeaderMap) {
+        let host = match self {
+            HostHeader::Preserve => request_host(req),
+            HostHeader::Upstream => None,
+            HostHeader::Custom(template) => Some(template.render(req)),
+        };
+        match host.and_then(|h| HeaderValue::from_str(&h).ok()) {
+            Some(value) => {
+                headers.insert(header::HOST, value);
+            }
+            None => {
+                headers.remove(header::HOST);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn test_render_request_variables() {
+        let req = TestRequest::get()
+            .uri("/api/users?page=2")
+            .peer_addr("192.0.2.1:5000".parse().unwrap())
+            .insert_header((header::HOST, "app.example.com"))
+            .insert_header(("x-request-id", "abc"))
+            .to_http_request();
+        let template = HeaderTemplate(String::from(
+            "$scheme://$host$request_uri $remote_addr $request_method $uri $args $http_x_request_id $unknown",
+        ));
+        assert_eq!(
+            template.render(&req),
+            "http://app.example.com/api/users?page=2 192.0.2.1 GET /api/users page=2 abc $unknown"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_header_actions() {
+        let req = TestRequest::get().to_http_request();
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static("cookie"), HeaderValue::from_static("a=1"));
+        HeaderAction::parse("set", "X-Gateway-Base-Path: https://oa.example.com/boot")
+            .unwrap()
+            .apply(&req, &mut headers);
+        HeaderAction::parse("add", "Via: 1.1 a").unwrap().apply(&req, &mut headers);
+        HeaderAction::parse("add", "Via: 1.1 b").unwrap().apply(&req, &mut headers);
+        HeaderAction::parse("remove", "Cookie").unwrap().apply(&req, &mut headers);
+        assert_eq!(
+            headers.get("x-gateway-base-path").unwrap(),
+            "https://oa.example.com/boot"
+        );
+        assert_eq!(headers.get_all("via").count(), 2);
+        assert!(!headers.contains_key("cookie"));
+        assert!(HeaderAction::parse("set", "NoColon").is_err());
+        assert!(HeaderAction::parse("remove", "Bad Name").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_host_header() {
+        let req = TestRequest::get()
+            .insert_header((header::HOST, "app.example.com"))
+            .to_http_request();
+        let mut headers = HeaderMap::new();
+        HostHeader::Preserve.apply(&req, &mut headers);
+        assert_eq!(headers.get(header::HOST).unwrap(), "app.example.com");
+        HostHeader::Upstream.apply(&req, &mut headers);
+        assert!(!headers.contains_key(header::HOST));
+        HostHeader::parse("internal.$host").apply(&req, &mut headers);
+        assert_eq!(headers.get(header::HOST).unwrap(), "internal.app.example.com");
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_web::error::PayloadError;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::{self, Bytes};
+use actix_web::{rt, HttpRequest, HttpResponse};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::logger::LOGGER;
+
+// 录制的响应体上限
+const MAX_RECORD_SIZE: usize = 10 << 20;
+
+/// When the fixtures of `mock=DIR` are used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockMode {
+    /// 上游失败或没有配置上游时
+    Fallback,
+    /// 有对应的文件时不请求上游
+    Always,
+    /// 和 Fallback 一样，另外把上游的响应保存下来
+    Record,
+}
+
+impl MockMode {
+    pub fn parse(value: &str) -> Result<MockMode, String> {
+        match value.trim() {
+            "fallback" => Ok(MockMode::Fallback),
+            "always" => Ok(MockMode::Always),
+            "record" => Ok(MockMode::Record),
+            other => Err(format!("Unknown mock-mode '{}', expected fallback, always or record", other)),
+        }
+    }
+}
+
+/// A directory of canned responses, `GET /api/users` is answered by `DIR/GET/api/users.json`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockFixtures {
+    pub dir: PathBuf,
+    pub mode: MockMode,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum FixtureHeader {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// `{"status": 200, "headers": {"x-total": "2"}, "body": [{"id": 1}, {"id": 2}]}`,
+/// a string body is sent as is, any other JSON value as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, FixtureHeader>,
+    #[serde(default)]
+    body: Value,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl MockFixtures {
+    /// `DIR/METHOD/path.json`, a path ending with `/` uses `index.json`, the query is ignored.
+    fn fixture_path(&self, method: &Method, path: &str) -> Option<PathBuf> {
+        let mut file = self.dir.join(method.as_str());
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        if segments.iter().any(|s| *s == "." || *s == ".." || s.contains('\\')) {
+            return None;
+        }
+        for segment in &segments {
+            file.push(segment);
+        }
+        if path.ends_with('/') || segments.is_empty() {
+            file.push("index");
+        }
+        let mut name = file.file_name()?.to_os_string();
+        name.push(".json");
+        file.set_file_name(name);
+        Some(file)
+    }
+
+    fn relative<'a>(&self, file: &'a Path) -> &'a Path {
+        file.strip_prefix(&self.dir).unwrap_or(file)
+    }
+
+    /// The fixture of the request, `None` when there is none or it is invalid.
+    /// The file is read on the blocking thread pool.
+    pub async fn response(&self, req: &HttpRequest) -> Option<HttpResponse> {
+        let file = self.fixture_path(req.method(), req.path())?;
+        let path = file.clone();
+        let content = web::block(move || fs::read(path)).await.ok()?.ok()?;
+        let fixture: Fixture = match serde_json::from_slice(&content) {
+            Ok(fixture) => fixture,
+            Err(e) => {
+                LOGGER.warn(format!("mock: invalid fixture {}: {}", file.display(), e));
+                return None;
+            }
+        };
+        let Ok(status) = StatusCode::from_u16(fixture.status) else {
+            LOGGER.warn(format!("mock: invalid status {} in {}", fixture.status, file.display()));
+            return None;
+        };
+        let mut response = HttpResponse::build(status);
+        let mut has_content_type = false;
+        for (name, value) in &fixture.headers {
+            has_content_type |= name.eq_ignore_ascii_case("content-type");
+            let values = match value {
+                FixtureHeader::One(value) => std::slice::from_ref(value),
+                FixtureHeader::Many(values) => values.as_slice(),
+            };
+            for value in values {
+                response.append_header((name.as_str(), value.as_str()));
+            }
+        }
+        let body = match fixture.body {
+            Value::Null => Bytes::new(),
+            Value::String(text) => {
+                if !has_content_type {
+                    response.content_type("text/plain; charset=utf-8");
+                }
+                Bytes::from(text)
+            }
+            json => {
+                if !has_content_type {
+                    response.content_type("application/json");
+                }
+                Bytes::from(json.to_string())
+            }
+        };
+        let relative = self.relative(&file).to_string_lossy().replace('\\', "/");
+        response.insert_header(("X-Mock", relative));
+        Some(response.body(body))
+    }
+
+    /// Passes an upstream response through and saves it as the fixture of the request once complete,
+    /// the file is written on the blocking thread pool. Server errors and bodies which are neither JSON nor text are not recorded.
+    pub fn record<S>(&self, req: &HttpRequest, status: StatusCode, headers: &HeaderMap, body: S) -> MockRecorder<S> {
+        let file = self
+            .fixture_path(req.method(), req.path())
+            .filter(|_| !status.is_server_error());
+        let is_json = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("json"));
+        let mut fixture_headers = BTreeMap::new();
+        for name in headers.keys().filter(|name| is_recorded(name)) {
+            let values: Vec<String> = headers
+                .get_all(name)
+                .filter_map(|v| v.to_str().ok())
+                .map(String::from)
+                .collect();
+            let value = match <[String; 1]>::try_from(values) {
+                Ok([value]) => FixtureHeader::One(value),
+                Err(values) => FixtureHeader::Many(values),
+            };
+            fixture_headers.insert(name.to_string(), value);
+        }
+        MockRecorder {
+            body,
+            buffer: file.as_ref().map(|_| Vec::new()),
+            pending: file.map(|file| PendingFixture {
+                file,
+                is_json,
+                fixture: Fixture {
+                    status: status.as_u16(),
+                    headers: fixture_headers,
+                    body: Value::Null,
+                },
+            }),
+        }
+    }
+}
+
+// 录制时不保存逐跳头以及和这次传输相关的头
+fn is_recorded(name: &header::HeaderName) -> bool {
+    !matches!(
+        name.as_str(),
+        "connection"
+            | "keep-alive"
+            | "transfer-encoding"
+            | "te"
+            | "trailer"
+            | "upgrade"
+            | "content-length"
+            | "content-encoding"
+            | "date"
+            | "age"
+    )
+}
+
+struct PendingFixture {
+    file: PathBuf,
+    is_json: bool,
+    fixture: Fixture,
+}
+
+impl PendingFixture {
+    fn save(mut self, body: Vec<u8>) {
+        self.fixture.body = if body.is_empty() {
+            Value::Null
+        } else if let Some(json) = self.is_json.then(|| serde_json::from_slice(&body).ok()).flatten() {
+            json
+        } else if let Ok(text) = String::from_utf8(body) {
+            Value::String(text)
+        } else {
+            LOGGER.warn(format!("mock: not recording binary response {}", self.file.display()));
+            return;
+        };
+        let result = self
+            .file
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| Ok(serde_json::to_vec_pretty(&self.fixture)?))
+            .and_then(|content| fs::write(&self.file, content));
+        match result {
+            Ok(_) => LOGGER.info(format!("mock: recorded {}", self.file.display())),
+            Err(e) => LOGGER.warn(format!("mock: failed to record {}: {}", self.file.display(), e)),
+        }
+    }
+}
+
+/// Upstream body stream saving a fixture when it completes.
+pub struct MockRecorder<S> {
+    body: S,
+    buffer: Option<Vec<u8>>,
+    pending: Option<PendingFixture>,
+}
+
+impl<S> Stream for MockRecorder<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(item) => item,
+        };
+        match &item {
+            Some(Ok(chunk)) => {
+                if let Some(buffer) = &mut this.buffer {
+                    if buffer.len() + chunk.len() > MAX_RECORD_SIZE {
+                        this.buffer = None;
+                    } else {
+                        buffer.extend_from_slice(chunk);
+                    }
+                }
+            }
+            Some(Err(_)) => this.buffer = None,
+            None => {
+                if let (Some(buffer), Some(pending)) = (this.buffer.take(), this.pending.take()) {
+                    // 在线程池里写文件，不阻塞 worker
+                    rt::spawn(web::block(move || pending.save(buffer)));
+                }
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use actix_web::http::header::HeaderValue;
+    use actix_web::test::TestRequest;
+    use futures::{stream, StreamExt};
+    use tempfile::TempDir;
+
+    fn fixtures() -> (TempDir, MockFixtures) {
+        let tmp = TempDir::new().unwrap();
+        let mock = MockFixtures {
+            dir: tmp.path().to_path_buf(),
+            mode: MockMode::Fallback,
+        };
+        (tmp, mock)
+    }
+
+    #[test]
+    fn test_fixture_path() {
+        let (_tmp, mock) = fixtures();
+        let path = |method: Method, path: &str| {
+            mock.fixture_path(&method, path)
+                .map(|file| mock.relative(&file).to_string_lossy().into_owned())
+        };
+        assert_eq!(path(Method::GET, "/api/users").as_deref(), Some("GET/api/users.json"));
+        assert_eq!(path(Method::POST, "/api/users/").as_deref(), Some("POST/api/users/index.json"));
+        assert_eq!(path(Method::GET, "/").as_deref(), Some("GET/index.json"));
+        assert_eq!(path(Method::GET, "/api/../secret"), None);
+        assert!(MockMode::parse("replay").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_serve_fixture() {
+        let (_tmp, mock) = fixtures();
+        fs::create_dir_all(mock.dir.join("GET/api")).unwrap();
+        fs::write(
+            mock.dir.join("GET/api/users.json"),
+            r#"{"headers": {"x-total": "1", "set-cookie": ["a=1", "b=2"]}, "body": [{"id": 1}]}"#,
+        )
+        .unwrap();
+        fs::write(mock.dir.join("GET/api/text.json"), r#"{"status": 201, "body": "created"}"#).unwrap();
+        fs::write(mock.dir.join("GET/api/broken.json"), "{").unwrap();
+
+        let req = TestRequest::get().uri("/api/users?page=2").to_http_request();
+        let res = mock.response(&req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "application/json");
+        assert_eq!(res.headers().get("x-total").unwrap(), "1");
+        assert_eq!(res.headers().get_all("set-cookie").count(), 2);
+        assert_eq!(res.headers().get("x-mock").unwrap(), "GET/api/users.json");
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), r#"[{"id":1}]"#);
+
+        let res = mock.response(&TestRequest::get().uri("/api/text").to_http_request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers().get("content-type").unwrap(), "text/plain; charset=utf-8");
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), "created");
+
+        assert!(mock.response(&TestRequest::get().uri("/api/broken").to_http_request()).await.is_none());
+        assert!(mock.response(&TestRequest::post().uri("/api/users").to_http_request()).await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_record_and_replay() {
+        let (_tmp, mock) = fixtures();
+        let req = TestRequest::get().uri("/api/items").to_http_request();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("14"));
+        headers.insert(header::ETAG, HeaderValue::from_static("\"v1\""));
+        let chunks = vec![Ok(Bytes::from_static(b"{\"items\":")), Ok(Bytes::from_static(b"[1,2]}"))];
+        let mut body = mock.record(&req, StatusCode::OK, &headers, stream::iter(chunks));
+        while body.next().await.is_some() {}
+        // 等待后台写完
+        let file = mock.dir.join("GET/api/items.json");
+        for _ in 0..100 {
+            if file.exists() {
+                break;
+            }
+            rt::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let saved: Value = serde_json::from_slice(&fs::read(mock.dir.join("GET/api/items.json")).unwrap()).unwrap();
+        assert_eq!(
+            saved,
+            serde_json::json!({
+                "status": 200,
+                "headers": {"content-type": "application/json", "etag": "\"v1\""},
+                "body": {"items": [1, 2]}
+            })
+        );
+        let res = mock.response(&req).await.unwrap();
+        assert_eq!(res.headers().get("etag").unwrap(), "\"v1\"");
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), r#"{"items":[1,2]}"#);
+
+        // 上游的 5xx 不录制
+        let req = TestRequest::get().uri("/api/down").to_http_request();
+        let chunks = vec![Ok(Bytes::from_static(b"oops"))];
+        let mut body = mock.record(&req, StatusCode::BAD_GATEWAY, &HeaderMap::new(), stream::iter(chunks));
+        while body.next().await.is_some() {}
+        assert!(!mock.dir.join("GET/api/down.json").exists());
+    }
+}
@@ -0,0 +1,326 @@
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use url::{Position, Url};
+
+use crate::upstream::Upstream;
+
+/// A rule of `redirect`, `cookie-path` or `cookie-domain`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rewrite {
+    /// 按代理规则自动反向映射
+    Default,
+    /// 以 `from` 开头（domain 为相等）时替换为 `to`
+    Replace { from: String, to: String },
+}
+
+/// The nginx `proxy_redirect`, `proxy_cookie_path` and `proxy_cookie_domain` of a proxy,
+/// all `default` unless configured.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseRewrites {
+    pub redirect: Vec<Rewrite>,
+    pub cookie_path: Vec<Rewrite>,
+    pub cookie_domain: Vec<Rewrite>,
+    // 已经配置过的选项，第一次配置时替换掉默认规则
+    configured: Vec<&'static str>,
+}
+
+impl Default for ResponseRewrites {
+    fn default() -> Self {
+        ResponseRewrites {
+            redirect: vec![Rewrite::Default],
+            cookie_path: vec![Rewrite::Default],
+            cookie_domain: vec![Rewrite::Default],
+            configured: vec![],
+        }
+    }
+}
+
+/// How paths of a proxy map between hs and the upstream.
+pub struct PathMapping<'a> {
+    pub origin_path: &'a str,
+    /// 目标地址以 / 结尾时，上游收到的路径是把 origin_path 换成了 target_path
+    pub strips_prefix: bool,
+    pub target_path: &'a str,
+    pub upstreams: &'a [Upstream],
+}
+
+impl PathMapping<'_> {
+    /// Public path of an upstream path, the reverse of `get_proxy_path`.
+    fn public_path(&self, path: &str) -> String {
+        if !self.strips_prefix {
+            return path.to_string();
+        }
+        let target = self.target_path.trim_end_matches('/');
+        match path.strip_prefix(target) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                let rest = if rest.is_empty() { "/" } else { rest };
+                format!("{}{}", self.origin_path.trim_end_matches('/'), rest)
+            }
+            // 不在目标路径下的无法映射
+            _ => path.to_string(),
+        }
+    }
+
+    fn is_upstream(&self, url: &Url) -> bool {
+        self.upstreams
+            .iter()
+            .any(|upstream| upstream.parsed.origin() == url.origin())
+    }
+
+    fn is_upstream_host(&self, domain: &str) -> bool {
+        let domain = domain.trim_start_matches('.');
+        self.upstreams
+            .iter()
+            .filter_map(|upstream| upstream.parsed.host_str())
+            .any(|host| host.eq_ignore_ascii_case(domain))
+    }
+}
+
+impl ResponseRewrites {
+    /// `key` is `redirect`, `cookie-path` or `cookie-domain`, `value` is `default`, `off` or `FROM TO`.
+    pub fn set(&mut self, key: &'static str, value: &str) -> Result<(), String> {
+        let rules = match key {
+            "redirect" => &mut self.redirect,
+            "cookie-path" => &mut self.cookie_path,
+            _ => &mut self.cookie_domain,
+        };
+        if !self.configured.contains(&key) {
+            self.configured.push(key);
+            rules.clear();
+        }
+        match value.trim() {
+            "off" => rules.clear(),
+            "default" => rules.push(Rewrite::Default),
+            value => {
+                let (from, to) = value
+                    .split_once(' ')
+                    .ok_or_else(|| format!("Invalid {} '{}', expected default, off or \"FROM TO\"", key, value))?;
+                rules.push(Rewrite::Replace {
+                    from: from.to_string(),
+                    to: to.trim().to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites `Location`, `Content-Location` and `Set-Cookie` of an upstream response.
+    pub fn apply(&self, mapping: &PathMapping, headers: &mut HeaderMap) {
+        for name in [header::LOCATION, header::CONTENT_LOCATION] {
+            let location = headers
+                .get(&name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| self.rewrite_location(mapping, v));
+            if let Some(value) = location.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                headers.insert(name, value);
+            }
+        }
+        if (self.cookie_path.is_empty() && self.cookie_domain.is_empty())
+            || !headers.contains_key(header::SET_COOKIE)
+        {
+            return;
+        }
+        let cookies: Vec<HeaderValue> = headers
+            .get_all(header::SET_COOKIE)
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|v| HeaderValue::from_str(&self.rewrite_cookie(mapping, v)).ok())
+                    .unwrap_or_else(|| value.clone())
+            })
+            .collect();
+        headers.remove(header::SET_COOKIE);
+        for cookie in cookies {
+            headers.append(header::SET_COOKIE, cookie);
+        }
+    }
+
+    fn rewrite_location(&self, mapping: &PathMapping, location: &str) -> Option<String> {
+        self.redirect.iter().find_map(|rule| match rule {
+            // 指向上游的绝对地址改为 hs 上的路径
+            Rewrite::Default => {
+                let url = Url::parse(location).ok()?;
+                mapping
+                    .is_upstream(&url)
+                    .then(|| mapping.public_path(&url[Position::BeforePath..]))
+            }
+            Rewrite::Replace { from, to } => location
+                .strip_prefix(from.as_str())
+                .map(|rest| format!("{}{}", to, rest)),
+        })
+    }
+
+    fn rewrite_cookie(&self, mapping: &PathMapping, cookie: &str) -> String {
+        let mut parts: Vec<String> = cookie.split(';').map(|p| p.trim().to_string()).collect();
+        // 第一部分是 cookie 自己的 name=value，只改后面的属性
+        let mut attributes = parts.split_off(1);
+        attributes.retain_mut(|part| {
+            let Some((key, value)) = part.split_once('=') else {
+                return true;
+            };
+            let rewritten = if key.trim().eq_ignore_ascii_case("path") {
+                self.rewrite_cookie_path(mapping, value.trim())
+            } else if key.trim().eq_ignore_ascii_case("domain") {
+                self.rewrite_cookie_domain(mapping, value.trim())
+            } else {
+                return true;
+            };
+            match rewritten {
+                // 空的 Domain 移除后 cookie 只属于当前 host
+                Some(value) if value.is_empty() => false,
+                Some(value) => {
+                    *part = format!("{}={}", key.trim(), value);
+                    true
+                }
+                None => true,
+            }
+        });
+        parts.append(&mut attributes);
+        parts.join("; ")
+    }
+
+    fn rewrite_cookie_path(&self, mapping: &PathMapping, path: &str) -> Option<String> {
+        self.cookie_path.iter().find_map(|rule| match rule {
+            Rewrite::Default => {
+                let public = mapping.public_path(path);
+                // Path=/ 对应 /api，而不是 /api/，这样 /api 本身也能带上 cookie
+                let is_root = path.trim_end_matches('/') == mapping.target_path.trim_end_matches('/');
+                let public = match public.strip_suffix('/') {
+                    Some(trimmed) if is_root && !trimmed.is_empty() => trimmed.to_string(),
+                    _ => public,
+                };
+                (public != path).then_some(public)
+            }
+            Rewrite::Replace { from, to } => path
+                .strip_prefix(from.as_str())
+                .map(|rest| format!("{}{}", to, rest)),
+        })
+    }
+
+    fn rewrite_cookie_domain(&self, mapping: &PathMapping, domain: &str) -> Option<String> {
+        self.cookie_domain.iter().find_map(|rule| match rule {
+            Rewrite::Default => mapping.is_upstream_host(domain).then(String::new),
+            Rewrite::Replace { from, to } => domain
+                .trim_start_matches('.')
+                .eq_ignore_ascii_case(from.trim_start_matches('.'))
+                .then(|| to.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams() -> Vec<Upstream> {
+        vec![
+            Upstream::parse("http://backend:8080/").unwrap(),
+            Upstream::parse("http://backend2:8080/").unwrap(),
+        ]
+    }
+
+    fn rewrite(rewrites: &ResponseRewrites, strips_prefix: bool, headers: &[(&str, &str)]) -> HeaderMap {
+        let upstreams = upstreams();
+        let mapping = PathMapping {
+            origin_path: "/api",
+            strips_prefix,
+            target_path: "/",
+            upstreams: &upstreams,
+        };
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        rewrites.apply(&mapping, &mut map);
+        map
+    }
+
+    #[test]
+    fn test_default_location() {
+        let rewrites = ResponseRewrites::default();
+        let headers = rewrite(
+            &rewrites,
+            true,
+            &[
+                ("location", "http://backend2:8080/login?next=/a#top"),
+                ("content-location", "http://other.example.com/x"),
+            ],
+        );
+        assert_eq!(headers.get("location").unwrap(), "/api/login?next=/a#top");
+        assert_eq!(headers.get("content-location").unwrap(), "http://other.example.com/x");
+        // 不去掉前缀时上游路径和 hs 上的一致
+        let headers = rewrite(&rewrites, false, &[("location", "http://backend:8080/api/login")]);
+        assert_eq!(headers.get("location").unwrap(), "/api/login");
+        // 只有路径的地址默认不处理
+        let headers = rewrite(&rewrites, true, &[("location", "/login")]);
+        assert_eq!(headers.get("location").unwrap(), "/login");
+    }
+
+    #[test]
+    fn test_replace_and_off() {
+        let mut rewrites = ResponseRewrites::default();
+        rewrites.set("redirect", "/ /api/").unwrap();
+        rewrites.set("redirect", "default").unwrap();
+        let headers = rewrite(&rewrites, true, &[("location", "/login")]);
+        assert_eq!(headers.get("location").unwrap(), "/api/login");
+        let headers = rewrite(&rewrites, true, &[("location", "http://backend:8080/login")]);
+        assert_eq!(headers.get("location").unwrap(), "/api/login");
+
+        let mut rewrites = ResponseRewrites::default();
+        rewrites.set("redirect", "off").unwrap();
+        rewrites.set("cookie-path", "off").unwrap();
+        rewrites.set("cookie-domain", "off").unwrap();
+        let headers = rewrite(
+            &rewrites,
+            true,
+            &[
+                ("location", "http://backend:8080/login"),
+                ("set-cookie", "a=1; Path=/; Domain=backend"),
+            ],
+        );
+        assert_eq!(headers.get("location").unwrap(), "http://backend:8080/login");
+        assert_eq!(headers.get("set-cookie").unwrap(), "a=1; Path=/; Domain=backend");
+        assert!(rewrites.set("redirect", "nospace").is_err());
+    }
+
+    #[test]
+    fn test_cookies() {
+        let headers = rewrite(
+            &ResponseRewrites::default(),
+            true,
+            &[
+                ("set-cookie", "session=abc; Path=/; Domain=.backend; HttpOnly"),
+                ("set-cookie", "theme=dark; path=/settings; Domain=example.com"),
+            ],
+        );
+        let cookies: Vec<_> = headers.get_all("set-cookie").map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(
+            cookies,
+            vec![
+                "session=abc; Path=/api; HttpOnly",
+                "theme=dark; path=/api/settings; Domain=example.com"
+            ]
+        );
+
+        let mut rewrites = ResponseRewrites::default();
+        rewrites.set("cookie-domain", "backend app.example.com").unwrap();
+        rewrites.set("cookie-path", "/ /").unwrap();
+        let headers = rewrite(&rewrites, true, &[("set-cookie", "a=1; Path=/x; Domain=backend")]);
+        assert_eq!(headers.get("set-cookie").unwrap(), "a=1; Path=/x; Domain=app.example.com");
+
+        // 名字是 domain 或 path 的 cookie 不当作属性
+        let headers = rewrite(
+            &ResponseRewrites::default(),
+            true,
+            &[
+                ("set-cookie", "domain=backend; Path=/; Domain=backend"),
+                ("set-cookie", "path=/; Path=/"),
+            ],
+        );
+        let cookies: Vec<_> = headers.get_all("set-cookie").map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(cookies, vec!["domain=backend; Path=/api", "path=/; Path=/api"]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::str::FromStr;
+
+use actix_web::dev::RequestHead;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Method;
+use fancy_regex::{Expander, Regex};
+
+/// When a proxy rule applies besides its path prefix: a path regex, methods and headers.
+/// Rules are tried by `priority`, higher first, then in the order they are given.
+#[derive(Clone, Debug, Default)]
+pub struct RouteMatch {
+    /// `~` 开头的规则用正则匹配整个路径，捕获组可以在目标地址中使用
+    pub regex: Option<Regex>,
+    pub methods: Vec<Method>,
+    /// 值为空时只要求有这个请求头
+    pub headers: Vec<(HeaderName, Option<HeaderValue>)>,
+    pub priority: i32,
+}
+
+impl RouteMatch {
+    /// `~^/api/v(\d+)/(.*)` for a regex rule, any other origin is a path prefix.
+    pub fn parse_origin(origin: &str) -> Result<Option<Regex>, String> {
+        let Some(pattern) = origin.strip_prefix('~') else {
+            return Ok(None);
+        };
+        Regex::new(pattern)
+            .map(Some)
+            .map_err(|e| format!("Invalid proxy regex '{}': {}", pattern, e))
+    }
+
+    /// `GET,POST`
+    pub fn parse_methods(value: &str) -> Result<Vec<Method>, String> {
+        value
+            .split(',')
+            .map(|method| {
+                Method::from_str(&method.trim().to_uppercase())
+                    .map_err(|_| format!("Invalid method '{}'", method.trim()))
+            })
+            .collect()
+    }
+
+    /// `Name: value` matches the exact value, `Name` only requires the header.
+    pub fn parse_header(value: &str) -> Result<(HeaderName, Option<HeaderValue>), String> {
+        let (name, value) = match value.split_once(':') {
+            Some((name, value)) => (name, Some(value.trim())),
+            None => (value, None),
+        };
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| format!("Invalid header name '{}'", name.trim()))?;
+        let value = value
+            .map(|v| HeaderValue::from_str(v).map_err(|_| format!("Invalid header value '{}'", v)))
+            .transpose()?;
+        Ok((name, value))
+    }
+
+    /// Whether there is more to check than the path prefix.
+    pub fn has_conditions(&self) -> bool {
+        self.regex.is_some() || !self.methods.is_empty() || !self.headers.is_empty()
+    }
+
+    pub fn matches(&self, head: &RequestHead) -> bool {
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(head.uri.path()).unwrap_or(false) {
+                return false;
+            }
+        }
+        if !self.methods.is_empty() && !self.methods.contains(&head.method) {
+            return false;
+        }
+        self.headers.iter().all(|(name, value)| match value {
+            Some(value) => head.headers.get_all(name).any(|v| v == value),
+            None => head.headers.contains_key(name),
+        })
+    }
+
+    /// Replaces `$1`, `$name` and `${name}` in `template` with the captures of the path regex.
+    pub fn expand(&self, template: &str, path: &str) -> Option<String> {
+        let captures = self.regex.as_ref()?.captures(path).ok()??;
+        Some(Expander::default().expansion(template, &captures))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_regex_route_expand() {
+        let route = RouteMatch {
+            regex: RouteMatch::parse_origin(r"~^/api/v(\d+)/(?P<rest>.*)").unwrap(),
+            ..RouteMatch::default()
+        };
+        assert_eq!(
+            route.expand("http://svc-v$1:8080/$rest", "/api/v2/users/7").as_deref(),
+            Some("http://svc-v2:8080/users/7")
+        );
+        assert_eq!(route.expand("http://svc-v$1/", "/other"), None);
+        let req = TestRequest::get().uri("/api/v2/users").to_srv_request();
+        assert!(route.matches(req.head()));
+        let req = TestRequest::get().uri("/api/latest/users").to_srv_request();
+        assert!(!route.matches(req.head()));
+        assert_eq!(RouteMatch::parse_origin("/api").unwrap().map(|r| r.to_string()), None);
+        assert!(RouteMatch::parse_origin("~^/api/(").is_err());
+    }
+
+    #[test]
+    fn test_method_and_header_conditions() {
+        let route = RouteMatch {
+            methods: RouteMatch::parse_methods("get, head").unwrap(),
+            headers: vec![
+                RouteMatch::parse_header("X-Canary: 1").unwrap(),
+                RouteMatch::parse_header("Authorization").unwrap(),
+            ],
+            ..RouteMatch::default()
+        };
+        assert!(route.has_conditions());
+        let req = TestRequest::get()
+            .insert_header(("x-canary", "1"))
+            .insert_header(("authorization", "Bearer t"))
+            .to_srv_request();
+        assert!(route.matches(req.head()));
+        let req = TestRequest::post()
+            .insert_header(("x-canary", "1"))
+            .insert_header(("authorization", "Bearer t"))
+            .to_srv_request();
+        assert!(!route.matches(req.head()));
+        let req = TestRequest::get()
+            .insert_header(("x-canary", "0"))
+            .insert_header(("authorization", "Bearer t"))
+            .to_srv_request();
+        assert!(!route.matches(req.head()));
+        assert!(!RouteMatch::default().has_conditions());
+        assert!(RouteMatch::parse_methods("GET,B@D").is_err());
+        assert!(RouteMatch::parse_header("Bad Name: 1").is_err());
+    }
+}
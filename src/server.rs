@@ -1,41 +1,71 @@
-use actix_web::dev::{Service, ServiceRequest};
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest};
 use actix_web::error::ErrorUnauthorized;
 use actix_web::middleware::Condition;
 use actix_web::{
     body::MessageBody,
     dev::ServiceResponse,
     middleware::{from_fn, Next},
-    App, Error,
+    guard, App, Error, Scope,
 };
 use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
 use actix_web_httpauth::middleware::HttpAuthentication;
-use awc::Client;
 use chrono::prelude::DateTime;
 use chrono::Local;
 // use env_logger::Env;
-use fancy_regex::{Captures, Regex};
+use fancy_regex::Regex;
+use std::cmp::Reverse;
 use std::fs::read_dir;
+use std::future::Future;
 use std::io::Read;
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::fs::metadata;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
+use futures::future::{join_all, try_join_all};
+use tokio::time::timeout;
 use log::{self, info};
-use std::env;
 
 use actix_files::NamedFile;
+#[cfg(unix)]
+use actix_web::rt::signal::unix::{signal, Signal, SignalKind};
+use actix_web::rt::task::JoinHandle;
+use actix_http::{Extensions, HttpService, Request};
+use actix_service::{fn_service, map_config, IntoServiceFactory, ServiceFactoryExt};
+use actix_web::dev::{AppConfig, Server, ServerHandle};
 use actix_multipart::form::{tempfile::TempFile, MultipartForm};
-use actix_web::http::header::{self, ContentDisposition, DispositionType};
+use actix_web::http::header::{
+    self, AcceptEncoding, ContentDisposition, ContentEncoding, DispositionType, Encoding, Header,
+};
 use actix_web::{
-    get, middleware, post, web, HttpRequest, HttpResponse, HttpServer, Responder,
+    get, http::StatusCode, middleware, post, rt, web, HttpMessage, HttpRequest, HttpResponse,
+    HttpServer, Responder,
 };
 use local_ip_address::list_afinet_netifas;
 use open::that;
 
 use crate::cli::{CliOption, WorkMode};
-use crate::logger::LOGGER;
-use crate::proxy::{forward_request, ws_forward_request, ProxyItem};
+#[cfg(unix)]
+use crate::client_ip::UnixPeer;
+use crate::client_ip::{client_ip, TrustedProxies};
+#[cfg(unix)]
+use crate::config::{diff_options, try_load_options};
+use crate::health::{health, ready, upstreams, HealthCheck, UpstreamStatus};
+use crate::h2c::{self, H2cStream};
+use crate::headers::{response_headers_middleware, ResponseHeaders};
+use crate::listen::{
+    bind_all, https_redirect_middleware, listen_specs, HttpsRedirect, HttpsRedirects, ListenAddr, ListenSpec,
+    Listener,
+};
+use crate::log_file::LogOutputs;
+use crate::logger::{AccessLogBody, AccessRecord, LOGGER};
+use crate::metrics::{metrics, metrics_middleware, set_route_class, RouteClass};
+use crate::pattern::{split_rule, PathPattern};
+use crate::proxy::{forward_request, ws_forward_request, ProxyErrorPage, ProxyItem};
+use crate::tls::load_rustls_config;
+use crate::upstream::HealthCheckers;
+use crate::ws_proxy;
 
 use askama::Template;
 
@@ -68,6 +98,41 @@ struct AppState {
     ignore_pattern: Regex,
     custom_404_url: String,
     enable_upload: bool,
+    precompressed: bool,
+    cache_rules: Vec<CacheRule>,
+}
+
+/// --cache-control 规则
+struct CacheRule {
+    pattern: PathPattern,
+    value: header::HeaderValue,
+}
+
+impl CacheRule {
+    fn parse(rule: &str) -> Result<CacheRule, String> {
+        let (pattern, value) = split_rule(rule)?;
+        let value = header::HeaderValue::from_str(value)
+            .map_err(|_| format!("Invalid Cache-Control value in '{}'", rule))?;
+        Ok(CacheRule { pattern, value })
+    }
+}
+
+impl AppState {
+    /// Sets `Cache-Control` from the first rule matching the served file (path relative to the
+    /// root, eg: `/assets/app.js`). Any `index.html`, whether requested directly or served for
+    /// a directory or the SPA fallback, defaults to `no-cache` so new deployments are picked up immediately.
+    fn apply_cache_control(&self, response: &mut HttpResponse, served_path: &str) {
+        let entry = served_path.rsplit('/').next() == Some("index.html");
+        let value = self
+            .cache_rules
+            .iter()
+            .find(|rule| rule.pattern.is_match(served_path))
+            .map(|rule| rule.value.clone())
+            .or_else(|| entry.then(|| header::HeaderValue::from_static("no-cache")));
+        if let Some(value) = value {
+            response.headers_mut().insert(header::CACHE_CONTROL, value);
+        }
+    }
 }
 
 #[derive(Debug, MultipartForm)]
@@ -79,9 +144,11 @@ struct UploadForm {
 
 #[post("")]
 async fn upload(
+    req: HttpRequest,
     MultipartForm(form): MultipartForm<UploadForm>,
     state: web::Data<AppState>,
 ) -> Result<impl Responder, Error> {
+    set_route_class(&req, RouteClass::Upload);
     let dest_dir = state.root_path.join(&*form.path);
     for f in form.files {
         let path = dest_dir.join(f.file_name.unwrap());
@@ -116,6 +183,7 @@ async fn handler(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpRes
             if md.is_dir() {
                 // 目录索引模式
                 if mode == WorkMode::Index {
+                    set_route_class(&req, RouteClass::Listing);
                     return render_dir_index(
                         &state.base_url,
                         &state.root_path,
@@ -127,30 +195,61 @@ async fn handler(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpRes
                 // SPA 模式
                 if mode == WorkMode::SPA {
                     if let Ok(response) = auto_render_index_html(&state.root_path) {
-                        return Ok(response.into_response(&req));
+                        set_route_class(&req, RouteClass::Spa);
+                        let mut response = response.into_response(&req);
+                        state.apply_cache_control(&mut response, "/index.html");
+                        return Ok(response);
                     } else {
                         return Ok(not_found_response(state));
                     }
                 }
                 // 默认模式 403
-                return Ok(forbidden_response());
+                Ok(forbidden_response())
             } else {
-                // 返回文件本身
-                let file = NamedFile::open_async(&path).await?;
+                // 优先返回预压缩文件（.br/.zst/.gz），否则返回文件本身
+                let (has_variants, variant) = if state.precompressed {
+                    find_precompressed(&req, &path)
+                } else {
+                    (false, None)
+                };
+                let file = match variant {
+                    Some((variant_path, encoding)) => {
+                        // 类型和 disposition 以原文件为准
+                        let original = NamedFile::open_async(&path).await?;
+                        NamedFile::open_async(&variant_path)
+                            .await?
+                            .set_content_type(original.content_type().clone())
+                            .set_content_disposition(original.content_disposition().clone())
+                            .set_content_encoding(encoding)
+                    }
+                    None => NamedFile::open_async(&path).await?,
+                };
+                set_route_class(&req, RouteClass::Static);
                 let mut response = file.prefer_utf8(true);
                 if state.cache {
                     response = response.use_etag(true).use_last_modified(true);
                 } else {
                     response = response.use_etag(false).use_last_modified(false);
                 }
-                Ok(response.into_response(&req))
+                let mut response = response.into_response(&req);
+                state.apply_cache_control(&mut response, &format!("/{}", file_path.to_string_lossy()));
+                if has_variants {
+                    response.headers_mut().append(
+                        header::VARY,
+                        header::HeaderValue::from_static("accept-encoding"),
+                    );
+                }
+                Ok(response)
             }
         }
         Err(_) => {
             // 文件不存在
             if mode == WorkMode::SPA {
                 if let Ok(response) = auto_render_index_html(&state.root_path) {
-                    return Ok(response.into_response(&req));
+                    set_route_class(&req, RouteClass::Spa);
+                    let mut response = response.into_response(&req);
+                    state.apply_cache_control(&mut response, "/index.html");
+                    return Ok(response);
                 }
             }
             Ok(not_found_response(state))
@@ -160,9 +259,9 @@ async fn handler(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpRes
 
 // 403
 fn forbidden_response() -> HttpResponse {
-    return HttpResponse::Forbidden()
+    HttpResponse::Forbidden()
         .content_type("text/html; charset=utf-8")
-        .finish();
+        .finish()
 }
 
 // 404
@@ -178,9 +277,9 @@ fn not_found_response(state: web::Data<AppState>) -> HttpResponse {
             return HttpResponse::NotFound().body(buf);
         }
     }
-    return HttpResponse::NotFound()
+    HttpResponse::NotFound()
         .content_type("text/html; charset=utf-8")
-        .finish();
+        .finish()
 }
 
 fn render_dir_index(
@@ -291,8 +390,46 @@ fn format_file_size(file_size: u64) -> String {
     format!("{} {}", size, units[unit_index])
 }
 
+// 预压缩文件的后缀及对应编码
+const PRECOMPRESSED_EXTENSIONS: [(ContentEncoding, &str); 3] = [
+    (ContentEncoding::Brotli, "br"),
+    (ContentEncoding::Zstd, "zst"),
+    (ContentEncoding::Gzip, "gz"),
+];
+
+/// Looks for `file.br`, `file.zst` and `file.gz` next to `path` and picks the one preferred
+/// by the client's `Accept-Encoding`. Returns whether any variant exists (the response then
+/// varies by `Accept-Encoding`) and the chosen one, `None` meaning the file itself.
+fn find_precompressed(req: &HttpRequest, path: &Path) -> (bool, Option<(PathBuf, ContentEncoding)>) {
+    let variants: Vec<(PathBuf, ContentEncoding)> = PRECOMPRESSED_EXTENSIONS
+        .iter()
+        .filter_map(|(encoding, extension)| {
+            let mut variant_path = path.as_os_str().to_owned();
+            variant_path.push(".");
+            variant_path.push(extension);
+            let variant_path = PathBuf::from(variant_path);
+            variant_path.is_file().then_some((variant_path, *encoding))
+        })
+        .collect();
+    if variants.is_empty() {
+        return (false, None);
+    }
+    let supported: Vec<Encoding> = variants
+        .iter()
+        .map(|(_, encoding)| Encoding::Known(*encoding))
+        .chain(std::iter::once(Encoding::identity()))
+        .collect();
+    let chosen = AcceptEncoding::parse(req)
+        .ok()
+        .and_then(|accept| accept.negotiate(supported.iter()));
+    let variant = variants
+        .into_iter()
+        .find(|(_, encoding)| chosen == Some(Encoding::Known(*encoding)));
+    (true, variant)
+}
+
 // 如果路径下有index.html，则直接返回index.html
-fn auto_render_index_html(path: &PathBuf) -> Result<NamedFile, bool> {
+fn auto_render_index_html(path: &Path) -> Result<NamedFile, bool> {
     // 拼接 index.html 路径
     let index_path = path.join("index.html");
     if index_path.exists() {
@@ -336,135 +473,387 @@ async fn basic_auth(
 
 async fn custom_logger_middleware(
     req: ServiceRequest,
-    next: Next<impl MessageBody>,
+    next: Next<impl MessageBody + 'static>,
 ) -> Result<ServiceResponse<impl MessageBody>, Error> {
-    let method = req.method().to_string();
-    let path = req.path().to_string();
-    let ip = req.peer_addr().unwrap().ip().to_string();
-    let time_start = SystemTime::now();
-    let rep = next.call(req).await;
-    if let Ok(resp) = &rep {
-        let status = resp.status();
-        let time_end = SystemTime::now();
-        let time_diff = time_end.duration_since(time_start).unwrap();
-        let time_diff_ms = time_diff.as_millis() as f64;
-        LOGGER.info(format_args!("{} \"{} {}\" {} {}ms", ip, method, path, status.as_u16(), time_diff_ms).to_string());
-    }
-    return rep
+    let start = Instant::now();
+    let header = |name: header::HeaderName| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    };
+    let mut record = AccessRecord {
+        remote_ip: client_ip(req.request())
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| String::from("-")),
+        method: req.method().to_string(),
+        path: req.path().to_string(),
+        query: req.query_string().to_string(),
+        protocol: format!("{:?}", req.version()),
+        user_agent: header(header::USER_AGENT),
+        referer: header(header::REFERER),
+        user: Authorization::<Basic>::parse(&req)
+            .ok()
+            .map(|auth| auth.into_scheme().user_id().to_string()),
+        ..Default::default()
+    };
+    let res = next.call(req).await?;
+    record.status = res.status().as_u16();
+    // 认证失败时不记录用户名
+    if res.status() == StatusCode::UNAUTHORIZED {
+        record.user = None;
+    }
+    record.served_by = res
+        .request()
+        .extensions()
+        .get::<RouteClass>()
+        .copied()
+        .unwrap_or(RouteClass::Other)
+        .as_str();
+    Ok(res.map_body(|_, body| AccessLogBody::new(body.boxed(), record, start)))
 }
 
-pub async fn start_server(options: &CliOption) -> std::io::Result<()> {
-    // 初始化日志
-    // env_logger::init_from_env(Env::default().default_filter_or("info"));
-    // 获取base url，移除开头的/和结尾的/
-    let mut base = options.base.clone();
-    if base.starts_with("/") {
-        base.remove(0);
-    }
-    if base.ends_with("/") {
-        base.pop();
-    }
-    // 构建base url，没有前置/的添加
-    let mut base_url: String = base.clone();
-    if !base_url.starts_with("/") {
-        base_url = format!("/{}", base_url);
-    }
-    // 是否开启压缩
-    let compress = options.compress.clone();
-    // 是否开启cache
-    let cache = options.cache.clone();
-    let disable_powered_by = options.disable_powered_by;
-    // 是否开启上传
-    let enable_upload = options.upload.clone();
-    // 获取文件路径，规范化为绝对路径（修复 -f . 时路径无 filename 的问题）
-    // 位置参数 folder 优先于 -f/--path
-    let raw_path = options.folder.as_deref().unwrap_or(&options.path);
-    let root_path = std::fs::canonicalize(raw_path)
-        .map_err(|e| std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            format!("Path '{}' does not exist or is not accessible: {}", options.path, e),
-        ))?;
-    // let log_path = options.log.clone();
-    let security = options.security.clone();
-    let mode = options.mode;
-    // 要忽略的文件
-    let ignore_pattern = Regex::new(&options.ignore_files).unwrap();
-    let custom_404_url: String = if let Some(url) = &options.custom_404 {
-        let mut _url = url.to_string();
-        if _url.starts_with("/") {
-            _url = _url.strip_prefix("/").unwrap().to_string();
+/// 一个站点的配置，默认站点和每个虚拟主机各一个
+#[derive(Clone)]
+struct Site {
+    /// 匹配的 Host，为空时匹配所有请求（默认站点）
+    server_names: Vec<String>,
+    state: web::Data<AppState>,
+    headers: web::Data<ResponseHeaders>,
+    proxies: Vec<ProxyItem>,
+    ws_proxies: Vec<ProxyItem>,
+    proxy_error_page: web::Data<ProxyErrorPage>,
+    // 是否反代所有路由
+    all_proxyed: bool,
+}
+
+impl Site {
+    fn from_options(options: &CliOption) -> std::io::Result<Site> {
+        // 获取base url，移除开头的/和结尾的/
+        let mut base = options.base.clone();
+        if base.starts_with("/") {
+            base.remove(0);
         }
-        if _url.ends_with("/") {
-            _url = _url.strip_suffix("/").unwrap().to_string();
+        if base.ends_with("/") {
+            base.pop();
         }
-        _url
-    } else {
-        String::from("")
-    };
+        // 获取文件路径，规范化为绝对路径（修复 -f . 时路径无 filename 的问题）
+        // 位置参数 folder 优先于 -f/--path
+        let raw_path = options.folder.as_deref().unwrap_or(&options.path);
+        let root_path = std::fs::canonicalize(raw_path)
+            .map_err(|e| std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Path '{}' does not exist or is not accessible: {}", raw_path, e),
+            ))?;
+        let mut username = String::new();
+        let mut password = String::new();
+        if let Some(security) = &options.security {
+            let parts: Vec<&str> = security.split(':').collect();
+            if parts.len() != 2 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Error when parse basic auth, expected username:password",
+                ));
+            }
+            username = parts[0].to_string();
+            password = parts[1].to_string();
+        }
+        // 要忽略的文件
+        let ignore_pattern = Regex::new(&options.ignore_files).unwrap();
+        let custom_404_url: String = if let Some(url) = &options.custom_404 {
+            let mut _url = url.to_string();
+            if _url.starts_with("/") {
+                _url = _url.strip_prefix("/").unwrap().to_string();
+            }
+            if _url.ends_with("/") {
+                _url = _url.strip_suffix("/").unwrap().to_string();
+            }
+            _url
+        } else {
+            String::from("")
+        };
 
-    // 代理地址支持环境变量
-    // eg: http://${APP_URL} -> http://localhost:8080 with APP_URL=localhost:8080
-    let proxy_regex = Regex::new(r"\$\{(.*?)\}").unwrap();
+        let cache_rules = options
+            .cache_control
+            .iter()
+            .map(|rule| CacheRule::parse(rule))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+        let headers = ResponseHeaders::new(
+            &options.headers,
+            options.security_headers,
+            options.content_security_policy.as_deref(),
+        )
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
 
-    // 是否反代所有路由
-    let mut all_proxyed = false;
+        // 是否反代所有路由
+        let mut all_proxyed = false;
 
-    // 反向代理
-    let proxies: Vec<ProxyItem> = options
-        .proxies
-        .iter()
-        .map(|item| {
-            let s: Vec<&str> = item.split("->").collect();
-            let target_url = proxy_regex.replace_all(s[1], |caps: &Captures | {
-                let var_name = &caps[1];
-                env::var(var_name).unwrap_or_else(|_| caps[0].to_string())
-            }).into_owned();
-            let _proxy = ProxyItem::new(s[0].to_string(), target_url);
-            if all_proxyed == false && _proxy.origin_path == "/" {
-                all_proxyed = true;
+        // 反向代理
+        let proxies: Vec<ProxyItem> = options
+            .proxies
+            .iter()
+            .map(|item| {
+                // 代理地址支持环境变量
+                let _proxy = ProxyItem::parse(item)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                if !all_proxyed && _proxy.origin_path == "/" && !_proxy.route.has_conditions() {
+                    all_proxyed = true;
+                }
+                info!("proxy: {} -> {}", _proxy.origin_path, _proxy.targets());
+                Ok(_proxy)
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        // websocket 代理
+        let ws_proxies: Vec<ProxyItem> = options
+            .websocket_proxies
+            .iter()
+            .map(|item| {
+                let _proxy = ProxyItem::parse(item)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                if _proxy.mock.is_some() {
+                    let message = format!("mock is not supported by websocket proxy '{}'", item);
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+                }
+                if !all_proxyed && _proxy.origin_path == "/" && !_proxy.route.has_conditions() {
+                    all_proxyed = true;
+                }
+                info!("websocket proxy: {} -> {}", _proxy.origin_path, _proxy.targets());
+                Ok(_proxy)
+            })
+            .collect::<std::io::Result<_>>()?;
+        // 代理失败时的错误页，相对于站点目录，启动时读取以便尽早报错
+        let proxy_error_page = match &options.proxy_error_page {
+            Some(page) => {
+                let path = root_path.join(page.trim_start_matches('/'));
+                let page = std::fs::read(&path).map_err(|e| {
+                    std::io::Error::new(e.kind(), format!("Invalid proxy error page '{}': {}", path.display(), e))
+                })?;
+                Some(page.into())
             }
-            info!("proxy: {} -> {}", _proxy.origin_path, _proxy.target_url);
-            return _proxy;
+            None => None,
+        };
+
+        Ok(Site {
+            server_names: options.server_name.clone(),
+            state: web::Data::new(AppState {
+                base_url: base,
+                username,
+                password,
+                root_path,
+                mode: options.mode,
+                cache: options.cache,
+                ignore_pattern,
+                custom_404_url,
+                enable_upload: options.upload,
+                precompressed: options.precompressed,
+                cache_rules,
+            }),
+            headers: web::Data::new(headers),
+            proxies,
+            ws_proxies,
+            proxy_error_page: web::Data::new(ProxyErrorPage(proxy_error_page)),
+            all_proxyed,
         })
+    }
+}
+
+/// 把一个站点挂载为 scope，虚拟主机按 Host 匹配
+fn site_scope(
+    site: &Site,
+) -> Scope<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    let mut scope = web::scope("")
+        .app_data(site.state.clone())
+        .app_data(site.headers.clone())
+        .app_data(site.proxy_error_page.clone());
+    if let Some((first, rest)) = site.server_names.split_first() {
+        let host_guard = rest
+            .iter()
+            .fold(guard::Any(guard::Host(first.clone())), |g, name| {
+                g.or(guard::Host(name.clone()))
+            });
+        scope = scope.guard(host_guard);
+    }
+    // 上传
+    if site.state.enable_upload {
+        let base = &site.state.base_url;
+        let mut upload_scope = String::from("/_upload");
+        if base != "/" {
+            upload_scope = format!("{}/_upload", base);
+        }
+        scope = scope.service(web::scope(&upload_scope).service(upload))
+    }
+    // -P 和 -W 的规则一起按 priority 从高到低匹配，相同时 -P 在前，各自保持配置顺序
+    let mut routes: Vec<(&ProxyItem, bool)> = site
+        .proxies
+        .iter()
+        .map(|proxy| (proxy, false))
+        .chain(site.ws_proxies.iter().map(|proxy| (proxy, true)))
         .collect();
+    routes.sort_by_key(|(proxy, _)| Reverse(proxy.route.priority));
+    for (proxy, websocket) in routes {
+        if websocket {
+            // websocket 代理
+            scope = scope.service(
+                proxy_scope(proxy)
+                    .app_data(web::Data::new(proxy.clone()))
+                    .default_service(web::to(ws_forward_request)),
+            );
+            continue;
+        }
+        // 反向代理
+        if proxy.origin_path == "/" && !proxy.route.has_conditions() {
+            scope = scope.app_data(web::Data::new(proxy.clone()))
+            .app_data(web::Data::new(proxy.client()))
+            .default_service(web::to(forward_request))
+        }
+        scope = scope.service(
+            proxy_scope(proxy)
+                .app_data(web::Data::new(proxy.clone()))
+                .app_data(web::Data::new(proxy.client()))
+                .default_service(web::to(forward_request)),
+        )
+    }
+    // 所有路由都被代理后就不需要文件路由了
+    if !site.all_proxyed {
+        scope = scope.service(handler);
+    }
+    scope
+        .wrap(Condition::new(
+            !site.state.username.is_empty(),
+            HttpAuthentication::basic(basic_auth),
+        ))
+        // 自定义响应头对文件、目录列表、代理和认证失败的响应都生效
+        .wrap(Condition::new(
+            !site.headers.is_empty(),
+            from_fn(response_headers_middleware),
+        ))
+}
 
-    // websocket 代理
-    let ws_proxies: Vec<ProxyItem> = options
-    .websocket_proxies
-    .iter()
-    .map(|item| {
-        let s: Vec<&str> = item.split("->").collect();
-        let target_url = proxy_regex.replace_all(s[1], |caps: &Captures | {
-            let var_name = &caps[1];
-            env::var(var_name).unwrap_or_else(|_| caps[0].to_string())
-        }).into_owned();
-        let _proxy = ProxyItem::new(s[0].to_string(), target_url);
-        if all_proxyed == false && _proxy.origin_path == "/" {
-            all_proxyed = true;
+/// 代理规则的 scope，正则、请求方法和请求头条件通过 guard 判断，不匹配时继续尝试后面的规则
+fn proxy_scope(proxy: &ProxyItem) -> Scope {
+    let scope = web::scope(proxy.scope_path());
+    if !proxy.route.has_conditions() {
+        return scope;
+    }
+    let route = proxy.route.clone();
+    scope.guard(guard::fn_guard(move |ctx| route.matches(ctx.head())))
+}
+
+/// 默认站点放在最后，匹配所有其他 Host
+fn build_sites(options: &CliOption) -> std::io::Result<Vec<Site>> {
+    let mut default_site = Site::from_options(options)?;
+    default_site.server_names.clear();
+    // 虚拟主机，按顺序匹配 Host，最后回退到默认站点
+    let mut sites = vec![];
+    for (i, vhost) in options.vhosts.iter().enumerate() {
+        if vhost.server_name.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("vhost #{} requires --server-name", i + 1),
+            ));
         }
-        info!("websocket proxy: {} -> {}", _proxy.origin_path, _proxy.target_url);
-        return _proxy;
-    })
-    .collect();
-    let server = HttpServer::new(move || {
-        let mut _user_id = String::new();
-        let mut _password = String::new();
-        if let Some(security) = &security {
-            let parts: Vec<&str> = security.split(':').collect();
-            if parts.len() != 2 {
-                panic!("Error when parse basic auth")
-            }
-            _user_id = parts[0].to_string();
-            _password = parts[1].to_string();
+        if !vhost.vhosts.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("vhost #{} can not contain another --vhost", i + 1),
+            ));
         }
+        info!("vhost: {}", vhost.server_name.join(","));
+        sites.push(Site::from_options(vhost)?);
+    }
+    sites.push(default_site);
+    Ok(sites)
+}
+
+/// Collects what the readiness endpoint checks from every site.
+fn build_health_check(options: &CliOption, sites: &[Site]) -> HealthCheck {
+    HealthCheck {
+        checks: options.ready_check.clone(),
+        root_paths: sites.iter().map(|site| site.state.root_path.clone()).collect(),
+        upstreams: sites
+            .iter()
+            .flat_map(|site| site.proxies.iter().chain(&site.ws_proxies))
+            .flat_map(|proxy| &proxy.pool.upstreams)
+            .filter(|upstream| !upstream.is_template())
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Lists the upstreams of every site for the status endpoint.
+fn build_upstream_status(sites: &[Site]) -> UpstreamStatus {
+    let proxies = sites.iter().flat_map(|site| {
+        let http = site.proxies.iter().map(|proxy| (proxy, false));
+        let ws = site.ws_proxies.iter().map(|proxy| (proxy, true));
+        http.chain(ws)
+    });
+    UpstreamStatus {
+        proxies: proxies
+            .map(|(proxy, websocket)| (proxy.origin_path.clone(), websocket, proxy.pool.clone()))
+            .collect(),
+    }
+}
+
+// 热加载后旧的一代继续处理未完成的请求和 websocket 的最长时间
+const RELOAD_DRAIN_TIMEOUT: u64 = 3600;
+const KEEP_ALIVE: Duration = Duration::from_secs(75);
+const CLIENT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds one generation of the server from `options` on an already bound listener.
+/// On reload a new generation is started on the same listeners before the old one is stopped,
+/// so no connection is refused and in-flight requests finish on the old workers.
+/// The upstream health checkers of the generation run until they are dropped.
+fn build_server(
+    options: &CliOption,
+    listeners: Vec<(ListenSpec, Listener)>,
+) -> std::io::Result<(Servers, HealthCheckers)> {
+    let sites = build_sites(options)?;
+    let health_check = web::Data::new(build_health_check(options, &sites));
+    let upstream_status = web::Data::new(build_upstream_status(&sites));
+    let mut checkers = HealthCheckers::default();
+    for site in &sites {
+        for proxy in site.proxies.iter().chain(&site.ws_proxies) {
+            checkers.spawn(&proxy.pool);
+        }
+    }
+    let trusted_proxies = web::Data::new(
+        TrustedProxies::parse(&options.trusted_proxies)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    let health_path = options.health_path.clone();
+    let ready_path = options.ready_path.clone();
+    let metrics_path = options.metrics_path.clone();
+    let upstreams_path = options.upstreams_path.clone();
+    // 是否开启压缩
+    let compress = options.compress;
+    let disable_powered_by = options.disable_powered_by;
+    // let log_path = options.log.clone();
+    // HTTPS 证书，启动前加载以便尽早报错
+    let mut tls_configs = vec![];
+    for (spec, _) in &listeners {
+        tls_configs.push(match spec.certificate(options) {
+            Some((cert, key)) => Some(load_rustls_config(cert, key)?),
+            None => None,
+        });
+    }
+    let redirects = https_redirects(&listeners)?;
+
+    let app = move || {
         let mut app = App::new()
+            .app_data(trusted_proxies.clone())
             // .wrap(middleware::Logger::default())
-            .wrap(from_fn(custom_logger_middleware))
             .wrap(Condition::new(compress, middleware::Compress::default()))
-            .wrap(Condition::new(
-                !_user_id.is_empty(),
-                HttpAuthentication::basic(basic_auth),
-            ))
             // TODO 改成Condition::new，但是类型太复杂
             .wrap_fn(move |req: ServiceRequest, srv| {
                 let fut = srv.call(req);
@@ -484,85 +873,397 @@ pub async fn start_server(options: &CliOption) -> std::io::Result<()> {
                     Ok(res)
                 }
             })
-            .app_data(web::Data::new(AppState {
-                base_url: base.clone(),
-                username: _user_id.to_string(),
-                password: _password.to_string(),
-                root_path: root_path.clone(),
-                mode,
-                cache,
-                ignore_pattern: ignore_pattern.clone(),
-                custom_404_url: custom_404_url.clone(),
-                enable_upload,
-            }));
-        // 上传
-        if enable_upload {
-            let mut scope = String::from("/_upload");
-            if &base != "/" {
-                scope = format!("{}/_upload", base);
+            .wrap(from_fn(https_redirect_middleware))
+            // 访问日志和统计在最外层，字节数为压缩后的大小
+            .wrap(from_fn(custom_logger_middleware))
+            .wrap(from_fn(metrics_middleware));
+        // 健康检查和监控注册在站点之前，不经过 basic auth
+        if !health_path.is_empty() {
+            app = app.route(&health_path, web::get().to(health));
+        }
+        if !ready_path.is_empty() {
+            app = app
+                .app_data(health_check.clone())
+                .route(&ready_path, web::get().to(ready));
+        }
+        if !metrics_path.is_empty() {
+            app = app.route(&metrics_path, web::get().to(metrics));
+        }
+        if !upstreams_path.is_empty() {
+            app = app
+                .app_data(upstream_status.clone())
+                .route(&upstreams_path, web::get().to(upstreams));
+        }
+        for site in &sites {
+            app = app.service(site_scope(site));
+        }
+        app
+    };
+
+    // 明文的 h2c 监听自己判断协议，以便响应 Upgrade: h2c
+    let mut servers = vec![];
+    let mut others = vec![];
+    for ((spec, listener), tls_config) in listeners.into_iter().zip(tls_configs) {
+        match (listener, tls_config) {
+            (Listener::Tcp(listener), None) if spec.h2c => {
+                servers.push(build_h2c_server(listener, app.clone(), redirects.clone())?)
             }
-            app = app.service(web::scope(&scope).service(upload))
+            (listener, tls_config) => others.push((listener, tls_config)),
         }
-        // 反向代理
-        for proxy in &proxies {
-            let _origin_path = &proxy.origin_path;
-            if _origin_path == "/" {
-                app = app.app_data(web::Data::new(proxy.clone()))
-                .app_data(web::Data::new(Client::new()))
-                .default_service(web::to(forward_request))
+    }
+    if others.is_empty() {
+        return Ok((Servers(servers), checkers));
+    }
+
+    let mut server = HttpServer::new(app)
+        // unix socket 的对端没有地址，标记后按 127.0.0.1 处理
+        .on_connect(move |io, extensions| {
+            #[cfg(unix)]
+            if io.downcast_ref::<rt::net::UnixStream>().is_some() {
+                extensions.insert(UnixPeer);
             }
-            app = app.service(
-                web::scope(_origin_path)
-                    .app_data(web::Data::new(proxy.clone()))
-                    .app_data(web::Data::new(Client::new()))
-                    .default_service(web::to(forward_request)),
+            let local = io.downcast_ref::<rt::net::TcpStream>().and_then(|tcp| tcp.local_addr().ok());
+            if let Some(port) = local.and_then(|local| redirects.port_for(local)) {
+                extensions.insert(HttpsRedirect(port));
+            }
+        })
+        .keep_alive(KEEP_ALIVE) // 保持连接
+        .client_request_timeout(CLIENT_REQUEST_TIMEOUT)
+        .shutdown_timeout(RELOAD_DRAIN_TIMEOUT)
+        // 信号由 start_server 统一处理
+        .disable_signals();
+    for (listener, tls_config) in others {
+        server = match (listener, tls_config) {
+            (Listener::Tcp(listener), Some(config)) => server.listen_rustls(listener, config)?,
+            (Listener::Tcp(listener), None) => server.listen(listener)?,
+            // ListenSpec 已拒绝 unix socket 上的 TLS
+            #[cfg(unix)]
+            (Listener::Unix(listener, _), _) => server.listen_uds(listener)?,
+        };
+    }
+    servers.push(server.run());
+    Ok((Servers(servers), checkers))
+}
+
+/// Serves a plain `h2c` listener, HTTP/2 is chosen by prior knowledge or `Upgrade: h2c`.
+/// `HttpServer` offers no way to look at the request before the protocol is chosen,
+/// so the listener gets its own server with the same app and timeouts.
+fn build_h2c_server<F, I, T, B>(
+    listener: std::net::TcpListener,
+    app: F,
+    redirects: HttpsRedirects,
+) -> std::io::Result<Server>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<T, Request>,
+    T: ServiceFactory<Request, Config = AppConfig, Response = ServiceResponse<B>, Error = Error, InitError = ()>
+        + 'static,
+    B: MessageBody + 'static,
+{
+    let addr = listener.local_addr()?;
+    let server = actix_server::Server::build()
+        .shutdown_timeout(RELOAD_DRAIN_TIMEOUT)
+        .disable_signals()
+        .listen(format!("hs-h2c-{}", addr), listener, move || {
+            let redirects = redirects.clone();
+            let app = app().into_factory().map_err(|e: Error| e.error_response());
+            fn_service(|io: rt::net::TcpStream| h2c::accept(io, CLIENT_REQUEST_TIMEOUT)).and_then(
+                HttpService::build()
+                    .keep_alive(KEEP_ALIVE)
+                    .client_request_timeout(CLIENT_REQUEST_TIMEOUT)
+                    .local_addr(addr)
+                    .on_connect_ext(move |io: &H2cStream, extensions: &mut Extensions| {
+                        let local = io.local_addr().ok();
+                        if let Some(port) = local.and_then(|local| redirects.port_for(local)) {
+                            extensions.insert(HttpsRedirect(port));
+                        }
+                    })
+                    .finish(map_config(app, |_| AppConfig::default())),
             )
+        })?
+        .run();
+    Ok(server)
+}
+
+/// Every server of a generation, plain `h2c` listeners are served apart from the others.
+struct Servers(Vec<Server>);
+
+impl Servers {
+    fn handle(&self) -> ServersHandle {
+        ServersHandle(self.0.iter().map(Server::handle).collect())
+    }
+
+    async fn run(self) -> std::io::Result<()> {
+        try_join_all(self.0).await.map(|_| ())
+    }
+}
+
+#[derive(Clone)]
+struct ServersHandle(Vec<ServerHandle>);
+
+impl ServersHandle {
+    fn stop(&self, graceful: bool) -> impl Future<Output = ()> {
+        let stopping = join_all(self.0.iter().map(|handle| handle.stop(graceful)));
+        async move {
+            stopping.await;
         }
-        // websocket 代理
-        for proxy in &ws_proxies {
-            let _proxy = proxy.clone();
-            app = app.service(
-                web::scope(&_proxy.origin_path)
-                    .app_data(web::Data::new(_proxy))
-                    .default_service(web::to(ws_forward_request)),
-            )
+    }
+}
+
+/// Plain listeners with `redirect-https`, sent to the port of the first HTTPS listener.
+fn https_redirects(listeners: &[(ListenSpec, Listener)]) -> std::io::Result<HttpsRedirects> {
+    let https_port = listeners.iter().find_map(|(spec, listener)| match listener {
+        Listener::Tcp(listener) if spec.tls => listener.local_addr().ok().map(|addr| addr.port()),
+        _ => None,
+    });
+    let mut redirects = HttpsRedirects::default();
+    for (spec, listener) in listeners {
+        if let (true, Listener::Tcp(listener), Some(port)) = (spec.redirect_https, listener, https_port) {
+            redirects.0.push((listener.local_addr()?, port));
+        }
+    }
+    Ok(redirects)
+}
+
+// 每一代服务使用同一组 socket 的副本
+fn clone_listeners(
+    specs: &[ListenSpec],
+    listeners: &[Listener],
+) -> std::io::Result<Vec<(ListenSpec, Listener)>> {
+    specs
+        .iter()
+        .zip(listeners)
+        .map(|(spec, listener)| Ok((spec.clone(), listener.try_clone()?)))
+        .collect()
+}
+
+/// Re-reads the command line, config file and environment and builds a new generation.
+#[cfg(unix)]
+fn reload_server(
+    current: &[ListenSpec],
+    listeners: &[Listener],
+) -> Result<(CliOption, Vec<ListenSpec>, Servers, HealthCheckers), String> {
+    let options = try_load_options()?;
+    let mut specs = listen_specs(&options)?;
+    // 地址不变时 tls、h2c、redirect-https 等设置可以直接生效
+    let sockets = |specs: &[ListenSpec]| -> Vec<(ListenAddr, bool)> {
+        specs.iter().map(|spec| (spec.addr.clone(), spec.ipv6_only)).collect()
+    };
+    if sockets(&specs) != sockets(current) {
+        let addrs = |specs: &[ListenSpec]| -> String {
+            specs.iter().map(|spec| spec.addr.to_string()).collect::<Vec<_>>().join(", ")
+        };
+        LOGGER.warn(format!(
+            "reload: listen address change to {} requires a restart, still listening on {}",
+            addrs(&specs),
+            addrs(current)
+        ));
+        specs = current.to_vec();
+    }
+    let log_outputs = LogOutputs::open(&options).map_err(|e| e.to_string())?;
+    let listeners = clone_listeners(&specs, listeners).map_err(|e| e.to_string())?;
+    let (server, checkers) = build_server(&options, listeners).map_err(|e| e.to_string())?;
+    LOGGER.set_outputs(log_outputs);
+    Ok((options, specs, server, checkers))
+}
+
+/// Binds every listener and serves until SIGTERM/SIGINT, unix socket files are removed on exit.
+/// A listener that can not be bound is an error, nothing is served then.
+pub async fn start_server(options: &CliOption) -> std::io::Result<()> {
+    // 初始化日志
+    // env_logger::init_from_env(Env::default().default_filter_or("info"));
+    let specs = listen_specs(options)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let listeners = bind_all(&specs)?;
+    let result = serve(options, specs, &listeners).await;
+    listeners.iter().for_each(Listener::remove_socket);
+    result
+}
+
+/// What a process signal asks the server to do.
+enum Control {
+    Shutdown,
+    #[cfg(unix)]
+    Reload,
+    #[cfg(unix)]
+    ReopenLogs,
+}
+
+/// SIGTERM/SIGINT shut down, SIGHUP reloads and SIGUSR1 reopens log files.
+/// Other platforms only handle Ctrl-C and offer no reload.
+#[cfg(unix)]
+struct ControlSignals {
+    hangup: Signal,
+    terminate: Signal,
+    interrupt: Signal,
+    user_defined1: Signal,
+}
+
+#[cfg(unix)]
+impl ControlSignals {
+    fn new() -> std::io::Result<Self> {
+        Ok(ControlSignals {
+            hangup: signal(SignalKind::hangup())?,
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+            user_defined1: signal(SignalKind::user_defined1())?,
+        })
+    }
+
+    async fn recv(&mut self) -> Control {
+        tokio::select! {
+            _ = self.terminate.recv() => Control::Shutdown,
+            _ = self.interrupt.recv() => Control::Shutdown,
+            _ = self.hangup.recv() => Control::Reload,
+            _ = self.user_defined1.recv() => Control::ReopenLogs,
         }
-        // 所有路由都被代理后就不需要文件路由了
-        if !all_proxyed {
-            app = app.service(handler);
+    }
+}
+
+#[cfg(not(unix))]
+struct ControlSignals;
+
+#[cfg(not(unix))]
+impl ControlSignals {
+    fn new() -> std::io::Result<Self> {
+        Ok(ControlSignals)
+    }
+
+    async fn recv(&mut self) -> Control {
+        // 无法监听 Ctrl-C 时只能等服务自行结束
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
         }
-        return app;
-    })
-    .backlog(1024)
-    .keep_alive(Duration::from_secs(75)) // 保持连接
-    .client_request_timeout(Duration::from_secs(10));
+        Control::Shutdown
+    }
+}
 
-    let host = options.host.as_str();
+/// The serving generation plus the older ones still draining after a reload.
+struct Generations {
+    options: CliOption,
+    // 只在热加载时用到
+    #[cfg_attr(not(unix), allow(dead_code))]
+    specs: Vec<ListenSpec>,
+    handle: ServersHandle,
+    running: JoinHandle<std::io::Result<()>>,
+    // 旧的一代和等待它停止的任务，停止后在下次热加载时移除
+    draining: Vec<(ServersHandle, JoinHandle<()>)>,
+    checkers: HealthCheckers,
+}
 
-    if let Ok(server) = server.bind((host, options.port)) {
-        print_all_host(host, options.port, options.open, &base_url);
-        server.run().await
-    } else {
-        panic!("port {} is in use.", options.port);
+impl Generations {
+    /// Starts a new generation from the reloaded configuration, then gracefully stops the current one.
+    /// The current generation keeps serving when the new configuration is rejected.
+    #[cfg(unix)]
+    fn reload(&mut self, listeners: &[Listener]) {
+        match reload_server(&self.specs, listeners) {
+            Ok((new_options, new_specs, new_server, new_checkers)) => {
+                let changes = diff_options(&self.options, &new_options);
+                if changes.is_empty() {
+                    LOGGER.info(String::from("reload: configuration unchanged"));
+                } else {
+                    LOGGER.info(format!("reload: {}", changes.join(", ")));
+                }
+                let old_handle = std::mem::replace(&mut self.handle, new_server.handle());
+                self.running = rt::spawn(new_server.run());
+                self.draining.retain(|(_, stopping)| !stopping.is_finished());
+                let stopping = rt::spawn(old_handle.stop(true));
+                self.draining.push((old_handle, stopping));
+                // 旧的一代不再需要健康检查
+                self.checkers = new_checkers;
+                LOGGER.set_format(new_options.log_format);
+                self.options = new_options;
+                self.specs = new_specs;
+            }
+            Err(e) => LOGGER.error(format!("reload rejected, keeping current configuration: {}", e)),
+        }
     }
 }
 
-fn print_all_host(host: &str, port: u16, open: bool, base: &str) {
-    if host.eq("0.0.0.0") {
+async fn serve(options: &CliOption, specs: Vec<ListenSpec>, listeners: &[Listener]) -> std::io::Result<()> {
+    LOGGER.set_format(options.log_format);
+    LOGGER.set_outputs(LogOutputs::open(options)?);
+    let (server, checkers) = build_server(options, clone_listeners(&specs, listeners)?)?;
+    // 构建base url，移除开头和结尾的/后添加前置/
+    let base_url = format!("/{}", options.base.trim_matches('/'));
+    for (index, spec) in specs.iter().enumerate() {
+        let scheme = if spec.tls { "https" } else { "http" };
+        // 只打开第一个地址
+        let open = options.open && index == 0;
+        match &spec.addr {
+            ListenAddr::Tcp(host, port) => print_all_host(scheme, host, *port, open, &base_url),
+            #[cfg(unix)]
+            ListenAddr::Unix(_) => println!("  {} ({}://localhost{})", spec.addr, scheme, base_url),
+        }
+    }
+
+    let mut generations = Generations {
+        options: options.clone(),
+        specs,
+        handle: server.handle(),
+        running: rt::spawn(server.run()),
+        draining: vec![],
+        checkers,
+    };
+    let mut signals = ControlSignals::new()?;
+    // 其他平台只有退出信号
+    #[cfg_attr(not(unix), allow(clippy::never_loop))]
+    loop {
+        tokio::select! {
+            result = &mut generations.running => {
+                return result.unwrap_or_else(|e| Err(std::io::Error::other(e)));
+            }
+            control = signals.recv() => match control {
+                Control::Shutdown => break,
+                #[cfg(unix)]
+                Control::Reload => generations.reload(listeners),
+                #[cfg(unix)]
+                Control::ReopenLogs => LOGGER.reopen(),
+            }
+        }
+    }
+    let Generations { options: current_options, handle, draining, checkers, .. } = generations;
+
+    // 平滑退出：停止接收新连接，等待进行中的请求完成，超时后直接退出
+    let shutdown_timeout = current_options.shutdown_timeout;
+    LOGGER.info(format!(
+        "shutting down, waiting up to {}s for in-flight requests",
+        shutdown_timeout
+    ));
+    drop(checkers);
+    ws_proxy::close_all();
+    let stopping = join_all(
+        std::iter::once(handle)
+            .chain(draining.into_iter().map(|(handle, _)| handle))
+            .map(|handle| handle.stop(true)),
+    );
+    if timeout(Duration::from_secs(shutdown_timeout), stopping).await.is_err() {
+        LOGGER.warn(String::from(
+            "shutdown timeout reached, closing remaining connections",
+        ));
+    }
+    LOGGER.flush();
+    Ok(())
+}
+
+fn print_all_host(scheme: &str, host: &str, port: u16, open: bool, base: &str) {
+    // [::] 默认也接受 IPv4 连接
+    if host.eq("0.0.0.0") || host.eq("::") {
         let ifas = list_afinet_netifas().unwrap();
 
         for (_, ip) in ifas.iter() {
             if matches!(ip, IpAddr::V4(_)) {
-                println!("  http://{:?}:{}{}", ip, port, base);
+                println!("  {}://{:?}:{}{}", scheme, ip, port, base);
             }
         }
         if open && !ifas.is_empty() {
-            let _ = that(format!("http://{:?}:{}{}", ifas[0].1, port, base));
+            let _ = that(format!("{}://{:?}:{}{}", scheme, ifas[0].1, port, base));
         }
     } else {
-        println!("  http://{}:{}{}", host, port, base);
+        let host = if host.contains(':') { format!("[{}]", host) } else { host.to_string() };
+        println!("  {}://{}:{}{}", scheme, host, port, base);
         if open {
-            let _ = that(format!("http://{}:{}{}", host, port, base));
+            let _ = that(format!("{}://{}:{}{}", scheme, host, port, base));
         }
     }
 }
@@ -570,6 +1271,7 @@ fn print_all_host(host: &str, port: u16, open: bool, base: &str) {
 mod tests {
     use super::*;
     use actix_web::{http::header, test, App};
+    use clap::Parser;
     use actix_web_httpauth::middleware::HttpAuthentication;
     use std::str;
     use tempfile::TempDir;
@@ -608,9 +1310,16 @@ mod tests {
             ignore_pattern: Regex::new(r"^\.").unwrap(),
             custom_404_url: String::new(),
             enable_upload: false,
+            precompressed: true,
+            cache_rules: vec![],
         }
     }
 
+    fn make_site(args: &[&str]) -> Site {
+        let options = CliOption::parse_from(std::iter::once("hs").chain(args.iter().copied()));
+        Site::from_options(&options).unwrap()
+    }
+
     // ── index 模式 ────────────────────────────────────────────────────────────
 
     #[actix_web::test]
@@ -839,6 +1548,176 @@ mod tests {
         assert!(!resp.headers().contains_key(header::ETAG));
     }
 
+    // ── Cache-Control 规则 ────────────────────────────────────────────────────
+
+    fn make_cache_state(root: &TempDir, mode: WorkMode) -> AppState {
+        std::fs::create_dir(root.path().join("assets")).unwrap();
+        std::fs::write(root.path().join("assets").join("app.1234.js"), "app").unwrap();
+        AppState {
+            cache_rules: vec![
+                CacheRule::parse("/assets/**=public, max-age=31536000, immutable").unwrap(),
+                CacheRule::parse("*.txt=no-store").unwrap(),
+            ],
+            ..make_state(root, mode)
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_cache_control_rule_for_assets() {
+        let tmp = setup_dir();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(make_cache_state(&tmp, WorkMode::SPA)))
+                .service(handler),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/assets/app.1234.js").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=31536000, immutable"
+        );
+        let req = test::TestRequest::get().uri("/subdir/sub.txt").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "no-store");
+    }
+
+    #[actix_web::test]
+    async fn test_spa_fallback_defaults_to_no_cache() {
+        let tmp = setup_dir();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(make_cache_state(&tmp, WorkMode::SPA)))
+                .service(handler),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/some/route").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "no-cache");
+    }
+
+    #[actix_web::test]
+    async fn test_index_html_defaults_to_no_cache() {
+        let tmp = setup_dir();
+        std::fs::write(tmp.path().join("index.html"), "<html>Index</html>").unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(make_state(&tmp, WorkMode::Server)))
+                .service(handler),
+        )
+        .await;
+        // 直接请求 index.html 也不缓存，其他文件没有默认值
+        let req = test::TestRequest::get().uri("/index.html").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "no-cache");
+        let req = test::TestRequest::get().uri("/file.txt").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(!resp.headers().contains_key(header::CACHE_CONTROL));
+    }
+
+    #[actix_web::test]
+    async fn test_no_cache_control_without_matching_rule() {
+        let tmp = setup_dir();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(make_state(&tmp, WorkMode::Index)))
+                .service(handler),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/file.txt").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(!resp.headers().contains_key(header::CACHE_CONTROL));
+    }
+
+    // ── 预压缩文件 ────────────────────────────────────────────────────────────
+
+    fn setup_precompressed_dir() -> TempDir {
+        let tmp = setup_dir();
+        std::fs::write(tmp.path().join("app.js"), "console.log(1)").unwrap();
+        std::fs::write(tmp.path().join("app.js.gz"), "gzip-bytes").unwrap();
+        std::fs::write(tmp.path().join("app.js.br"), "brotli-bytes").unwrap();
+        tmp
+    }
+
+    #[actix_web::test]
+    async fn test_precompressed_prefers_brotli() {
+        let tmp = setup_precompressed_dir();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(make_state(&tmp, WorkMode::Index)))
+                .service(handler),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/app.js")
+            .insert_header((header::ACCEPT_ENCODING, "gzip, deflate, br"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+        let ct = resp.headers().get(header::CONTENT_TYPE).unwrap().to_str().unwrap();
+        assert!(ct.contains("javascript"));
+        assert_eq!(test::read_body(resp).await, "brotli-bytes");
+    }
+
+    #[actix_web::test]
+    async fn test_precompressed_honors_quality() {
+        let tmp = setup_precompressed_dir();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(make_state(&tmp, WorkMode::Index)))
+                .service(handler),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/app.js")
+            .insert_header((header::ACCEPT_ENCODING, "br;q=0.5, gzip"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(test::read_body(resp).await, "gzip-bytes");
+    }
+
+    #[actix_web::test]
+    async fn test_precompressed_falls_back_to_original() {
+        let tmp = setup_precompressed_dir();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(make_state(&tmp, WorkMode::Index)))
+                .service(handler),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/app.js").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+        assert_eq!(test::read_body(resp).await, "console.log(1)");
+    }
+
+    #[actix_web::test]
+    async fn test_precompressed_disabled() {
+        let tmp = setup_precompressed_dir();
+        let state = AppState {
+            precompressed: false,
+            ..make_state(&tmp, WorkMode::Index)
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(handler),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/app.js")
+            .insert_header((header::ACCEPT_ENCODING, "br"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(test::read_body(resp).await, "console.log(1)");
+    }
+
     // ── 文件忽略规则 ──────────────────────────────────────────────────────────
 
     #[actix_web::test]
@@ -1066,6 +1945,379 @@ mod tests {
         assert_eq!(body, "Hello World");
     }
 
+    // ── 虚拟主机 ──────────────────────────────────────────────────────────────
+
+    #[actix_web::test]
+    async fn test_vhost_selected_by_host_header() {
+        let app_dir = setup_dir();
+        let docs_dir = TempDir::new().unwrap();
+        std::fs::write(docs_dir.path().join("guide.txt"), "Docs guide").unwrap();
+        let app_path = app_dir.path().to_str().unwrap();
+        let docs_path = docs_dir.path().to_str().unwrap();
+        let docs = make_site(&["--server-name", "docs.example.com", "-m", "index", "-f", docs_path]);
+        let default = make_site(&["-m", "spa", "-f", app_path]);
+        let app = test::init_service(
+            App::new()
+                .service(site_scope(&docs))
+                .service(site_scope(&default)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/guide.txt")
+            .insert_header((header::HOST, "docs.example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(test::read_body(resp).await, "Docs guide");
+
+        // 其他 Host 回退到默认站点（SPA）
+        let req = test::TestRequest::get()
+            .uri("/guide.txt")
+            .insert_header((header::HOST, "app.example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(test::read_body(resp).await, "<html>SPA Index</html>");
+    }
+
+    #[actix_web::test]
+    async fn test_vhost_has_own_basic_auth() {
+        let tmp = setup_dir();
+        let path = tmp.path().to_str().unwrap();
+        let private = make_site(&["--server-name", "private.example.com", "-f", path, "-s", "user:pass"]);
+        let public = make_site(&["-f", path]);
+        let app = test::init_service(
+            App::new()
+                .service(site_scope(&private))
+                .service(site_scope(&public)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/file.txt")
+            .insert_header((header::HOST, "private.example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+
+        let req = test::TestRequest::get()
+            .uri("/file.txt")
+            .insert_header((header::HOST, "public.example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    // ── 自定义响应头 ──────────────────────────────────────────────────────────
+
+    #[actix_web::test]
+    async fn test_site_headers_on_listing_and_auth_error() {
+        let tmp = setup_dir();
+        let path = tmp.path().to_str().unwrap();
+        let site = make_site(&["-f", path, "--security-headers", "--header", "X-Site: main"]);
+        let private = make_site(&[
+            "--server-name", "private.example.com", "-f", path, "-s", "user:pass",
+            "--header", "X-Site: private",
+        ]);
+        let app = test::init_service(
+            App::new()
+                .service(site_scope(&private))
+                .service(site_scope(&site)),
+        )
+        .await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-site").unwrap(), "main");
+        assert_eq!(resp.headers().get("x-content-type-options").unwrap(), "nosniff");
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((header::HOST, "private.example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+        assert_eq!(resp.headers().get("x-site").unwrap(), "private");
+    }
+
+    // ── 健康检查 ──────────────────────────────────────────────────────────────
+
+    #[actix_web::test]
+    async fn test_health_routes_bypass_basic_auth() {
+        let dir = setup_dir();
+        let path = dir.path().to_str().unwrap();
+        let options = CliOption::parse_from(["hs", "-f", path, "-s", "user:pass", "--ready-check", "root"]);
+        let site = make_site(&["-f", path, "-s", "user:pass"]);
+        let health_check = web::Data::new(build_health_check(&options, std::slice::from_ref(&site)));
+        let app = test::init_service(
+            App::new()
+                .route(&options.health_path, web::get().to(health))
+                .app_data(health_check)
+                .route(&options.ready_path, web::get().to(ready))
+                .service(site_scope(&site)),
+        )
+        .await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/_health").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/_ready").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        // 其他路径仍然需要认证
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.status(), 401);
+    }
+
+    // ── 代理错误页 ────────────────────────────────────────────────────────────
+
+    #[actix_web::test]
+    async fn test_missing_proxy_error_page_is_rejected() {
+        let dir = setup_dir();
+        let path = dir.path().to_str().unwrap();
+        let options = CliOption::parse_from(["hs", "-f", path, "--proxy-error-page", "50x.html"]);
+        assert!(Site::from_options(&options).is_err());
+        std::fs::write(dir.path().join("50x.html"), "<h1>Down</h1>").unwrap();
+        let site = Site::from_options(&options).unwrap();
+        assert_eq!(site.proxy_error_page.0.as_deref(), Some(&b"<h1>Down</h1>"[..]));
+    }
+
+    // ── 代理路由条件与优先级 ──────────────────────────────────────────────────
+
+    #[actix_web::test]
+    async fn test_proxy_conditions_fall_through_to_files() {
+        let dir = setup_dir();
+        let path = dir.path().to_str().unwrap();
+        // 端口 1 上没有服务，代理到这里的请求会返回 502
+        let site = make_site(&[
+            "-f",
+            path,
+            "-P",
+            r"~^/subdir/.*\.txt$->http://127.0.0.1:1;header=X-Canary: 1",
+            "-P",
+            "/file.txt->http://127.0.0.1:1;method=DELETE",
+        ]);
+        assert!(!site.all_proxyed);
+        let app = test::init_service(App::new().service(site_scope(&site))).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/subdir/sub.txt").to_request()).await;
+        assert_eq!(test::read_body(resp).await, "Sub content");
+        let req = test::TestRequest::get()
+            .uri("/subdir/sub.txt")
+            .insert_header(("x-canary", "1"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 502);
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/file.txt").to_request()).await;
+        assert_eq!(test::read_body(resp).await, "Hello World");
+        let req = test::TestRequest::delete().uri("/file.txt").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 502);
+    }
+
+    #[actix_web::test]
+    async fn test_proxy_priority_order() {
+        let site = make_site(&[
+            "-P",
+            "/api->http://127.0.0.1:1/",
+            "-P",
+            r"~^/api/v(\d+)/->http://127.0.0.1:2/v$1/;priority=10",
+            "-W",
+            "/api/ws->http://127.0.0.1:3/;priority=5",
+        ]);
+        let app = test::init_service(App::new().service(site_scope(&site))).await;
+        let paths = [("/api/v2/users", 502), ("/api/ws", 400), ("/api/users", 502)];
+        for (path, status) in paths {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+            assert_eq!(resp.status(), status, "{}", path);
+        }
+        // -W 的规则和 -P 一起排序，priority 高的先匹配
+        let site = make_site(&["-P", "/api->http://127.0.0.1:1/", "-W", "/api/ws->http://127.0.0.1:3/"]);
+        let app = test::init_service(App::new().service(site_scope(&site))).await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/api/ws").to_request()).await;
+        assert_eq!(resp.status(), 502);
+    }
+
+    // ── 代理缓存 ──────────────────────────────────────────────────────────────
+
+    #[actix_web::test]
+    async fn test_proxy_cache_revalidates_with_its_own_validators() {
+        // 模拟一个按客户端 ETag 回复 304 的上游
+        let upstream = HttpServer::new(|| {
+            App::new().default_service(web::to(|req: HttpRequest| async move {
+                if req.headers().contains_key(header::IF_NONE_MATCH) {
+                    return HttpResponse::NotModified().finish();
+                }
+                let body = if req.headers().contains_key(header::IF_MODIFIED_SINCE) { "v2" } else { "v1" };
+                HttpResponse::Ok()
+                    .insert_header((header::CACHE_CONTROL, "max-age=0"))
+                    .insert_header((header::LAST_MODIFIED, "Sun, 06 Nov 1994 08:49:37 GMT"))
+                    .body(body)
+            }))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let port = upstream.addrs()[0].port();
+        let upstream = upstream.run();
+        let upstream_handle = upstream.handle();
+        rt::spawn(upstream);
+
+        let site = make_site(&["-P", &format!("/api->http://127.0.0.1:{}/;cache=1M", port)]);
+        let app = test::init_service(App::new().service(site_scope(&site))).await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/api/a").to_request()).await;
+        assert_eq!(resp.headers().get("x-cache").unwrap(), "MISS");
+        assert_eq!(test::read_body(resp).await, "v1");
+        // 客户端的 If-None-Match 不能让上游的 304 刷新过期的缓存
+        let req = test::TestRequest::get()
+            .uri("/api/a")
+            .insert_header((header::IF_NONE_MATCH, "\"v2\""))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("x-cache").unwrap(), "MISS");
+        assert_eq!(test::read_body(resp).await, "v2");
+        upstream_handle.stop(false).await;
+    }
+
+    // ── 代理 mock ─────────────────────────────────────────────────────────────
+
+    #[actix_web::test]
+    async fn test_proxy_mock_fallback() {
+        let mocks = TempDir::new().unwrap();
+        std::fs::create_dir_all(mocks.path().join("GET/api")).unwrap();
+        std::fs::write(mocks.path().join("GET/api/users.json"), r#"{"body": [{"id": 1}]}"#).unwrap();
+        let mocks = mocks.path().to_str().unwrap();
+        let site = make_site(&[
+            "-P",
+            &format!("/api->http://127.0.0.1:1;mock={}", mocks),
+            "-P",
+            &format!("/offline->;mock={}", mocks),
+        ]);
+        let app = test::init_service(App::new().service(site_scope(&site))).await;
+
+        // 上游连不上时返回 mock 文件
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/api/users").to_request()).await;
+        assert_eq!(resp.headers().get("x-mock").unwrap(), "GET/api/users.json");
+        assert_eq!(test::read_body(resp).await, r#"[{"id":1}]"#);
+        let req = test::TestRequest::get().uri("/api/other").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 502);
+        let req = test::TestRequest::get().uri("/offline/x").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 502);
+        let options = CliOption::parse_from(["hs", "-W", &format!("/ws->;mock={}", mocks)]);
+        assert!(Site::from_options(&options).is_err());
+    }
+
+    // ── Unix socket ───────────────────────────────────────────────────────────
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn test_unix_socket_listener_and_upstream() {
+        let tmp = TempDir::new().unwrap();
+        let upstream_socket = tmp.path().join("api.sock");
+        let upstream = HttpServer::new(|| {
+            App::new().default_service(web::to(|req: HttpRequest| async move {
+                let forwarded_for = req.headers().get("x-forwarded-for").cloned();
+                HttpResponse::Ok().body(format!("{} {:?}", req.uri(), forwarded_for))
+            }))
+        })
+        .workers(1)
+        .bind_uds(&upstream_socket)
+        .unwrap()
+        .run();
+        let upstream_handle = upstream.handle();
+        rt::spawn(upstream);
+
+        let socket = tmp.path().join("hs.sock");
+        let options = CliOption::parse_from([
+            "hs",
+            "--listen",
+            &format!("unix:{}", socket.display()),
+            "-P",
+            &format!("/api->unix:{}:/v1/", upstream_socket.display()),
+        ]);
+        let specs = listen_specs(&options).unwrap();
+        let listeners = bind_all(&specs).unwrap();
+        let (server, _checkers) = build_server(&options, clone_listeners(&specs, &listeners).unwrap()).unwrap();
+        let handle = server.handle();
+        rt::spawn(server.run());
+
+        let client = awc::Client::builder()
+            .connector(crate::upstream::unix_connector(&socket))
+            .finish();
+        let mut resp = client.get("http://localhost/api/users?page=2").send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        // unix socket 的对端按 127.0.0.1 转发
+        assert_eq!(resp.body().await.unwrap(), r#"/v1/users?page=2 Some("127.0.0.1")"#);
+
+        handle.stop(false).await;
+        upstream_handle.stop(false).await;
+        listeners[0].remove_socket();
+        assert!(!socket.exists());
+    }
+
+    // ── HTTPS 重定向 ──────────────────────────────────────────────────────────
+
+    #[actix_web::test]
+    async fn test_redirect_plain_listener_to_https() {
+        let dir = setup_dir();
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        std::fs::write(&cert, crate::tls::tests::CERT_PEM).unwrap();
+        std::fs::write(&key, crate::tls::tests::KEY_PEM).unwrap();
+        let options = CliOption::parse_from([
+            "hs",
+            "-f",
+            dir.path().to_str().unwrap(),
+            "--tls-cert",
+            cert.to_str().unwrap(),
+            "--tls-key",
+            key.to_str().unwrap(),
+            "--listen",
+            "127.0.0.1:0;redirect-https",
+            "--listen",
+            "127.0.0.1:0;tls",
+        ]);
+        let specs = listen_specs(&options).unwrap();
+        let listeners = bind_all(&specs).unwrap();
+        let ports: Vec<u16> = listeners
+            .iter()
+            .map(|listener| match listener {
+                Listener::Tcp(listener) => listener.local_addr().unwrap().port(),
+                #[cfg(unix)]
+                Listener::Unix(..) => unreachable!(),
+            })
+            .collect();
+        let (server, _checkers) = build_server(&options, clone_listeners(&specs, &listeners).unwrap()).unwrap();
+        let handle = server.handle();
+        rt::spawn(server.run());
+
+        let client = awc::Client::builder().disable_redirects().finish();
+        let url = |path: &str| format!("http://127.0.0.1:{}{}", ports[0], path);
+        let resp = client
+            .get(url("/file.txt?v=1"))
+            .insert_header(("host", format!("example.com:{}", ports[0])))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 301);
+        let location = format!("https://example.com:{}/file.txt?v=1", ports[1]);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), location.as_str());
+        // 伪造的 X-Forwarded-Host 不会变成重定向地址
+        let resp = client
+            .get(url("/file.txt"))
+            .insert_header(("host", "example.com"))
+            .insert_header(("x-forwarded-host", "evil.example.net"))
+            .insert_header(("forwarded", "host=evil.example.net"))
+            .send()
+            .await
+            .unwrap();
+        let location = format!("https://example.com:{}/file.txt", ports[1]);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), location.as_str());
+        // POST 重定向后保留请求方法
+        let resp = client.post(url("/upload")).send().await.unwrap();
+        assert_eq!(resp.status(), 308);
+        // ACME 验证仍然走 HTTP
+        let resp = client.get(url("/.well-known/acme-challenge/token")).send().await.unwrap();
+        assert_eq!(resp.status(), 404);
+        handle.stop(false).await;
+    }
+
     // ── root path canonicalize (fix: -f . 访问 / 报 "Provided path has no filename") ──
 
     #[actix_web::test]
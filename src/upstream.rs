@@ -0,0 +1,554 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use actix_tls::connect::{ConnectError, ConnectInfo, Connection};
+#[cfg(unix)]
+use actix_web::dev::{fn_service, Service};
+#[cfg(unix)]
+use actix_web::http::Uri;
+#[cfg(unix)]
+use actix_web::rt::net::UnixStream;
+use actix_web::rt::{self, task::JoinHandle, time::sleep};
+use awc::Client;
+#[cfg(unix)]
+use awc::Connector;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use fancy_regex::Regex;
+use url::Url;
+
+use crate::health::upstream_probe_url;
+use crate::logger::LOGGER;
+
+// 目标地址中的正则捕获组引用：$1、$name、${name}
+static CAPTURE_REF_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$(\d+|[A-Za-z_][A-Za-z0-9_]*|\{[^}]*\})").unwrap());
+
+/// How a proxy picks one of its upstreams.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Balancer {
+    #[default]
+    RoundRobin,
+    LeastConn,
+    Random,
+    /// 同一客户端 IP 总是落到同一个上游（上游被摘除时除外）
+    IpHash,
+}
+
+impl Balancer {
+    pub fn parse(value: &str) -> Result<Balancer, String> {
+        match value.trim() {
+            "round-robin" => Ok(Balancer::RoundRobin),
+            "least-conn" => Ok(Balancer::LeastConn),
+            "random" => Ok(Balancer::Random),
+            "ip-hash" => Ok(Balancer::IpHash),
+            other => Err(format!(
+                "Unknown lb '{}', expected round-robin, least-conn, random or ip-hash",
+                other
+            )),
+        }
+    }
+}
+
+/// Passive health tracking: an upstream failing `max_fails` times in a row
+/// is skipped for `fail_timeout`.
+#[derive(Clone, Copy, Debug)]
+pub struct HealthPolicy {
+    pub max_fails: u32,
+    pub fail_timeout: Duration,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        HealthPolicy {
+            max_fails: 3,
+            fail_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Expected status codes of an active health check, eg: `200`, `2xx,3xx`, `200-204`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatusMatch(Vec<(u16, u16)>);
+
+impl Default for StatusMatch {
+    fn default() -> Self {
+        StatusMatch(vec![(200, 399)])
+    }
+}
+
+impl StatusMatch {
+    pub fn parse(value: &str) -> Result<StatusMatch, String> {
+        let invalid = |item: &str| format!("Invalid status '{}', expected eg: 200, 2xx or 200-204", item);
+        let code = |item: &str| item.trim().parse::<u16>().ok().filter(|c| (100..=599).contains(c));
+        value
+            .split(',')
+            .map(|item| {
+                let item = item.trim();
+                if let Some(class) = item.strip_suffix("xx") {
+                    let class = code(&format!("{}00", class)).ok_or_else(|| invalid(item))?;
+                    return Ok((class, class + 99));
+                }
+                let (from, to) = item.split_once('-').unwrap_or((item, item));
+                match (code(from), code(to)) {
+                    (Some(from), Some(to)) if from <= to => Ok((from, to)),
+                    _ => Err(invalid(item)),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(StatusMatch)
+    }
+
+    pub fn matches(&self, status: u16) -> bool {
+        self.0.iter().any(|(from, to)| (*from..=*to).contains(&status))
+    }
+}
+
+/// Active health check: `path` of every upstream is requested each `interval`,
+/// an upstream not answering an expected status within `timeout` is marked down.
+#[derive(Clone, Debug)]
+pub struct ActiveCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub status: StatusMatch,
+}
+
+impl ActiveCheck {
+    pub fn new(path: &str) -> ActiveCheck {
+        ActiveCheck {
+            path: path.trim().to_string(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            status: StatusMatch::default(),
+        }
+    }
+}
+
+/// Result of the latest active health check.
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckResult {
+    pub ok: bool,
+    /// 响应状态码或者失败原因
+    pub detail: String,
+    pub at: DateTime<Local>,
+}
+
+// 上游运行时状态，同一代的所有 worker 共享
+#[derive(Default)]
+struct UpstreamState {
+    active: AtomicUsize,
+    fails: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    // 主动健康检查标记为不可用
+    down: AtomicBool,
+    last_check: Mutex<Option<CheckResult>>,
+}
+
+/// One target of a proxy rule.
+#[derive(Clone)]
+pub struct Upstream {
+    /// 原始字符串，仅用于显示/日志
+    pub url: String,
+    /// 启动时预解析，避免每次请求重复 parse
+    pub parsed: Url,
+    /// `unix:/run/api.sock:/prefix` 的 socket 文件，此时 parsed 为 `http://localhost/prefix`
+    #[cfg(unix)]
+    pub socket: Option<PathBuf>,
+    // 捕获组替换用的地址，unix socket 上游不含 socket 部分
+    target: String,
+    // 正则规则的目标地址引用了捕获组
+    template: bool,
+    state: Arc<UpstreamState>,
+}
+
+impl Upstream {
+    /// Parses `http(s)://` and `ws(s)://` URLs, or `unix:PATH[:/prefix]` for a unix domain socket.
+    pub fn parse(url: &str) -> Result<Upstream, String> {
+        Upstream::parse_target(url, false)
+    }
+
+    /// Parses the target of a `~` regex rule, `$1`, `$name` and `${name}` refer to its captures.
+    pub fn parse_template(url: &str) -> Result<Upstream, String> {
+        Upstream::parse_target(url, true)
+    }
+
+    fn parse_target(url: &str, captures: bool) -> Result<Upstream, String> {
+        let url = url.trim().to_string();
+        #[cfg(not(unix))]
+        if url.starts_with("unix:") {
+            return Err(format!(
+                "Invalid proxy target '{}', unix sockets are not supported on this platform",
+                url
+            ));
+        }
+        #[cfg(not(unix))]
+        let target = url.clone();
+        #[cfg(unix)]
+        let (socket, target) = match url.strip_prefix("unix:") {
+            Some(rest) => {
+                let (path, prefix) = rest.split_once(':').unwrap_or((rest, ""));
+                if path.is_empty() || !(prefix.is_empty() || prefix.starts_with('/')) {
+                    return Err(format!(
+                        "Invalid proxy target '{}', expected unix:/path/to.sock[:/prefix]",
+                        url
+                    ));
+                }
+                (Some(PathBuf::from(path)), format!("http://localhost{}", prefix))
+            }
+            None => (None, url.clone()),
+        };
+        // 捕获组引用先换成 0 再校验，比如 http://127.0.0.1:300$1/
+        let template = captures && CAPTURE_REF_REGEX.is_match(&target).unwrap_or(false);
+        let checked = if template { CAPTURE_REF_REGEX.replace_all(&target, "0") } else { target.as_str().into() };
+        let parsed = Url::parse(&checked).map_err(|e| format!("Invalid proxy target URL '{}': {}", url, e))?;
+        Ok(Upstream {
+            url,
+            parsed,
+            #[cfg(unix)]
+            socket,
+            target,
+            template,
+            state: Arc::default(),
+        })
+    }
+
+    /// A target of a regex rule using captures, eg: `http://svc-v$1/$2`, only known per request.
+    pub fn is_template(&self) -> bool {
+        self.template
+    }
+
+    /// The URL captures are substituted in, `http://localhost/prefix` for a unix socket.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Client dialing this upstream, through its unix socket if it has one.
+    pub fn client(&self, timeout: Duration) -> Client {
+        let builder = Client::builder().timeout(timeout);
+        #[cfg(unix)]
+        if let Some(socket) = &self.socket {
+            return builder.connector(unix_connector(socket)).finish();
+        }
+        builder.finish()
+    }
+
+    pub fn is_available(&self) -> bool {
+        !self.is_down() && !self.is_ejected()
+    }
+
+    /// Marked down by the active health check.
+    pub fn is_down(&self) -> bool {
+        self.state.down.load(Ordering::Relaxed)
+    }
+
+    /// Skipped after consecutive failures of proxied requests.
+    pub fn is_ejected(&self) -> bool {
+        match *self.state.ejected_until.lock().unwrap() {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+
+    pub fn consecutive_fails(&self) -> u32 {
+        self.state.fails.load(Ordering::Relaxed)
+    }
+
+    pub fn last_check(&self) -> Option<CheckResult> {
+        self.state.last_check.lock().unwrap().clone()
+    }
+
+    /// Stores an active check result, returns true when the upstream went up or down.
+    pub fn record_check(&self, result: CheckResult) -> bool {
+        let was_down = self.state.down.swap(!result.ok, Ordering::Relaxed);
+        *self.state.last_check.lock().unwrap() = Some(result.clone());
+        was_down == result.ok
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.state.active.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request in flight until the guard is dropped, used by least-conn.
+    pub fn connect(&self) -> ConnectionGuard {
+        self.state.active.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.state.clone())
+    }
+
+    pub fn record_success(&self) {
+        self.state.fails.store(0, Ordering::Relaxed);
+        *self.state.ejected_until.lock().unwrap() = None;
+    }
+
+    /// Returns true when this failure ejected the upstream.
+    pub fn record_failure(&self, policy: &HealthPolicy) -> bool {
+        let fails = self.state.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if policy.max_fails == 0 || fails < policy.max_fails {
+            return false;
+        }
+        self.state.fails.store(0, Ordering::Relaxed);
+        *self.state.ejected_until.lock().unwrap() = Some(Instant::now() + policy.fail_timeout);
+        true
+    }
+}
+
+pub struct ConnectionGuard(Arc<UpstreamState>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The upstreams of a proxy rule and how to choose between them.
+#[derive(Clone)]
+pub struct UpstreamPool {
+    pub upstreams: Vec<Upstream>,
+    pub balancer: Balancer,
+    pub health: HealthPolicy,
+    pub active_check: Option<ActiveCheck>,
+    next: Arc<AtomicUsize>,
+}
+
+impl UpstreamPool {
+    pub fn new(upstreams: Vec<Upstream>) -> UpstreamPool {
+        UpstreamPool {
+            upstreams,
+            balancer: Balancer::default(),
+            health: HealthPolicy::default(),
+            active_check: None,
+            next: Arc::default(),
+        }
+    }
+
+    /// Picks an upstream not in `tried`, preferring available ones.
+    /// When every candidate is ejected they are tried anyway instead of failing right away.
+    pub fn select(&self, client_ip: Option<IpAddr>, tried: &[usize]) -> Option<usize> {
+        let candidates: Vec<usize> = (0..self.upstreams.len())
+            .filter(|i| !tried.contains(i))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let available: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|i| self.upstreams[*i].is_available())
+            .collect();
+        let pool = if available.is_empty() { &candidates } else { &available };
+        let index = match self.balancer {
+            Balancer::RoundRobin => pool[self.next.fetch_add(1, Ordering::Relaxed) % pool.len()],
+            Balancer::Random => pool[fastrand::usize(..pool.len())],
+            Balancer::LeastConn => *pool
+                .iter()
+                .min_by_key(|i| self.upstreams[**i].active_connections())
+                .unwrap(),
+            Balancer::IpHash => {
+                let mut hasher = DefaultHasher::new();
+                client_ip.hash(&mut hasher);
+                let preferred = hasher.finish() as usize % self.upstreams.len();
+                // 首选的不可用时顺延到下一个
+                (0..self.upstreams.len())
+                    .map(|offset| (preferred + offset) % self.upstreams.len())
+                    .find(|i| pool.contains(i))
+                    .unwrap()
+            }
+        };
+        Some(index)
+    }
+}
+
+/// Background active health checks of one server generation, stopped when dropped.
+#[derive(Default)]
+pub struct HealthCheckers(Vec<JoinHandle<()>>);
+
+impl HealthCheckers {
+    /// Starts one checker for every upstream of `pool` when it has an active check.
+    pub fn spawn(&mut self, pool: &UpstreamPool) {
+        let Some(check) = &pool.active_check else {
+            return;
+        };
+        // 目标地址由请求决定的上游无法主动检查
+        for upstream in pool.upstreams.iter().filter(|u| !u.is_template()) {
+            let upstream = upstream.clone();
+            let check = check.clone();
+            self.0.push(rt::spawn(async move {
+                let client = upstream.client(check.timeout);
+                loop {
+                    let result = probe(&client, &upstream, &check).await;
+                    if upstream.record_check(result.clone()) {
+                        if result.ok {
+                            LOGGER.info(format!("proxy: upstream {} is up", upstream.url));
+                        } else {
+                            LOGGER.warn(format!("proxy: upstream {} is down: {}", upstream.url, result.detail));
+                        }
+                    }
+                    sleep(check.interval).await;
+                }
+            }));
+        }
+    }
+}
+
+impl Drop for HealthCheckers {
+    fn drop(&mut self) {
+        for handle in &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+/// awc connector connecting every request to `socket` whatever the host of the URL is.
+#[cfg(unix)]
+pub fn unix_connector(
+    socket: &Path,
+) -> Connector<impl Service<ConnectInfo<Uri>, Response = Connection<Uri, UnixStream>, Error = ConnectError> + Clone>
+{
+    let socket = Rc::new(socket.to_path_buf());
+    Connector::new().connector(fn_service(move |info: ConnectInfo<Uri>| {
+        let socket = socket.clone();
+        async move {
+            let stream = UnixStream::connect(socket.as_path()).await.map_err(ConnectError::Io)?;
+            Ok(Connection::new(info.request().clone(), stream))
+        }
+    }))
+}
+
+async fn probe(client: &Client, upstream: &Upstream, check: &ActiveCheck) -> CheckResult {
+    let (ok, detail) = match upstream_probe_url(&upstream.parsed).join(&check.path) {
+        Ok(url) => match client.get(url.as_str()).send().await {
+            Ok(res) => (
+                check.status.matches(res.status().as_u16()),
+                format!("status {}", res.status().as_u16()),
+            ),
+            Err(e) => (false, e.to_string()),
+        },
+        Err(e) => (false, format!("invalid health check path: {}", e)),
+    };
+    CheckResult {
+        ok,
+        detail,
+        at: Local::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(balancer: Balancer) -> UpstreamPool {
+        let mut pool = UpstreamPool::new(
+            ["http://a/", "http://b/", "http://c/"]
+                .iter()
+                .map(|u| Upstream::parse(u).unwrap())
+                .collect(),
+        );
+        pool.balancer = balancer;
+        pool
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = pool(Balancer::RoundRobin);
+        let picks: Vec<usize> = (0..4).map(|_| pool.select(None, &[]).unwrap()).collect();
+        assert_eq!(picks, vec![0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn test_least_conn_prefers_idle_upstream() {
+        let pool = pool(Balancer::LeastConn);
+        let _a = pool.upstreams[0].connect();
+        let _b = pool.upstreams[1].connect();
+        assert_eq!(pool.select(None, &[]), Some(2));
+        drop(_a);
+        assert_eq!(pool.select(None, &[2]), Some(0));
+    }
+
+    #[test]
+    fn test_ip_hash_is_stable() {
+        let pool = pool(Balancer::IpHash);
+        let ip = Some("203.0.113.7".parse().unwrap());
+        let first = pool.select(ip, &[]).unwrap();
+        assert!((0..10).all(|_| pool.select(ip, &[]) == Some(first)));
+    }
+
+    #[test]
+    fn test_random_skips_tried() {
+        let pool = pool(Balancer::Random);
+        assert!((0..20).all(|_| pool.select(None, &[0, 2]) == Some(1)));
+        assert_eq!(pool.select(None, &[0, 1, 2]), None);
+    }
+
+    #[test]
+    fn test_passive_ejection() {
+        let pool = pool(Balancer::RoundRobin);
+        let policy = HealthPolicy {
+            max_fails: 2,
+            fail_timeout: Duration::from_secs(60),
+        };
+        assert!(!pool.upstreams[0].record_failure(&policy));
+        assert!(pool.upstreams[0].record_failure(&policy));
+        assert!(!pool.upstreams[0].is_available());
+        assert!((0..6).all(|_| pool.select(None, &[]) != Some(0)));
+        // 全部不可用时仍然尝试
+        assert_eq!(pool.select(None, &[1, 2]), Some(0));
+        pool.upstreams[0].record_success();
+        assert!(pool.upstreams[0].is_available());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_unix_socket_target() {
+        let upstream = Upstream::parse("unix:/run/api.sock:/prefix/").unwrap();
+        assert_eq!(upstream.socket, Some(PathBuf::from("/run/api.sock")));
+        assert_eq!(upstream.parsed.as_str(), "http://localhost/prefix/");
+        assert_eq!(upstream.url, "unix:/run/api.sock:/prefix/");
+        let upstream = Upstream::parse("unix:/run/api.sock").unwrap();
+        assert_eq!(upstream.parsed.path(), "/");
+        assert!(Upstream::parse("unix:/run/api.sock:prefix").is_err());
+        assert!(Upstream::parse("unix::/prefix").is_err());
+        // 捕获组只替换路径部分
+        let upstream = Upstream::parse_template("unix:/run/api.sock:/v$1/").unwrap();
+        assert!(upstream.is_template());
+        assert_eq!(upstream.target(), "http://localhost/v$1/");
+        assert!(Upstream::parse("http://a/").unwrap().socket.is_none());
+    }
+
+    #[test]
+    fn test_status_match() {
+        assert!(StatusMatch::default().matches(302));
+        assert!(!StatusMatch::default().matches(500));
+        let status = StatusMatch::parse("2xx, 401-403").unwrap();
+        assert!(status.matches(204) && status.matches(401) && status.matches(403));
+        assert!(!status.matches(404));
+        assert!(StatusMatch::parse("abc").is_err());
+        assert!(StatusMatch::parse("9xx").is_err());
+        assert!(StatusMatch::parse("500-200").is_err());
+    }
+
+    #[test]
+    fn test_active_check_marks_down_and_up() {
+        let pool = pool(Balancer::RoundRobin);
+        let result = |ok| CheckResult {
+            ok,
+            detail: String::new(),
+            at: Local::now(),
+        };
+        assert!(!pool.upstreams[1].record_check(result(true)));
+        assert!(pool.upstreams[1].record_check(result(false)));
+        assert!(!pool.upstreams[1].record_check(result(false)));
+        assert!(pool.upstreams[1].is_down());
+        assert!((0..6).all(|_| pool.select(None, &[]) != Some(1)));
+        assert!(pool.upstreams[1].record_check(result(true)));
+        assert!(pool.upstreams[1].is_available());
+    }
+}
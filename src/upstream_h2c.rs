@@ -0,0 +1,230 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+use actix_web::error::PayloadError;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::StatusCode;
+#[cfg(unix)]
+use actix_web::rt::net::UnixStream;
+use actix_web::rt::{self, net::TcpStream};
+use actix_web::web::Bytes;
+use awc::error::{ConnectError, SendRequestError};
+use awc::ClientRequest;
+use futures::future::poll_fn;
+use futures::stream::{self, LocalBoxStream};
+use futures::StreamExt;
+use h2::client::SendRequest;
+use h2::SendStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::timeout;
+
+use crate::logger::LOGGER;
+
+pub type Body = LocalBoxStream<'static, Result<Bytes, PayloadError>>;
+
+/// HTTP/2 with prior knowledge to `http://` upstreams, awc only speaks HTTP/2 over TLS.
+/// Requests of a worker to the same upstream are multiplexed on one connection.
+#[derive(Clone)]
+pub struct H2cClient {
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    connections: Rc<RefCell<HashMap<String, SendRequest<Bytes>>>>,
+}
+
+impl H2cClient {
+    pub fn new(connect_timeout: Duration, read_timeout: Duration) -> H2cClient {
+        H2cClient {
+            connect_timeout,
+            read_timeout,
+            connections: Rc::default(),
+        }
+    }
+
+    // `socket` 不为空时 address 只作为连接的名字
+    async fn connection(&self, address: &str, socket: Option<&Path>) -> Result<SendRequest<Bytes>, SendRequestError> {
+        let cached = self.connections.borrow().get(address).cloned();
+        if let Some(connection) = cached {
+            // 连接断开后重新建立
+            if let Ok(connection) = connection.ready().await {
+                return Ok(connection);
+            }
+            self.connections.borrow_mut().remove(address);
+        }
+        let send = match socket {
+            #[cfg(unix)]
+            Some(socket) => handshake(address, self.dial(UnixStream::connect(socket)).await?).await?,
+            // 其他平台的上游不会有 socket
+            #[cfg(not(unix))]
+            Some(_) => unreachable!("unix sockets are not supported on this platform"),
+            None => {
+                let tcp = self.dial(TcpStream::connect(address)).await?;
+                let _ = tcp.set_nodelay(true);
+                handshake(address, tcp).await?
+            }
+        };
+        self.connections
+            .borrow_mut()
+            .insert(address.to_string(), send.clone());
+        Ok(send)
+    }
+
+    async fn dial<T>(&self, connect: impl Future<Output = std::io::Result<T>>) -> Result<T, SendRequestError> {
+        timeout(self.connect_timeout, connect)
+            .await
+            .map_err(|_| SendRequestError::Connect(ConnectError::Timeout))?
+            .map_err(|e| SendRequestError::Connect(ConnectError::Io(e)))
+    }
+    /// Sends the head of an awc request with `body`, the `Host` header becomes `:authority`.
+    /// The connection is made to `socket` instead of the host of the URL when given.
+    pub async fn send(
+        &self,
+        request: &ClientRequest,
+        socket: Option<&Path>,
+        body: Option<Body>,
+    ) -> Result<(StatusCode, HeaderMap, Body), SendRequestError> {
+        let uri = request.get_uri();
+        let address = match socket {
+            Some(socket) => format!("unix:{}", socket.display()),
+            None => format!("{}:{}", uri.host().unwrap_or_default(), uri.port_u16().unwrap_or(80)),
+        };
+        let mut parts = uri.clone().into_parts();
+        if let Some(host) = request.headers().get(header::HOST) {
+            if let Ok(authority) = http::uri::Authority::try_from(host.as_bytes()) {
+                parts.authority = Some(authority);
+            }
+        }
+        let uri = http::Uri::from_parts(parts).map_err(|e| SendRequestError::Http(e.into()))?;
+        let mut head = http::Request::builder()
+            .method(request.get_method().clone())
+            .uri(uri)
+            .version(http::Version::HTTP_2);
+        for (name, value) in request.headers().iter().filter(|(name, value)| allowed_in_h2(name, value)) {
+            head = head.header(name, value);
+        }
+        let head = head.body(()).map_err(SendRequestError::Http)?;
+
+        let (response, send_stream) = self
+            .connection(&address, socket)
+            .await?
+            .send_request(head, body.is_none())
+            .map_err(SendRequestError::H2)?;
+        if let Some(body) = body {
+            rt::spawn(send_body(send_stream, body));
+        }
+        let response = timeout(self.read_timeout, response)
+            .await
+            .map_err(|_| SendRequestError::Timeout)?
+            .map_err(SendRequestError::H2)?;
+        let (parts, recv) = response.into_parts();
+        let body = stream::unfold(recv, |mut recv| async move {
+            let chunk = recv.data().await?;
+            if let Ok(chunk) = &chunk {
+                // 读完后才允许上游继续发送
+                let _ = recv.flow_control().release_capacity(chunk.len());
+            }
+            Some((chunk.map_err(PayloadError::Http2Payload), recv))
+        });
+        Ok((parts.status, HeaderMap::from(parts.headers), body.boxed_local()))
+    }
+}
+
+async fn handshake<T>(name: &str, io: T) -> Result<SendRequest<Bytes>, SendRequestError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let (send, connection) = h2::client::handshake(io).await.map_err(SendRequestError::H2)?;
+    let name = name.to_string();
+    rt::spawn(async move {
+        if let Err(e) = connection.await {
+            LOGGER.warn(format!("proxy: h2c connection to {} closed: {}", name, e));
+        }
+    });
+    Ok(send)
+}
+
+// HTTP/2 不允许连接相关的请求头
+fn allowed_in_h2(name: &header::HeaderName, value: &header::HeaderValue) -> bool {
+    match name.as_str() {
+        "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade" | "host" => false,
+        "te" => value == "trailers",
+        _ => true,
+    }
+}
+
+// 按上游的流量控制窗口发送请求体，请求体出错时取消这个请求
+async fn send_body(mut send: SendStream<Bytes>, mut body: Body) {
+    while let Some(chunk) = body.next().await {
+        let Ok(mut chunk) = chunk else {
+            send.send_reset(h2::Reason::CANCEL);
+            return;
+        };
+        while !chunk.is_empty() {
+            send.reserve_capacity(chunk.len());
+            let capacity = match poll_fn(|cx| send.poll_capacity(cx)).await {
+                Some(Ok(capacity)) => capacity,
+                _ => return,
+            };
+            let data = chunk.split_to(capacity.min(chunk.len()));
+            if send.send_data(data, false).is_err() {
+                return;
+            }
+        }
+    }
+    let _ = send.send_data(Bytes::new(), true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+    #[actix_web::test]
+    async fn test_h2c_request_with_body() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|req: HttpRequest, body: Bytes| async move {
+                let host = req.connection_info().host().to_string();
+                HttpResponse::Ok().body(format!("{:?} {} {}", req.version(), host, body.len()))
+            }))
+        })
+        .workers(1)
+        .listen_auto_h2c(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        rt::spawn(server);
+
+        let client = H2cClient::new(Duration::from_secs(5), Duration::from_secs(5));
+        let request = awc::Client::default()
+            .post(format!("http://127.0.0.1:{}/upload", port))
+            .insert_header(("host", "app.example.com"))
+            .insert_header(("connection", "keep-alive"));
+        // 超过默认的 64K 流量控制窗口
+        let chunks = [Bytes::from(vec![b'x'; 50_000]), Bytes::from(vec![b'y'; 50_000])];
+        for _ in 0..2 {
+            let body = stream::iter(chunks.clone().map(Ok)).boxed_local();
+            let (status, _, body) = client
+                .send(&request, None, Some(body))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let body: Vec<Bytes> = body.map(|chunk| chunk.unwrap()).collect().await;
+            assert_eq!(body.concat(), b"HTTP/2.0 app.example.com 100000");
+        }
+        // 两个请求复用同一个连接
+        assert_eq!(client.connections.borrow().len(), 1);
+        handle.stop(false).await;
+
+        let closed = H2cClient::new(Duration::from_secs(1), Duration::from_secs(1));
+        let request = awc::Client::default().get(format!("http://127.0.0.1:{}/", port));
+        assert!(matches!(
+            closed.send(&request, None, None).await,
+            Err(SendRequestError::Connect(_))
+        ));
+    }
+}
@@ -1,23 +1,50 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
 
 use actix::{
     io::{SinkWrite, WriteHandler},
-    Actor, ActorContext, AsyncContext, StreamHandler,
+    Actor, ActorContext, AsyncContext, Handler, Message, Recipient, StreamHandler,
 };
 use actix_web::{
     error::{InternalError, PayloadError},
-    http::StatusCode,
+    http::{header::HeaderMap, StatusCode},
     HttpRequest, HttpResponse,
 };
 use actix_web_actors::ws::{self, handshake, CloseReason, ProtocolError, WebsocketContext};
 use bytes::Bytes;
 use futures::{Sink, Stream, StreamExt};
 
+/// Asks a proxy session to close both sides with "going away", sent on shutdown.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+// 活跃的 websocket 代理会话
+static SESSIONS: LazyLock<Mutex<HashMap<u64, Recipient<Shutdown>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Number of websocket proxy sessions currently open.
+pub fn active_sessions() -> usize {
+    SESSIONS.lock().unwrap().len()
+}
+
+/// Sends close frames to every active websocket proxy session.
+pub fn close_all() {
+    for session in SESSIONS.lock().unwrap().values() {
+        session.do_send(Shutdown);
+    }
+}
+
 /// WebsocketProxy proxies an incoming websocket connection to another websocket, connected via awc.
 pub struct WebsocketProxy<S>
 where
     S: Unpin + Sink<ws::Message>,
 {
+    id: u64,
     send: SinkWrite<ws::Message, S>,
 }
 
@@ -45,7 +72,7 @@ where
 /// start a websocket proxy
 ///
 /// `target` should be a URL of the form `ws://<host>` or `wss://<host>`
-/// see awc::Client::ws for more information
+/// see awc::Client::ws for more information, `client` makes the connection (eg: to a unix socket)
 /// req and stream are exactly like the arguments to actix_web_actors::ws::start
 /// ```
 /// # use actix_web::{get, Error, HttpRequest, HttpResponse, web};
@@ -55,12 +82,15 @@ where
 ///     stream: web::Payload,
 ///     port: web::Path<u16>,
 /// ) -> Result<HttpResponse, Error> {
-///     actix_ws_proxy::start(&req, format!("ws://127.0.0.1:{}", port), stream).await
+///     let client = awc::Client::new();
+///     actix_ws_proxy::start(&req, &client, format!("ws://127.0.0.1:{}", port), Default::default(), stream).await
 /// }
 /// ```
 pub async fn start<T>(
     req: &HttpRequest,
+    client: &awc::Client,
     target: String,
+    headers: HeaderMap,
     stream: T,
 ) -> Result<HttpResponse, actix_web::Error>
 where
@@ -68,8 +98,11 @@ where
 {
     let mut res = handshake(req)?;
 
-    let (_, conn) = awc::Client::new()
-        .ws(target)
+    let mut ws_request = client.ws(target);
+    for (name, value) in headers.iter() {
+        ws_request = ws_request.header(name.clone(), value.clone());
+    }
+    let (_, conn) = ws_request
         .connect()
         .await
         .map_err(|e| InternalError::new(e, StatusCode::BAD_GATEWAY))?;
@@ -79,6 +112,7 @@ where
     let out = WebsocketContext::with_factory(stream, |ctx| {
         ctx.add_stream(recv);
         WebsocketProxy {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
             send: SinkWrite::new(send, ctx),
         }
     });
@@ -101,6 +135,37 @@ where
     S: Unpin + 'static + Sink<ws::Message>,
 {
     type Context = WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        SESSIONS
+            .lock()
+            .unwrap()
+            .insert(self.id, ctx.address().recipient());
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        SESSIONS.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl<S> Handler<Shutdown> for WebsocketProxy<S>
+where
+    S: Unpin + 'static + Sink<ws::Message>,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: Shutdown, ctx: &mut Self::Context) {
+        let reason = Some(CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some(String::from("server shutting down")),
+        });
+
+        ctx.close(reason.clone());
+        let _ = self.send.write(ws::Message::Close(reason));
+        self.send.close();
+
+        ctx.stop();
+    }
 }
 
 // This represents messages from upstream, so we send them downstream